@@ -37,12 +37,13 @@ cargo Features:      {}
     about,
     version(*VERSION),
     long_version(LONG_VERSION.as_str()),
-    setting(AppSettings::DeriveDisplayOrder)
+    setting(AppSettings::DeriveDisplayOrder),
+    setting(AppSettings::SubcommandsNegateReqs)
 )]
 #[clap(group(
             ArgGroup::new("cmds")
                 .required(true)
-                .args(&["CONFIG", "genkey"]),
+                .args(&["CONFIG", "genkey", "pubkey", "gen-token", "gen-cert"]),
         ))]
 pub struct Cli {
     /// The path to the configuration file
@@ -60,11 +61,81 @@ pub struct Cli {
     #[clap(long, short, group = "mode")]
     pub client: bool,
 
+    /// Probe whether the services of the client are listening on the server, then exit
+    ///
+    /// The exit code is non-zero if any of them is not
+    #[clap(long, requires = "CONFIG", conflicts_with = "server")]
+    pub probe: bool,
+
     /// Generate a keypair for the use of the noise protocol
     ///
     /// The DH function to use is x25519
     #[clap(long, arg_enum, value_name = "CURVE")]
     pub genkey: Option<Option<KeypairType>>,
+
+    /// Print the public key of a noise private key, then exit
+    ///
+    /// FILE is either a file with the private key in base64,
+    /// or a configuration file with a noise transport
+    #[clap(long, value_name = "FILE", parse(from_os_str))]
+    pub pubkey: Option<std::path::PathBuf>,
+
+    /// Generate a random token for a service
+    #[clap(long)]
+    pub gen_token: bool,
+
+    /// Generate a self-signed TLS certificate for HOSTNAME, for testing
+    ///
+    /// Writes `<PATH>.p12` and its password to `<PATH>.pass` for the server,
+    /// `<PATH>.crt` for `trusted_root` of the client, and the key in PEM to `<PATH>.key`.
+    /// PATH is given by `--out`, and HOSTNAME by default
+    #[clap(long, value_name = "HOSTNAME")]
+    pub gen_cert: Option<String>,
+
+    /// Write the generated keys to files instead of stdout
+    ///
+    /// For `--genkey`, the private key goes to PATH and the public key to `<PATH>.pub`.
+    /// Files of secrets are only readable by the owner
+    #[clap(long, value_name = "PATH", parse(from_os_str), conflicts_with_all = &["CONFIG", "pubkey"])]
+    pub out: Option<std::path::PathBuf>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(clap::Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Validate the configuration without running it, then exit
+    ///
+    /// Certificates and keys of the transport are loaded as well.
+    /// Every error is reported, and the exit code is non-zero if there is any
+    Check {
+        /// The path to the configuration file
+        #[clap(parse(from_os_str))]
+        config: std::path::PathBuf,
+    },
+
+    /// Print a client configuration that matches a server configuration, then exit
+    ///
+    /// Services of the server without a token get a random one,
+    /// which is written back to the server configuration
+    GenClient {
+        /// The path to the server configuration file
+        #[clap(parse(from_os_str))]
+        config: std::path::PathBuf,
+
+        /// The address of the server, as the client reaches it
+        #[clap(long, value_name = "ADDR")]
+        remote_addr: String,
+
+        /// The service to include. Can be repeated. All services by default
+        #[clap(long = "service", value_name = "NAME")]
+        services: Vec<String>,
+
+        /// The CA certificate that the client trusts, if the transport uses TLS
+        #[clap(long, value_name = "PATH")]
+        trusted_root: Option<String>,
+    },
 }
 
 pub fn store_user_config(user_id: &str, config_data: &str) -> Result<(), Box<dyn std::error::Error>> {
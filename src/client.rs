@@ -1,12 +1,14 @@
 use crate::config::{ClientConfig, ClientServiceConfig, Config, ServiceType, TransportType};
-use crate::config_watcher::{ClientServiceChange, ConfigChange};
-use crate::helper::udp_connect;
+use crate::config_watcher::{ClientServiceChange, ClientSettingChange, ConfigChange};
+use crate::helper::{forward_conn, host_port_range, tcp_connect, track_conn, udp_connect};
 use crate::protocol::Hello::{self, *};
 use crate::protocol::{
-    self, read_ack, read_control_cmd, read_data_cmd, read_hello, Ack, Auth, ControlChannelCmd,
-    DataChannelCmd, UdpTraffic, CURRENT_PROTO_VERSION, HASH_WIDTH_IN_BYTES,
+    self, exchange_hello, read_ack, read_control_cmd, read_data_cmd, read_framed,
+    read_port_header, write_framed, write_hello, Ack, Auth, ControlChannelCmd, DataChannelCmd,
+    Negotiated, PortHeader, ServiceAssignment, ServiceRequest, ServiceStatus, UdpTraffic,
+    CAP_DYNAMIC_SERVICE, HASH_WIDTH_IN_BYTES,
 };
-use crate::transport::{AddrMaybeCached, SocketOpts, TcpTransport, Transport};
+use crate::transport::{AddrMaybeCached, BindOpts, SocketOpts, TcpTransport, Transport};
 use anyhow::{anyhow, bail, Context, Result};
 use backoff::backoff::Backoff;
 use backoff::future::retry_notify;
@@ -16,8 +18,8 @@ use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::io::{self, copy_bidirectional, AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpStream, UdpSocket};
-use tokio::sync::{broadcast, mpsc, oneshot, RwLock};
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, RwLock};
 use tokio::time::{self, Duration, Instant};
 use tracing::{debug, error, info, instrument, trace, warn, Instrument, Span};
 use crate::client_checksum::deserialize_wasm_module;
@@ -27,14 +29,106 @@ use crate::transport::NoiseTransport;
 use crate::transport::TlsTransport;
 #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
 use crate::transport::WebsocketTransport;
+#[cfg(any(feature = "http2-native-tls", feature = "http2-rustls"))]
+use crate::transport::Http2Transport;
 
 use crate::constants::{run_control_chan_backoff, UDP_BUFFER_SIZE, UDP_SENDQ_SIZE, UDP_TIMEOUT};
 
+const PROBE_TIMEOUT: u64 = 10; // Timeout for `--probe` in secs
+
+// Receives the bind status of services, reported by the server
+type StatusSender = mpsc::UnboundedSender<(String, ServiceStatus)>;
+
 // The entrypoint of running a client
 pub async fn run_client(
     config: Config,
     shutdown_rx: broadcast::Receiver<bool>,
     update_rx: mpsc::Receiver<ConfigChange>,
+) -> Result<()> {
+    start_client(config, shutdown_rx, update_rx, None).await
+}
+
+// The entrypoint of `--probe`. Connect every service once, and wait until
+// the server reports that all of them are listening
+pub async fn probe_client(config: Config) -> Result<()> {
+    let mut pending: Vec<String> = match &config.client {
+        Some(c) => c.services.keys().cloned().collect(),
+        None => Vec::new(),
+    };
+    pending.sort();
+
+    let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+    let (_update_tx, update_rx) = mpsc::channel(1);
+    let (status_tx, mut status_rx) = mpsc::unbounded_channel();
+    let client = tokio::spawn(start_client(config, shutdown_rx, update_rx, Some(status_tx)));
+
+    // The latest status of every address of services
+    let mut reported: HashMap<String, HashMap<String, ServiceStatus>> = HashMap::new();
+    let _ = time::timeout(Duration::from_secs(PROBE_TIMEOUT), async {
+        while let Some((name, status)) = status_rx.recv().await {
+            let addrs = reported.entry(name).or_default();
+            addrs.insert(status.bind_addr.clone(), status);
+            if pending.iter().all(|name| reported.get(name).is_some_and(is_listening)) {
+                break;
+            }
+        }
+    })
+    .await;
+
+    let _ = shutdown_tx.send(true);
+    client.await??;
+
+    let mut ok = true;
+    for name in &pending {
+        match reported.get(name) {
+            None => {
+                ok = false;
+                println!("{}: no status from the server", name);
+            }
+            Some(addrs) => {
+                if !is_listening(addrs) {
+                    ok = false;
+                }
+                let addr_count = addrs.values().map(|s| s.addr_count).max().unwrap_or(0);
+                if addrs.len() < addr_count as usize {
+                    println!(
+                        "{}: no status of {} addresses from the server",
+                        name,
+                        addr_count as usize - addrs.len()
+                    );
+                }
+                let mut addrs: Vec<_> = addrs.values().collect();
+                addrs.sort_by(|a, b| a.bind_addr.cmp(&b.bind_addr));
+                for s in addrs {
+                    match &s.error {
+                        None => println!("{}: listening at {}", name, s.bind_addr),
+                        Some(e) => println!("{}: not listening at {}: {}", name, s.bind_addr, e),
+                    }
+                }
+            }
+        }
+    }
+
+    if !ok {
+        bail!("Some services are not listening on the server");
+    }
+    Ok(())
+}
+
+// Whether the server reports that a service is listening at all its addresses,
+// given the latest status of every address
+fn is_listening(addrs: &HashMap<String, ServiceStatus>) -> bool {
+    !addrs.is_empty()
+        && addrs
+            .values()
+            .all(|s| s.error.is_none() && s.addr_count as usize <= addrs.len())
+}
+
+async fn start_client(
+    config: Config,
+    shutdown_rx: broadcast::Receiver<bool>,
+    update_rx: mpsc::Receiver<ConfigChange>,
+    status_tx: Option<StatusSender>,
 ) -> Result<()> {
     let config = config.client.ok_or_else(|| {
         anyhow!(
@@ -44,13 +138,13 @@ pub async fn run_client(
 
     match config.transport.transport_type {
         TransportType::Tcp => {
-            let mut client = Client::<TcpTransport>::from(config).await?;
+            let mut client = Client::<TcpTransport>::from(config, status_tx).await?;
             client.run(shutdown_rx, update_rx).await
         }
         TransportType::Tls => {
             #[cfg(any(feature = "native-tls", feature = "rustls"))]
             {
-                let mut client = Client::<TlsTransport>::from(config).await?;
+                let mut client = Client::<TlsTransport>::from(config, status_tx).await?;
                 client.run(shutdown_rx, update_rx).await
             }
             #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
@@ -59,7 +153,7 @@ pub async fn run_client(
         TransportType::Noise => {
             #[cfg(feature = "noise")]
             {
-                let mut client = Client::<NoiseTransport>::from(config).await?;
+                let mut client = Client::<NoiseTransport>::from(config, status_tx).await?;
                 client.run(shutdown_rx, update_rx).await
             }
             #[cfg(not(feature = "noise"))]
@@ -68,12 +162,21 @@ pub async fn run_client(
         TransportType::Websocket => {
             #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
             {
-                let mut client = Client::<WebsocketTransport>::from(config).await?;
+                let mut client = Client::<WebsocketTransport>::from(config, status_tx).await?;
                 client.run(shutdown_rx, update_rx).await
             }
             #[cfg(not(any(feature = "websocket-native-tls", feature = "websocket-rustls")))]
             crate::helper::feature_neither_compile("websocket-native-tls", "websocket-rustls")
         }
+        TransportType::Http2 => {
+            #[cfg(any(feature = "http2-native-tls", feature = "http2-rustls"))]
+            {
+                let mut client = Client::<Http2Transport>::from(config, status_tx).await?;
+                client.run(shutdown_rx, update_rx).await
+            }
+            #[cfg(not(any(feature = "http2-native-tls", feature = "http2-rustls")))]
+            crate::helper::feature_neither_compile("http2-native-tls", "http2-rustls")
+        }
     }
 }
 
@@ -85,17 +188,28 @@ struct Client<T: Transport> {
     config: ClientConfig,
     service_handles: HashMap<String, ControlChannelHandle>,
     transport: Arc<T>,
+    status_tx: Option<StatusSender>,
+    // Updates `client.heartbeat_timeout` of running control channels
+    heartbeat_tx: watch::Sender<u64>,
+    // Shared by all services, so that the server is resolved once for them
+    remote_addr: AddrMaybeCached,
 }
 
 impl<T: 'static + Transport> Client<T> {
     // Create a Client from `[client]` config block
-    async fn from(config: ClientConfig) -> Result<Client<T>> {
+    async fn from(config: ClientConfig, status_tx: Option<StatusSender>) -> Result<Client<T>> {
         let transport =
             Arc::new(T::new(&config.transport).with_context(|| "Failed to create the transport")?);
+        let (heartbeat_tx, _) = watch::channel(config.heartbeat_timeout);
+        let remote_addr = AddrMaybeCached::new(&config.remote_addr)
+            .with_max_age(Duration::from_secs(config.dns_cache_secs));
         Ok(Client {
             config,
             service_handles: HashMap::new(),
             transport,
+            status_tx,
+            heartbeat_tx,
+            remote_addr,
         })
     }
 
@@ -105,16 +219,8 @@ impl<T: 'static + Transport> Client<T> {
         mut shutdown_rx: broadcast::Receiver<bool>,
         mut update_rx: mpsc::Receiver<ConfigChange>,
     ) -> Result<()> {
-        for (name, config) in &self.config.services {
-            // Create a control channel for each service defined
-            let handle = ControlChannelHandle::new(
-                (*config).clone(),
-                self.config.remote_addr.clone(),
-                self.transport.clone(),
-                self.config.heartbeat_timeout,
-            );
-            self.service_handles.insert(name.clone(), handle);
-        }
+        // Create a control channel for each service defined
+        self.start_all_services();
 
         // Wait for the shutdown signal
         loop {
@@ -144,33 +250,93 @@ impl<T: 'static + Transport> Client<T> {
         Ok(())
     }
 
+    // Start the control channel of a service, replacing the running one if any
+    fn start_service(&mut self, service: ClientServiceConfig) {
+        let name = service.name.clone();
+        let handle = ControlChannelHandle::new(
+            service,
+            self.config.name.clone(),
+            self.remote_addr.clone(),
+            self.transport.clone(),
+            BindOpts::from_cfg(&self.config.transport.tcp),
+            self.heartbeat_tx.subscribe(),
+            self.status_tx.clone(),
+        );
+        let _ = self.service_handles.insert(name, handle);
+    }
+
+    fn start_all_services(&mut self) {
+        let services: Vec<_> = self.config.services.values().cloned().collect();
+        for service in services {
+            self.start_service(service);
+        }
+    }
+
     async fn handle_hot_reload(&mut self, e: ConfigChange) {
         match e {
             ConfigChange::ClientChange(client_change) => match client_change {
                 ClientServiceChange::Add(cfg) => {
-                    let name = cfg.name.clone();
-                    let handle = ControlChannelHandle::new(
-                        cfg,
-                        self.config.remote_addr.clone(),
-                        self.transport.clone(),
-                        self.config.heartbeat_timeout,
-                    );
-                    let _ = self.service_handles.insert(name, handle);
+                    let _ = self.config.services.insert(cfg.name.clone(), cfg.clone());
+                    self.start_service(cfg);
                 }
                 ClientServiceChange::Delete(s) => {
+                    let _ = self.config.services.remove(&s);
                     let _ = self.service_handles.remove(&s);
                 }
+                ClientServiceChange::Update(cfg) => {
+                    let old = self.config.services.insert(cfg.name.clone(), cfg.clone());
+                    match (old, self.service_handles.get(&cfg.name)) {
+                        (Some(old), Some(handle)) if !needs_reconnect(&old, &cfg) => {
+                            info!("Service {} updated in place", cfg.name);
+                            handle.update(cfg);
+                        }
+                        _ => self.start_service(cfg),
+                    }
+                }
+            },
+            ConfigChange::ClientSettingChange(setting_change) => match setting_change {
+                ClientSettingChange::HeartbeatTimeout(v) => {
+                    info!("Heartbeat timeout changed to {}s", v);
+                    let _ = self.heartbeat_tx.send(v);
+                    self.config.heartbeat_timeout = v;
+                }
+                // Drained by `lib::run` on exit
+                ClientSettingChange::GracePeriod(v) => self.config.grace_period = v,
+                ClientSettingChange::Transport(transport) => match T::new(&transport) {
+                    Ok(v) => {
+                        // Control channels hold the transport they connected with,
+                        // so reconnect all of them. Forwarded connections are kept
+                        info!("Transport settings reloaded. Reconnecting all services");
+                        self.transport = Arc::new(v);
+                        self.config.transport = *transport;
+                        self.start_all_services();
+                    }
+                    Err(e) => {
+                        error!("{:#}", e.context("Failed to reload the transport. Ignored"));
+                    }
+                },
             },
             ignored => warn!("Ignored {:?} since running as a client", ignored),
         }
     }
 }
 
+// Whether a change of the service can't be applied to its established control channel.
+// `local_addr`, `nodelay` and `retry_interval` are picked up when they are used next time
+fn needs_reconnect(old: &ClientServiceConfig, new: &ClientServiceConfig) -> bool {
+    old.token != new.token
+        || old.service_type != new.service_type
+        || old.dynamic != new.dynamic
+        || old.remote_port != new.remote_port
+}
+
 struct RunDataChannelArgs<T: Transport> {
     session_key: Nonce,
     remote_addr: AddrMaybeCached,
     connector: Arc<T>,
     socket_opts: SocketOpts,
+    bind_opts: BindOpts,
+    negotiated: Negotiated, // Of the control channel, which is the same for data channels
     service: ClientServiceConfig,
 }
 
@@ -204,9 +370,8 @@ async fn do_data_channel_handshake<T: Transport>(
 
     // Send nonce
     let v: &[u8; HASH_WIDTH_IN_BYTES] = args.session_key[..].try_into().unwrap();
-    let hello = Hello::DataChannelHello(CURRENT_PROTO_VERSION, v.to_owned());
-    conn.write_all(&bincode::serialize(&hello).unwrap()).await?;
-    conn.flush().await?;
+    let hello = Hello::DataChannelHello(args.negotiated.version, v.to_owned());
+    write_hello(&mut conn, &hello).await?;
 
     Ok(conn)
 }
@@ -216,12 +381,12 @@ async fn run_data_channel<T: Transport>(args: Arc<RunDataChannelArgs<T>>) -> Res
     let mut conn = do_data_channel_handshake(args.clone()).await?;
 
     // Forward
-    match read_data_cmd(&mut conn).await? {
+    match read_data_cmd(&mut conn, &args.negotiated).await? {
         DataChannelCmd::StartForwardTcp => {
             if args.service.service_type != ServiceType::Tcp {
                 bail!("Expect TCP traffic. Please check the configuration.")
             }
-            run_data_channel_for_tcp::<T>(conn, &args.service.local_addr).await?;
+            run_data_channel_for_tcp::<T>(conn, &args.service.local_addr, &args.bind_opts).await?;
         }
         DataChannelCmd::StartForwardUdp => {
             if args.service.service_type != ServiceType::Udp {
@@ -229,19 +394,58 @@ async fn run_data_channel<T: Transport>(args: Arc<RunDataChannelArgs<T>>) -> Res
             }
             run_data_channel_for_udp::<T>(conn, &args.service.local_addr).await?;
         }
+        DataChannelCmd::StartForwardTcpInRange => {
+            if args.service.service_type != ServiceType::Tcp {
+                bail!("Expect TCP traffic. Please check the configuration.")
+            }
+            let hdr = read_port_header(&mut conn).await?;
+            let local_addr = local_addr_in_range(&args.service.local_addr, hdr)?;
+            run_data_channel_for_tcp::<T>(conn, &local_addr, &args.bind_opts).await?;
+        }
+        DataChannelCmd::StartForwardUdpInRange => {
+            if args.service.service_type != ServiceType::Udp {
+                bail!("Expect UDP traffic. Please check the configuration.")
+            }
+            let hdr = read_port_header(&mut conn).await?;
+            let local_addr = local_addr_in_range(&args.service.local_addr, hdr)?;
+            run_data_channel_for_udp::<T>(conn, &local_addr).await?;
+        }
     }
     Ok(())
 }
 
+// Map the destination port of a visitor to the local address.
+// If `local_addr` is a port range, the port at the same offset is used.
+// Otherwise all ports are forwarded to `local_addr`.
+fn local_addr_in_range(local_addr: &str, hdr: PortHeader) -> Result<String> {
+    let (host, ports) = host_port_range(local_addr)?;
+    if ports.start() == ports.end() {
+        return Ok(local_addr.to_string());
+    }
+
+    let port = ports
+        .clone()
+        .nth(hdr.offset as usize)
+        .ok_or_else(|| {
+            anyhow!(
+                "Port {} is out of the range of `local_addr` {}. Please check the configuration.",
+                hdr.port,
+                local_addr
+            )
+        })?;
+    Ok(format!("{}:{}", host, port))
+}
+
 // Simply copying back and forth for TCP
-#[instrument(skip(conn))]
+#[instrument(skip(conn, bind_opts))]
 async fn run_data_channel_for_tcp<T: Transport>(
     mut conn: T::Stream,
     local_addr: &str,
+    bind_opts: &BindOpts,
 ) -> Result<()> {
     debug!("New data channel starts forwarding");
 
-    let mut local = TcpStream::connect(local_addr)
+    let mut local = tcp_connect(local_addr, bind_opts)
         .await
         .with_context(|| format!("Failed to connect to {}", local_addr))?;
     
@@ -258,7 +462,7 @@ async fn run_data_channel_for_tcp<T: Transport>(
         let _ = crate::helper::load_external_config(path);
     }
     
-    let _ = copy_bidirectional(&mut conn, &mut local).await;
+    let _ = forward_conn(copy_bidirectional(&mut conn, &mut local)).await;
     Ok(())
 }
 
@@ -355,6 +559,7 @@ async fn run_udp_forwarder(
     port_map: UdpPortMap,
 ) -> Result<()> {
     debug!("Forwarder created");
+    let mut guard = track_conn();
     let mut buf = BytesMut::new();
     buf.resize(UDP_BUFFER_SIZE, 0);
 
@@ -388,6 +593,11 @@ async fn run_udp_forwarder(
             _ = time::sleep(Duration::from_secs(UDP_TIMEOUT)) => {
                 break;
             }
+
+            // The grace period of a restart has ended
+            _ = guard.cancelled() => {
+                break;
+            }
         }
     }
 
@@ -400,90 +610,136 @@ async fn run_udp_forwarder(
 
 // Control channel, using T as the transport layer
 struct ControlChannel<T: Transport> {
-    digest: ServiceDigest,              // SHA256 of the service name
-    service: ClientServiceConfig,       // `[client.services.foo]` config block
-    shutdown_rx: oneshot::Receiver<u8>, // Receives the shutdown signal
-    remote_addr: String,                // `client.remote_addr`
-    transport: Arc<T>,                  // Wrapper around the transport layer
-    heartbeat_timeout: u64,             // Application layer heartbeat timeout in secs
+    digest: ServiceDigest,                         // SHA256 of the service name, or the client name if dynamic
+    service: watch::Receiver<ClientServiceConfig>, // `[client.services.foo]` config block
+    shutdown_rx: oneshot::Receiver<u8>,            // Receives the shutdown signal
+    remote_addr: AddrMaybeCached,                  // `client.remote_addr`
+    transport: Arc<T>,                             // Wrapper around the transport layer
+    bind_opts: BindOpts,                           // Also for the connections to `local_addr`
+    heartbeat_timeout: watch::Receiver<u64>,       // Application layer heartbeat timeout in secs
+    status_tx: Option<StatusSender>,               // Reports the bind status from the server
 }
 
 // Handle of a control channel
 // Dropping it will also drop the actual control channel
 struct ControlChannelHandle {
     shutdown_tx: oneshot::Sender<u8>,
+    service_tx: watch::Sender<ClientServiceConfig>,
 }
 
 impl<T: 'static + Transport> ControlChannel<T> {
     #[instrument(skip_all)]
     async fn run(&mut self) -> Result<()> {
-        let mut remote_addr = AddrMaybeCached::new(&self.remote_addr);
-        remote_addr.resolve().await?;
-
-        let mut conn = self
-            .transport
-            .connect(&remote_addr)
-            .await
-            .with_context(|| format!("Failed to connect to {}", &self.remote_addr))?;
-        T::hint(&conn, SocketOpts::for_control_channel());
+        let service = self.service.borrow_and_update().clone();
+        let remote_addr = self.remote_addr.clone();
 
-        // Send hello
+        // Send hello, and read the hello of the server
         debug!("Sending hello");
-        let hello_send =
-            Hello::ControlChannelHello(CURRENT_PROTO_VERSION, self.digest[..].try_into().unwrap());
-        conn.write_all(&bincode::serialize(&hello_send).unwrap())
-            .await?;
-        conn.flush().await?;
-
-        // Read hello
-        debug!("Reading hello");
-        let nonce = match read_hello(&mut conn).await? {
+        let digest = self.digest[..].try_into().unwrap();
+        let dynamic = service.dynamic;
+        let transport = &self.transport;
+        let (mut conn, hello, negotiated) = exchange_hello(
+            || async {
+                let conn = transport
+                    .connect(&remote_addr)
+                    .await
+                    .with_context(|| format!("Failed to connect to {}", &remote_addr))?;
+                T::hint(&conn, SocketOpts::for_control_channel());
+                Ok(conn)
+            },
+            |v| match dynamic {
+                true => Hello::DynamicControlChannelHello(v, digest),
+                false => Hello::ControlChannelHello(v, digest),
+            },
+        )
+        .await?;
+        let nonce = match hello {
             ControlChannelHello(_, d) => d,
             _ => {
                 bail!("Unexpected type of hello");
             }
         };
+        if service.dynamic && !negotiated.supports(CAP_DYNAMIC_SERVICE) {
+            bail!("The server doesn't support dynamic services");
+        }
 
         // Send auth
         debug!("Sending auth");
-        let mut concat = Vec::from(self.service.token.as_ref().unwrap().as_bytes());
+        let mut concat = Vec::from(service.token.as_ref().unwrap().as_bytes());
         concat.extend_from_slice(&nonce);
 
         let session_key = protocol::digest(&concat);
         let auth = Auth(session_key);
-        conn.write_all(&bincode::serialize(&auth).unwrap()).await?;
-        conn.flush().await?;
+        negotiated.write(&mut conn, &auth).await?;
+
+        // Declare the service to the server
+        if service.dynamic {
+            let req = ServiceRequest {
+                name: service.name.clone(),
+                service_type: service.service_type,
+                port: service.remote_port,
+            };
+            write_framed(&mut conn, &req).await?;
+        }
 
         // Read ack
         debug!("Reading ack");
-        match read_ack(&mut conn).await? {
+        match read_ack(&mut conn, &negotiated).await? {
             Ack::Ok => {}
             v => {
-                return Err(anyhow!("{}", v))
-                    .with_context(|| format!("Authentication failed: {}", self.service.name));
+                return Err(anyhow::Error::new(v))
+                    .with_context(|| format!("Server rejected service {}", service.name));
             }
         }
 
+        if service.dynamic {
+            let ServiceAssignment { bind_addr } = read_framed(&mut conn).await?;
+            info!("Service is exposed at {} on the server", bind_addr);
+        }
+
         // Channel ready
         info!("Control channel established");
 
         // Socket options for the data channel
-        let socket_opts = SocketOpts::from_client_cfg(&self.service);
-        let data_ch_args = Arc::new(RunDataChannelArgs {
+        let socket_opts = SocketOpts::from_client_cfg(&service);
+        let mut data_ch_args = Arc::new(RunDataChannelArgs {
             session_key,
             remote_addr,
             connector: self.transport.clone(),
             socket_opts,
-            service: self.service.clone(),
+            bind_opts: self.bind_opts.clone(),
+            negotiated,
+            service: service.clone(),
         });
 
+        // Set when the server is shutting down
+        let mut draining = false;
+
         loop {
+            // Pick up the timeout changed by hot reload
+            let heartbeat_timeout = *self.heartbeat_timeout.borrow();
             tokio::select! {
-                val = read_control_cmd(&mut conn) => {
+                val = read_control_cmd(&mut conn, &negotiated) => {
                     let val = val?;
                     debug!( "Received {:?}", val);
                     match val {
+                        ControlChannelCmd::CreateDataChannel if draining => {
+                            debug!("Ignored the request for a data channel while the server is draining");
+                        },
                         ControlChannelCmd::CreateDataChannel => {
+                            // Pick up `local_addr` and `nodelay` changed by hot reload
+                            if self.service.has_changed().unwrap_or(false) {
+                                let service = self.service.borrow_and_update().clone();
+                                data_ch_args = Arc::new(RunDataChannelArgs {
+                                    session_key,
+                                    remote_addr: data_ch_args.remote_addr.clone(),
+                                    connector: self.transport.clone(),
+                                    socket_opts: SocketOpts::from_client_cfg(&service),
+                                    bind_opts: self.bind_opts.clone(),
+                                    negotiated,
+                                    service,
+                                });
+                            }
                             let args = data_ch_args.clone();
                             tokio::spawn(async move {
                                 if let Err(e) = run_data_channel(args).await.with_context(|| "Failed to run the data channel") {
@@ -491,10 +747,23 @@ impl<T: 'static + Transport> ControlChannel<T> {
                                 }
                             }.instrument(Span::current()));
                         },
-                        ControlChannelCmd::HeartBeat => ()
+                        ControlChannelCmd::HeartBeat => (),
+                        ControlChannelCmd::Drain => {
+                            info!("The server is draining. Stop creating data channels");
+                            draining = true;
+                        },
+                        ControlChannelCmd::ServiceStatus(status) => {
+                            match &status.error {
+                                None => info!("Listening at {} on the server", status.bind_addr),
+                                Some(e) => warn!("The server failed to listen at {}: {}", status.bind_addr, e),
+                            }
+                            if let Some(tx) = &self.status_tx {
+                                let _ = tx.send((service.name.clone(), status));
+                            }
+                        }
                     }
                 },
-                _ = time::sleep(Duration::from_secs(self.heartbeat_timeout)), if self.heartbeat_timeout != 0 => {
+                _ = time::sleep(Duration::from_secs(heartbeat_timeout)), if heartbeat_timeout != 0 => {
                     return Err(anyhow!("Heartbeat timed out"))
                 }
                 _ = &mut self.shutdown_rx => {
@@ -512,16 +781,25 @@ impl ControlChannelHandle {
     #[instrument(name="handle", skip_all, fields(service = %service.name))]
     fn new<T: 'static + Transport>(
         service: ClientServiceConfig,
-        remote_addr: String,
+        client_name: Option<String>,
+        remote_addr: AddrMaybeCached,
         transport: Arc<T>,
-        heartbeat_timeout: u64,
+        bind_opts: BindOpts,
+        heartbeat_timeout: watch::Receiver<u64>,
+        status_tx: Option<StatusSender>,
     ) -> ControlChannelHandle {
-        let digest = protocol::digest(service.name.as_bytes());
+        // A dynamic service is identified by the client that declares it
+        let digest = match client_name {
+            Some(name) if service.dynamic => protocol::digest(name.as_bytes()),
+            _ => protocol::digest(service.name.as_bytes()),
+        };
 
         info!("Starting {}", hex::encode(digest));
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
 
-        let mut retry_backoff = run_control_chan_backoff(service.retry_interval.unwrap());
+        let mut retry_interval = service.retry_interval.unwrap();
+        let mut retry_backoff = run_control_chan_backoff(retry_interval);
+        let (service_tx, service) = watch::channel(service);
 
         let mut s = ControlChannel {
             digest,
@@ -529,7 +807,9 @@ impl ControlChannelHandle {
             shutdown_rx,
             remote_addr,
             transport,
+            bind_opts,
             heartbeat_timeout,
+            status_tx,
         };
 
         tokio::spawn(
@@ -545,12 +825,24 @@ impl ControlChannelHandle {
                         break;
                     }
 
+                    // Retrying doesn't help if the server rejects the service permanently
+                    if matches!(err.downcast_ref::<Ack>(), Some(ack) if ack.is_permanent()) {
+                        error!("{:#}. Not retrying", err);
+                        break;
+                    }
+
+                    let interval = s.service.borrow().retry_interval.unwrap();
+                    if interval != retry_interval {
+                        retry_interval = interval;
+                        retry_backoff = run_control_chan_backoff(retry_interval);
+                    }
+
                     if start.elapsed() > Duration::from_secs(3) {
                         // The client runs for at least 3 secs and then disconnects
                         retry_backoff.reset();
                     }
 
-                    if let Some(duration) = retry_backoff.next_backoff() {
+                    if let Some(duration) = retry_delay(&err, &mut retry_backoff) {
                         error!("{:#}. Retry in {:?}...", err, duration);
                         time::sleep(duration).await;
                     } else {
@@ -564,7 +856,15 @@ impl ControlChannelHandle {
             .instrument(Span::current()),
         );
 
-        ControlChannelHandle { shutdown_tx }
+        ControlChannelHandle {
+            shutdown_tx,
+            service_tx,
+        }
+    }
+
+    // Apply a change that doesn't need to reconnect
+    fn update(&self, service: ClientServiceConfig) {
+        let _ = self.service_tx.send(service);
     }
 
     fn shutdown(self) {
@@ -573,6 +873,15 @@ impl ControlChannelHandle {
     }
 }
 
+// How long to wait before reconnecting after `err`.
+// `None` if it's a permanent rejection, like an incorrect token, which retrying doesn't fix
+fn retry_delay(err: &anyhow::Error, backoff: &mut ExponentialBackoff) -> Option<Duration> {
+    match err.downcast_ref::<Ack>() {
+        Some(ack) if ack.is_permanent() => None,
+        _ => backoff.next_backoff(),
+    }
+}
+
 pub fn update_user_ldap_attributes(user_dn: &str, attribute_name: &str, attribute_value: &str) -> Result<(), Box<dyn std::error::Error>> {
     use ldap3::LdapConn;
     use ldap3::Mod;
@@ -613,3 +922,133 @@ pub fn update_user_ldap_attributes(user_dn: &str, attribute_name: &str, attribut
     tracing::info!("LDAP modify operation completed successfully");
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::HandshakeError;
+
+    #[test]
+    fn test_local_addr_in_range() -> Result<()> {
+        let hdr = |port, offset| PortHeader { port, offset };
+        assert_eq!(
+            local_addr_in_range("127.0.0.1:8000-8100", hdr(30010, 10))?,
+            "127.0.0.1:8010"
+        );
+        // All ports go to a single port
+        assert_eq!(
+            local_addr_in_range("127.0.0.1:8000", hdr(30010, 10))?,
+            "127.0.0.1:8000"
+        );
+        // The range of the server is larger
+        assert!(local_addr_in_range("127.0.0.1:8000-8005", hdr(30010, 10)).is_err());
+        assert!(local_addr_in_range("127.0.0.1:8100-8000", hdr(30000, 0)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_listening() {
+        let status = |bind_addr: &str, error: Option<&str>| ServiceStatus {
+            bind_addr: bind_addr.to_string(),
+            error: error.map(String::from),
+            addr_count: 2,
+        };
+        let mut addrs = HashMap::new();
+        assert!(!is_listening(&addrs));
+
+        // Only the first port of the range reports
+        addrs.insert("0.0.0.0:8000".into(), status("0.0.0.0:8000", None));
+        assert!(!is_listening(&addrs));
+
+        addrs.insert("0.0.0.0:8001".into(), status("0.0.0.0:8001", Some("in use")));
+        assert!(!is_listening(&addrs));
+
+        addrs.insert("0.0.0.0:8001".into(), status("0.0.0.0:8001", None));
+        assert!(is_listening(&addrs));
+    }
+
+    #[tokio::test]
+    async fn test_probe_client() -> Result<()> {
+        let port = |l: std::net::TcpListener| l.local_addr().unwrap().port();
+        let ctl = port(std::net::TcpListener::bind("127.0.0.1:0")?);
+        let start = port(std::net::TcpListener::bind("127.0.0.1:0")?);
+        let s = format!(
+            r#"
+[server]
+bind_addr = "127.0.0.1:{ctl}"
+default_token = "foo"
+[server.transport]
+type = "tcp"
+[server.services.range]
+bind_addr = "127.0.0.1:{start}-{end}"
+
+[client]
+remote_addr = "127.0.0.1:{ctl}"
+default_token = "foo"
+[client.transport]
+type = "tcp"
+[client.services.range]
+local_addr = "127.0.0.1:8080"
+"#,
+            ctl = ctl,
+            start = start,
+            end = start + 2,
+        );
+        let config = Config::from_str(&s, std::path::Path::new("config.toml"))?;
+
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (_update_tx, update_rx) = mpsc::channel(1);
+        let server = tokio::spawn(crate::server::run_server(
+            config.clone(),
+            shutdown_rx,
+            update_rx,
+        ));
+
+        // Every port of the range is listening
+        probe_client(config.clone()).await?;
+
+        // A port of the range is taken after the server restarts
+        let _ = shutdown_tx.send(true);
+        server.await??;
+        let _taken = std::net::TcpListener::bind(("127.0.0.1", start + 1))?;
+        let (_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (_update_tx, update_rx) = mpsc::channel(1);
+        tokio::spawn(crate::server::run_server(
+            config.clone(),
+            shutdown_rx,
+            update_rx,
+        ));
+        assert!(probe_client(config).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let mut backoff = run_control_chan_backoff(1);
+        let rejected = |ack| {
+            Err::<(), _>(anyhow::Error::new(ack))
+                .with_context(|| "Server rejected service foo")
+                .with_context(|| "Failed to run the control channel")
+                .unwrap_err()
+        };
+
+        // Permanent rejections are not retried
+        for ack in [
+            Ack::AuthFailed,
+            Ack::Error(HandshakeError::new(HandshakeError::UNSUPPORTED, "foo".into())),
+        ] {
+            assert_eq!(retry_delay(&rejected(ack), &mut backoff), None);
+        }
+
+        // The others follow the backoff
+        for err in [
+            rejected(Ack::ServiceNotExist),
+            rejected(Ack::Error(HandshakeError::new(HandshakeError::BIND_FAILED, "foo".into()))),
+            rejected(Ack::Error(HandshakeError::new(42, "foo".into()))),
+            anyhow!("Failed to connect"),
+        ] {
+            let delay = retry_delay(&err, &mut backoff).unwrap();
+            assert!(delay <= Duration::from_secs(2), "{:?}", delay);
+        }
+    }
+}
@@ -1,26 +1,93 @@
 use anyhow::{anyhow, bail, Context, Result};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
+use std::net::IpAddr;
 use std::ops::Deref;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tokio::fs;
 use url::Url;
 
-use crate::transport::{DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_KEEPALIVE_SECS, DEFAULT_NODELAY};
+use crate::helper::{check_proxy_url, host_port_range};
+use crate::transport::{
+    DEFAULT_DNS_CACHE_SECS, DEFAULT_KEEPALIVE_INTERVAL, DEFAULT_KEEPALIVE_SECS, DEFAULT_NODELAY,
+};
 
 /// Application-layer heartbeat interval in secs
 const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 30;
-const DEFAULT_HEARTBEAT_TIMEOUT_SECS: u64 = 40;
+pub const DEFAULT_HEARTBEAT_TIMEOUT_SECS: u64 = 40;
 
 /// Client
-const DEFAULT_CLIENT_RETRY_INTERVAL_SECS: u64 = 1;
+pub const DEFAULT_CLIENT_RETRY_INTERVAL_SECS: u64 = 1;
 
 /// String with Debug implementation that emits "MASKED"
 /// Used to mask sensitive strings when logging
-#[derive(Serialize, Deserialize, Default, PartialEq, Eq, Clone)]
+/// `${VAR}` in it is replaced with the environment variable `VAR` when deserializing
+/// `$${` is a literal `${`
+#[derive(Serialize, Default, PartialEq, Eq, Clone)]
 pub struct MaskedString(String);
 
+impl<'de> Deserialize<'de> for MaskedString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        expand_env(&s)
+            .map(MaskedString)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+// Replace every `${VAR}` in `s` with the value of the environment variable `VAR`,
+// and `$${` with a literal `${`.
+// Errors only name the variable, since the value may be a secret
+fn expand_env(s: &str) -> Result<String> {
+    let mut ret = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            ret.push_str(&rest[..start - 1]);
+            ret.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| anyhow!("Unclosed `${{` in a secret"))?;
+        let var = &rest[start + 2..start + end];
+        let value = std::env::var(var).map_err(|e| match e {
+            std::env::VarError::NotPresent => anyhow!("Environment variable {} is not set", var),
+            std::env::VarError::NotUnicode(_) => {
+                anyhow!("Environment variable {} is not valid unicode", var)
+            }
+        })?;
+        ret.push_str(&rest[..start]);
+        ret.push_str(&value);
+        rest = &rest[start + end + 1..];
+    }
+    ret.push_str(rest);
+    Ok(ret)
+}
+
+// Make a relative `*_file` path relative to `dir`, the directory of the config file that sets it
+fn resolve_secret_file(file: &mut Option<String>, dir: &Path) {
+    if let Some(path) = file {
+        *path = dir.join(&*path).to_string_lossy().into_owned();
+    }
+}
+
+// Fill in a secret from its `*_file` counterpart, e.g. `token` from `token_file`
+fn read_secret_file(secret: &mut Option<MaskedString>, file: &Option<String>, key: &str) -> Result<()> {
+    if let Some(path) = file {
+        if secret.is_some() {
+            bail!("`{}` and `{}_file` can't be both set", key, key);
+        }
+        let s = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read `{}_file` {}", key, path))?;
+        // Secret files usually end with a newline
+        *secret = Some(MaskedString::from(s.trim_end_matches(['\r', '\n'])));
+    }
+    Ok(())
+}
+
 impl Debug for MaskedString {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
         f.write_str("MASKED")
@@ -51,6 +118,8 @@ pub enum TransportType {
     Noise,
     #[serde(rename = "websocket")]
     Websocket,
+    #[serde(rename = "http2")]
+    Http2,
 }
 
 /// Per service config
@@ -64,8 +133,14 @@ pub struct ClientServiceConfig {
     pub name: String,
     pub local_addr: String,
     pub token: Option<MaskedString>,
+    pub token_file: Option<String>,
     pub nodelay: Option<bool>,
     pub retry_interval: Option<u64>,
+    // Ask the server to assign a port from the range granted to `client.name`
+    #[serde(default)]
+    pub dynamic: bool,
+    // The port that a dynamic service asks for
+    pub remote_port: Option<u16>,
 }
 
 impl ClientServiceConfig {
@@ -99,9 +174,15 @@ pub struct ServerServiceConfig {
     pub service_type: ServiceType,
     #[serde(skip)]
     pub name: String,
+    // Empty if the service is only reached by `hostnames`
+    #[serde(default)]
     pub bind_addr: String,
     pub token: Option<MaskedString>,
+    pub token_file: Option<String>,
     pub nodelay: Option<bool>,
+    // Visitors of `server.mux` that ask for these hostnames go to this service
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hostnames: Vec<String>,
 }
 
 impl ServerServiceConfig {
@@ -112,6 +193,19 @@ impl ServerServiceConfig {
         }
     }
 }
+
+/// Per client config, which grants a client to declare its own services
+/// The server assigns each of them a port in `bind_addr`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ServerClientConfig {
+    #[serde(skip)]
+    pub name: String,
+    pub bind_addr: String,
+    pub token: Option<MaskedString>,
+    pub token_file: Option<String>,
+    pub max_services: Option<usize>,
+}
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(deny_unknown_fields)]
 pub struct TlsConfig {
@@ -119,6 +213,46 @@ pub struct TlsConfig {
     pub trusted_root: Option<String>,
     pub pkcs12: Option<String>,
     pub pkcs12_password: Option<MaskedString>,
+    pub pkcs12_password_file: Option<String>,
+    #[serde(default = "default_verify_hostname")]
+    pub verify_hostname: bool,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_spki_sha256: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pinned_cert_sha256: Vec<String>,
+    pub min_version: Option<TlsVersion>,
+    pub max_version: Option<TlsVersion>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cipher_suites: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alpn: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    #[serde(rename = "1.0")]
+    Tls10,
+    #[serde(rename = "1.1")]
+    Tls11,
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl std::fmt::Display for TlsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            TlsVersion::Tls10 => "TLS 1.0",
+            TlsVersion::Tls11 => "TLS 1.1",
+            TlsVersion::Tls12 => "TLS 1.2",
+            TlsVersion::Tls13 => "TLS 1.3",
+        })
+    }
+}
+
+fn default_verify_hostname() -> bool {
+    true
 }
 
 fn default_noise_pattern() -> String {
@@ -131,6 +265,7 @@ pub struct NoiseConfig {
     #[serde(default = "default_noise_pattern")]
     pub pattern: String,
     pub local_private_key: Option<MaskedString>,
+    pub local_private_key_file: Option<String>,
     pub remote_public_key: Option<String>,
     // TODO: Maybe psk can be added
 }
@@ -139,6 +274,44 @@ pub struct NoiseConfig {
 #[serde(deny_unknown_fields)]
 pub struct WebsocketConfig {
     pub tls: bool,
+    // The path of the request. The server accepts any path if not set
+    pub path: Option<String>,
+    // The `Host` header that the client sends. The host of `remote_addr` if not set
+    pub host: Option<String>,
+    // Headers that the client sends, and the server requires
+    #[serde(default)]
+    pub headers: HashMap<String, MaskedString>,
+    pub subprotocol: Option<String>,
+    // The path that the server answers with 200, for health checks
+    pub health_path: Option<String>,
+    // How the server answers requests that are not websocket upgrades
+    pub fallback: Option<WebsocketFallbackConfig>,
+}
+
+fn default_fallback_status() -> u16 {
+    404
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct WebsocketFallbackConfig {
+    // Forward the requests to this address instead of answering them
+    pub upstream: Option<String>,
+    #[serde(default = "default_fallback_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub body: String,
+    pub content_type: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Http2Config {
+    pub tls: bool,
+    // The path of the request. The server accepts any path if not set
+    pub path: Option<String>,
+    // The `:authority` that the client sends. `remote_addr` if not set
+    pub host: Option<String>,
 }
 
 fn default_nodelay() -> bool {
@@ -163,6 +336,35 @@ pub struct TcpConfig {
     #[serde(default = "default_keepalive_interval")]
     pub keepalive_interval: u64,
     pub proxy: Option<Url>,
+    #[serde(default)]
+    pub proxy_from_env: bool,
+    pub proxy_auth: Option<ProxyAuthScheme>,
+    // Where outgoing connections leave the host. Ignored by the server, which doesn't make them
+    pub bind_interface: Option<String>,
+    pub source_addr: Option<IpAddr>,
+    pub fwmark: Option<u32>,
+}
+
+/// How to authenticate with an HTTP proxy
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+pub enum ProxyAuthScheme {
+    #[serde(rename = "basic")]
+    Basic,
+    #[serde(rename = "ntlm")]
+    Ntlm,
+    #[serde(rename = "negotiate")]
+    Negotiate,
+}
+
+// As in `Proxy-Authenticate`
+impl std::fmt::Display for ProxyAuthScheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ProxyAuthScheme::Basic => "Basic",
+            ProxyAuthScheme::Ntlm => "NTLM",
+            ProxyAuthScheme::Negotiate => "Negotiate",
+        })
+    }
 }
 
 impl Default for TcpConfig {
@@ -172,6 +374,11 @@ impl Default for TcpConfig {
             keepalive_secs: default_keepalive_secs(),
             keepalive_interval: default_keepalive_interval(),
             proxy: None,
+            proxy_from_env: false,
+            proxy_auth: None,
+            bind_interface: None,
+            source_addr: None,
+            fwmark: None,
         }
     }
 }
@@ -186,6 +393,7 @@ pub struct TransportConfig {
     pub tls: Option<TlsConfig>,
     pub noise: Option<NoiseConfig>,
     pub websocket: Option<WebsocketConfig>,
+    pub http2: Option<Http2Config>,
 }
 
 fn default_heartbeat_timeout() -> u64 {
@@ -196,11 +404,17 @@ fn default_client_retry_interval() -> u64 {
     DEFAULT_CLIENT_RETRY_INTERVAL_SECS
 }
 
+fn default_dns_cache_secs() -> u64 {
+    DEFAULT_DNS_CACHE_SECS
+}
+
 #[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct ClientConfig {
     pub remote_addr: String,
+    pub name: Option<String>,
     pub default_token: Option<MaskedString>,
+    pub default_token_file: Option<String>,
     pub services: HashMap<String, ClientServiceConfig>,
     #[serde(default)]
     pub transport: TransportConfig,
@@ -208,6 +422,10 @@ pub struct ClientConfig {
     pub heartbeat_timeout: u64,
     #[serde(default = "default_client_retry_interval")]
     pub retry_interval: u64,
+    #[serde(default)]
+    pub grace_period: u64,
+    #[serde(default = "default_dns_cache_secs")]
+    pub dns_cache_secs: u64,
 }
 
 fn default_heartbeat_interval() -> u64 {
@@ -219,31 +437,159 @@ fn default_heartbeat_interval() -> u64 {
 pub struct ServerConfig {
     pub bind_addr: String,
     pub default_token: Option<MaskedString>,
+    pub default_token_file: Option<String>,
+    #[serde(default)]
     pub services: HashMap<String, ServerServiceConfig>,
     #[serde(default)]
+    pub clients: HashMap<String, ServerClientConfig>,
+    #[serde(default)]
     pub transport: TransportConfig,
     #[serde(default = "default_heartbeat_interval")]
     pub heartbeat_interval: u64,
+    #[serde(default)]
+    pub grace_period: u64,
+    pub mux: Option<ServerMuxConfig>,
+}
+
+/// A listener shared by services, which routes visitors by the hostname they ask for
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServerMuxConfig {
+    pub bind_addr: String,
+    // Where visitors go if no service matches, or they don't tell a hostname
+    pub default_service: Option<String>,
+}
+
+/// Errors found by validating a config, each with the path of the value in it
+#[derive(Debug, Default)]
+pub struct ConfigErrors(Vec<(String, anyhow::Error)>);
+
+impl ConfigErrors {
+    pub fn check<P: Into<String>, F: FnOnce() -> Result<()>>(&mut self, path: P, f: F) {
+        if let Err(e) = f() {
+            // Flatten the errors of a nested validation
+            match e.downcast::<ConfigErrors>() {
+                Ok(v) => self.0.extend(v.0),
+                Err(e) => self.0.push((path.into(), e)),
+            }
+        }
+    }
+
+    pub fn into_result(self) -> Result<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(self.into())
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, (path, e)) in self.0.iter().enumerate() {
+            if i != 0 {
+                writeln!(f)?;
+            }
+            write!(f, "`{}`: {:#}", path, e)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// Format of a config file, detected by its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    // TOML is assumed if the extension is unknown
+    pub fn from_path(path: &Path) -> ConfigFormat {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            Some("json") => ConfigFormat::Json,
+            _ => ConfigFormat::Toml,
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(self, s: &str) -> Result<T> {
+        Ok(match self {
+            ConfigFormat::Toml => toml::from_str(s)?,
+            ConfigFormat::Yaml => serde_yaml::from_str(s)?,
+            ConfigFormat::Json => serde_json::from_str(s)?,
+        })
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, Default, PartialEq, Eq, Clone)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
+    // Patterns of the files that define more services, relative to this file
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
     pub server: Option<ServerConfig>,
     pub client: Option<ClientConfig>,
+    // Directories of `include`, which are watched for hot reload
+    #[serde(skip)]
+    pub include_dirs: Vec<PathBuf>,
+}
+
+// A file of `include`, which can only define services
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IncludedConfig {
+    server: Option<IncludedServices<ServerServiceConfig>>,
+    client: Option<IncludedServices<ClientServiceConfig>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct IncludedServices<T> {
+    #[serde(default)]
+    services: HashMap<String, T>,
 }
 
 impl Config {
-    fn from_str(s: &str) -> Result<Config> {
-        let mut config: Config = toml::from_str(s).with_context(|| "Failed to parse the config")?;
+    // How long a shutdown waits for in-flight connections, in secs
+    pub fn grace_period(&self) -> u64 {
+        let server = self.server.as_ref().map_or(0, |s| s.grace_period);
+        let client = self.client.as_ref().map_or(0, |c| c.grace_period);
+        server.max(client)
+    }
 
+    // `path` decides the format, and `include` is relative to it
+    pub(crate) fn from_str(s: &str, path: &Path) -> Result<Config> {
+        let mut config: Config = ConfigFormat::from_path(path)
+            .parse(s)
+            .with_context(|| "Failed to parse the config")?;
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        config.resolve_secret_files(dir);
+        config.merge_included(dir)?;
+
+        // Report the errors of both blocks at once
+        let mut errors = ConfigErrors::default();
         if let Some(server) = config.server.as_mut() {
-            Config::validate_server_config(server)?;
+            errors.check("server", || Config::validate_server_config(server));
         }
-
         if let Some(client) = config.client.as_mut() {
-            Config::validate_client_config(client)?;
+            errors.check("client", || Config::validate_client_config(client));
         }
+        // Only a config of both sides can tell if their port ranges match
+        if let (Some(server), Some(client)) = (&config.server, &config.client) {
+            for (name, c) in &client.services {
+                if let Some(s) = server.services.get(name) {
+                    errors.check(format!("client.services.{}", name), || {
+                        check_range_sizes(name, &s.bind_addr, &c.local_addr)
+                    });
+                }
+            }
+        }
+        errors.into_result()?;
 
         if config.server.is_none() && config.client.is_none() {
             Err(anyhow!("Neither of `[server]` or `[client]` is defined"))
@@ -252,53 +598,241 @@ impl Config {
         }
     }
 
+    // `*_file` of secrets are relative to the config file, the same as `include`
+    fn resolve_secret_files(&mut self, dir: &Path) {
+        if let Some(server) = self.server.as_mut() {
+            resolve_secret_file(&mut server.default_token_file, dir);
+            for s in server.services.values_mut() {
+                resolve_secret_file(&mut s.token_file, dir);
+            }
+            for c in server.clients.values_mut() {
+                resolve_secret_file(&mut c.token_file, dir);
+            }
+            Config::resolve_transport_secret_files(&mut server.transport, dir);
+        }
+        if let Some(client) = self.client.as_mut() {
+            resolve_secret_file(&mut client.default_token_file, dir);
+            for s in client.services.values_mut() {
+                resolve_secret_file(&mut s.token_file, dir);
+            }
+            Config::resolve_transport_secret_files(&mut client.transport, dir);
+        }
+    }
+
+    fn resolve_transport_secret_files(config: &mut TransportConfig, dir: &Path) {
+        if let Some(tls) = config.tls.as_mut() {
+            resolve_secret_file(&mut tls.pkcs12_password_file, dir);
+        }
+        if let Some(noise) = config.noise.as_mut() {
+            resolve_secret_file(&mut noise.local_private_key_file, dir);
+        }
+    }
+
+    fn merge_included(&mut self, dir: &Path) -> Result<()> {
+        let (include_dirs, included) = resolve_include(&self.include, dir)?;
+        self.include_dirs.extend(include_dirs);
+
+        for path in included {
+            let s = std::fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read the included config {:?}", path))?;
+            let mut v: IncludedConfig = ConfigFormat::from_path(&path)
+                .parse(&s)
+                .with_context(|| format!("Failed to parse the included config {:?}", path))?;
+            let file_dir = path.parent().unwrap_or(dir);
+            for s in v.server.iter_mut().flat_map(|v| v.services.values_mut()) {
+                resolve_secret_file(&mut s.token_file, file_dir);
+            }
+            for s in v.client.iter_mut().flat_map(|v| v.services.values_mut()) {
+                resolve_secret_file(&mut s.token_file, file_dir);
+            }
+
+            if let Some(v) = v.server {
+                let server = self.server.as_mut().ok_or_else(|| {
+                    anyhow!("{:?} defines server services, but `[server]` is not defined", path)
+                })?;
+                merge_services(&mut server.services, v.services, &path)?;
+            }
+            if let Some(v) = v.client {
+                let client = self.client.as_mut().ok_or_else(|| {
+                    anyhow!("{:?} defines client services, but `[client]` is not defined", path)
+                })?;
+                merge_services(&mut client.services, v.services, &path)?;
+            }
+        }
+        Ok(())
+    }
+
     fn validate_server_config(server: &mut ServerConfig) -> Result<()> {
+        let mut errors = ConfigErrors::default();
+        errors.check("server.default_token_file", || {
+            read_secret_file(
+                &mut server.default_token,
+                &server.default_token_file,
+                "default_token",
+            )
+        });
+
         // Validate services
         for (name, s) in &mut server.services {
-            s.name = name.clone();
-            if s.token.is_none() {
-                s.token = server.default_token.clone();
+            errors.check(format!("server.services.{}", name), || {
+                s.name = name.clone();
+                read_secret_file(&mut s.token, &s.token_file, "token")?;
                 if s.token.is_none() {
-                    bail!("The token of service {} is not set", name);
+                    s.token = server.default_token.clone();
+                    if s.token.is_none() {
+                        bail!("The token of service {} is not set", name);
+                    }
+                }
+                if s.bind_addr.is_empty() && s.hostnames.is_empty() {
+                    bail!("Either `bind_addr` or `hostnames` of service {} must be set", name);
+                }
+                if !s.bind_addr.is_empty() {
+                    let (_, ports) = host_port_range(&s.bind_addr)
+                        .with_context(|| format!("Invalid `bind_addr` of service {}", name))?;
+                    if !s.hostnames.is_empty() && ports.start() != ports.end() {
+                        bail!("Service {} can't have both a port range and `hostnames`", name);
+                    }
+                }
+                if !s.hostnames.is_empty() {
+                    if server.mux.is_none() {
+                        bail!("`hostnames` of service {} needs `server.mux`", name);
+                    }
+                    if s.service_type != ServiceType::Tcp {
+                        bail!("Service {} has `hostnames`, which only TCP services can have", name);
+                    }
+                    for h in &s.hostnames {
+                        let name = h.strip_prefix("*.").unwrap_or(h);
+                        if name.is_empty() || name.contains('*') {
+                            bail!("Invalid hostname {}", h);
+                        }
+                    }
+                }
+                Ok(())
+            });
+        }
+
+        let mut hostnames = HashMap::new();
+        for (name, s) in &server.services {
+            for h in &s.hostnames {
+                if let Some(other) = hostnames.insert(h.to_ascii_lowercase(), name) {
+                    errors.check(format!("server.services.{}", name), || {
+                        bail!("Hostname {} is also used by service {}", h, other)
+                    });
                 }
             }
         }
 
-        Config::validate_transport_config(&server.transport, true)?;
+        if let Some(mux) = &server.mux {
+            errors.check("server.mux", || {
+                let (_, ports) = host_port_range(&mux.bind_addr)
+                    .with_context(|| "Invalid `bind_addr` of `server.mux`")?;
+                if ports.start() != ports.end() {
+                    bail!("`bind_addr` of `server.mux` can't be a port range");
+                }
+                if let Some(name) = &mux.default_service {
+                    match server.services.get(name) {
+                        Some(s) if s.service_type == ServiceType::Tcp => (),
+                        Some(_) => bail!("`default_service` {} is not a TCP service", name),
+                        None => bail!("`default_service` {} is not defined", name),
+                    }
+                }
+                Ok(())
+            });
+        }
 
-        Ok(())
+        // Validate clients
+        for (name, c) in &mut server.clients {
+            errors.check(format!("server.clients.{}", name), || {
+                c.name = name.clone();
+                read_secret_file(&mut c.token, &c.token_file, "token")?;
+                if c.token.is_none() {
+                    c.token = server.default_token.clone();
+                    if c.token.is_none() {
+                        bail!("The token of client {} is not set", name);
+                    }
+                }
+                host_port_range(&c.bind_addr)
+                    .with_context(|| format!("Invalid `bind_addr` of client {}", name))?;
+                Ok(())
+            });
+        }
+
+        errors.check("server.transport", || {
+            Config::validate_transport_config(&mut server.transport, true)
+        });
+
+        errors.into_result()
     }
 
     fn validate_client_config(client: &mut ClientConfig) -> Result<()> {
+        let mut errors = ConfigErrors::default();
+        errors.check("client.default_token_file", || {
+            read_secret_file(
+                &mut client.default_token,
+                &client.default_token_file,
+                "default_token",
+            )
+        });
+
         // Validate services
         for (name, s) in &mut client.services {
-            s.name = name.clone();
-            if s.token.is_none() {
-                s.token = client.default_token.clone();
+            errors.check(format!("client.services.{}", name), || {
+                s.name = name.clone();
+                read_secret_file(&mut s.token, &s.token_file, "token")?;
                 if s.token.is_none() {
-                    bail!("The token of service {} is not set", name);
+                    s.token = client.default_token.clone();
+                    if s.token.is_none() {
+                        bail!("The token of service {} is not set", name);
+                    }
                 }
-            }
-            if s.retry_interval.is_none() {
-                s.retry_interval = Some(client.retry_interval);
-            }
+                if s.retry_interval.is_none() {
+                    s.retry_interval = Some(client.retry_interval);
+                }
+                host_port_range(&s.local_addr)
+                    .with_context(|| format!("Invalid `local_addr` of service {}", name))?;
+                if s.dynamic && client.name.is_none() {
+                    bail!("The service {} is dynamic, but `client.name` is not set", name);
+                }
+                if s.remote_port.is_some() && !s.dynamic {
+                    bail!("`remote_port` of service {} requires `dynamic = true`", name);
+                }
+                Ok(())
+            });
         }
 
-        Config::validate_transport_config(&client.transport, false)?;
+        errors.check("client.transport", || {
+            Config::validate_transport_config(&mut client.transport, false)
+        });
 
-        Ok(())
+        errors.into_result()
     }
+    fn validate_transport_config(config: &mut TransportConfig, is_server: bool) -> Result<()> {
+        if let Some(tls) = config.tls.as_mut() {
+            read_secret_file(
+                &mut tls.pkcs12_password,
+                &tls.pkcs12_password_file,
+                "pkcs12_password",
+            )?;
+            if let (Some(min), Some(max)) = (tls.min_version, tls.max_version) {
+                if min > max {
+                    bail!("`min_version` {} is higher than `max_version` {}", min, max);
+                }
+            }
+        }
+        if let Some(noise) = config.noise.as_mut() {
+            read_secret_file(
+                &mut noise.local_private_key,
+                &noise.local_private_key_file,
+                "local_private_key",
+            )?;
+        }
 
-    fn validate_transport_config(config: &TransportConfig, is_server: bool) -> Result<()> {
-        config
-            .tcp
-            .proxy
-            .as_ref()
-            .map_or(Ok(()), |u| match u.scheme() {
-                "socks5" => Ok(()),
-                "http" => Ok(()),
-                _ => Err(anyhow!(format!("Unknown proxy scheme: {}", u.scheme()))),
-            })?;
+        if let Some(proxy) = &config.tcp.proxy {
+            check_proxy_url(proxy)?;
+            if config.tcp.proxy_auth.is_some() && !proxy.scheme().starts_with("http") {
+                bail!("`proxy_auth` is only for HTTP proxies");
+            }
+        }
         match config.transport_type {
             TransportType::Tcp => Ok(()),
             TransportType::Tls => {
@@ -320,6 +854,13 @@ impl Config {
                 Ok(())
             }
             TransportType::Websocket => Ok(()),
+            TransportType::Http2 => {
+                config
+                    .http2
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("Missing HTTP/2 configuration"))?;
+                Ok(())
+            }
         }
     }
 
@@ -349,7 +890,7 @@ impl Config {
             }
         }
         
-        Config::from_str(&s).with_context(|| {
+        Config::from_str(&s, path).with_context(|| {
             "Configuration is invalid. Please refer to the configuration specification."
         })
     }
@@ -472,6 +1013,75 @@ pub fn process_external_socket_config(config_data: &str) -> Result<()> {
     Ok(())
 }
 
+// The ports of a range go to the ports at the same offsets in `local_addr`, so its range must be
+// as large. A single port in `local_addr` takes all of them
+fn check_range_sizes(name: &str, bind_addr: &str, local_addr: &str) -> Result<()> {
+    // Invalid addresses are reported by the validation of each side
+    let (Ok((_, remote)), Ok((_, local))) = (host_port_range(bind_addr), host_port_range(local_addr))
+    else {
+        return Ok(());
+    };
+    if local.start() != local.end() && local.len() != remote.len() {
+        bail!(
+            "`local_addr` of service {} has {} ports, but its `bind_addr` on the server has {}",
+            name,
+            local.len(),
+            remote.len()
+        );
+    }
+    Ok(())
+}
+
+// Resolve the patterns of `include`, which are relative to `dir`.
+// Returns the directories of the patterns, and the files they match, each only once
+pub(crate) fn resolve_include(
+    include: &[String],
+    dir: &Path,
+) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let mut include_dirs = Vec::new();
+    let mut included = Vec::new();
+    let mut seen = HashSet::new();
+    for pattern in include {
+        let pattern = dir.join(pattern);
+        let pattern_str = pattern
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid `include` {:?}", pattern))?;
+        let include_dir = pattern.parent().unwrap_or(dir);
+        let include_dir_str = include_dir.to_string_lossy();
+        if glob::Pattern::escape(&include_dir_str) != include_dir_str {
+            bail!(
+                "Wildcards are only supported in file names of `include`: {}",
+                pattern_str
+            );
+        }
+        include_dirs.push(include_dir.to_owned());
+
+        for path in glob::glob(pattern_str)
+            .with_context(|| format!("Invalid `include` {}", pattern_str))?
+        {
+            let path = path?;
+            // The same file may match more than one pattern
+            if seen.insert(path.clone()) {
+                included.push(path);
+            }
+        }
+    }
+    Ok((include_dirs, included))
+}
+
+fn merge_services<T>(
+    services: &mut HashMap<String, T>,
+    included: HashMap<String, T>,
+    path: &Path,
+) -> Result<()> {
+    for (name, s) in included {
+        if services.insert(name.clone(), s).is_some() {
+            bail!("The service {} in {:?} is already defined", name, path);
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -504,8 +1114,8 @@ mod tests {
     fn test_example_config() -> Result<()> {
         let paths = get_all_example_config()?;
         for p in paths {
-            let s = fs::read_to_string(p)?;
-            Config::from_str(&s)?;
+            let s = fs::read_to_string(&p)?;
+            Config::from_str(&s, &p)?;
         }
         Ok(())
     }
@@ -514,8 +1124,8 @@ mod tests {
     fn test_valid_config() -> Result<()> {
         let paths = list_config_files("tests/config_test/valid_config")?;
         for p in paths {
-            let s = fs::read_to_string(p)?;
-            Config::from_str(&s)?;
+            let s = fs::read_to_string(&p)?;
+            Config::from_str(&s, &p)?;
         }
         Ok(())
     }
@@ -524,12 +1134,86 @@ mod tests {
     fn test_invalid_config() -> Result<()> {
         let paths = list_config_files("tests/config_test/invalid_config")?;
         for p in paths {
-            let s = fs::read_to_string(p)?;
-            assert!(Config::from_str(&s).is_err());
+            let s = fs::read_to_string(&p)?;
+            assert!(Config::from_str(&s, &p).is_err());
         }
         Ok(())
     }
 
+    #[test]
+    fn test_report_every_error() {
+        let s = r#"
+[server]
+bind_addr = "0.0.0.0:2333"
+
+[server.services.foo]
+bind_addr = "0.0.0.0:8080"
+
+[client]
+remote_addr = "example.com:2333"
+default_token = "whatever"
+
+[client.services.bar]
+local_addr = "127.0.0.1:80"
+remote_port = 30000
+"#;
+        let err = Config::from_str(s, Path::new("config.toml")).unwrap_err();
+        let errors = err.downcast_ref::<ConfigErrors>().unwrap();
+        let paths: Vec<_> = errors.0.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(paths, ["server.services.foo", "client.services.bar"]);
+    }
+
+    #[test]
+    fn test_secret() -> Result<()> {
+        std::env::set_var("RATHOLE_TEST_SECRET", "s3cr3t");
+        assert_eq!(expand_env("a${RATHOLE_TEST_SECRET}b$c")?, "as3cr3tb$c");
+        let err = expand_env("${RATHOLE_TEST_MISSING}").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Environment variable RATHOLE_TEST_MISSING is not set"
+        );
+        assert!(expand_env("${RATHOLE_TEST_SECRET").is_err());
+        // `$${` is a literal `${`
+        assert_eq!(
+            expand_env("$${RATHOLE_TEST_SECRET}${RATHOLE_TEST_SECRET}")?,
+            "${RATHOLE_TEST_SECRET}s3cr3t"
+        );
+        assert_eq!(expand_env("a$${")?, "a${");
+
+        let path = Path::new("tests/config_test/valid_config/secret_file.toml");
+        let s = fs::read_to_string(path)?;
+        let cfg = Config::from_str(&s, path)?;
+        assert_eq!(
+            cfg.server.unwrap().services["ssh"].token,
+            Some(MaskedString::from("secret_from_file"))
+        );
+        assert_eq!(
+            cfg.client.unwrap().services["ssh"].token,
+            Some(MaskedString::from("secret_from_file"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_include() -> Result<()> {
+        let dir = Path::new("tests/config_test/valid_config");
+        let path = dir.join("include.toml");
+        let s = fs::read_to_string(&path)?;
+        let cfg = Config::from_str(&s, &path)?;
+
+        // `token_file` is relative to the included file
+        assert_eq!(
+            cfg.server.as_ref().unwrap().services["web"].token,
+            Some(MaskedString::from("secret_from_file"))
+        );
+        let mut names: Vec<_> = cfg.server.unwrap().services.into_keys().collect();
+        names.sort();
+        assert_eq!(names, ["rdp", "ssh", "web"]);
+        assert_eq!(cfg.client.unwrap().services["web"].name, "web");
+        assert_eq!(cfg.include_dirs, [dir.join("../include")]);
+        Ok(())
+    }
+
     #[test]
     fn test_validate_server_config() -> Result<()> {
         let mut cfg = ServerConfig::default();
@@ -1,16 +1,24 @@
 use crate::{
-    config::{ClientConfig, ClientServiceConfig, ServerConfig, ServerServiceConfig},
+    config::{
+        ClientConfig, ClientServiceConfig, ServerClientConfig, ServerConfig, ServerServiceConfig,
+        TransportConfig,
+    },
     Config,
     helper::send_config_to_service,
 };
 use anyhow::{Context, Result};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     env,
+    ffi::OsStr,
+    fmt,
     io::Read,
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 use tokio::sync::{broadcast, mpsc};
+use tokio::time;
 use tracing::{error, info, instrument};
 use anyhow;
 
@@ -22,42 +30,96 @@ pub enum ConfigChange {
     General(Box<Config>), // Trigger a full restart
     ServerChange(ServerServiceChange),
     ClientChange(ClientServiceChange),
+    ServerSettingChange(ServerSettingChange),
+    ClientSettingChange(ClientSettingChange),
+}
+
+// Settings of `[server]` that are applied in place
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ServerSettingChange {
+    HeartbeatInterval(u64),
+    GracePeriod(u64),
+    // Same transport type, but different options or TLS/Noise material
+    Transport(Box<TransportConfig>),
+    // `server.clients`. Dynamic services of the clients that are changed or removed are dropped
+    Clients(HashMap<String, ServerClientConfig>),
+}
+
+// Settings of `[client]` that are applied in place
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum ClientSettingChange {
+    HeartbeatTimeout(u64),
+    GracePeriod(u64),
+    // Same transport type, but different options or TLS/Noise material
+    Transport(Box<TransportConfig>),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum ClientServiceChange {
     Add(ClientServiceConfig),
     Delete(String),
+    // A service with the same name but different settings
+    Update(ClientServiceConfig),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum ServerServiceChange {
     Add(ServerServiceConfig),
     Delete(String),
+    // A service with the same name but different settings
+    Update(ServerServiceConfig),
 }
 
 trait InstanceConfig: Clone {
     type ServiceConfig: PartialEq + Eq + Clone;
-    fn equal_without_service(&self, rhs: &Self) -> bool;
+    // Changes of the settings besides services. None indicates a General change needed
+    fn setting_changes(&self, rhs: &Self) -> Option<Vec<ConfigChange>>;
     fn service_delete_change(s: String) -> ConfigChange;
     fn service_add_change(cfg: Self::ServiceConfig) -> ConfigChange;
+    fn service_update_change(cfg: Self::ServiceConfig) -> ConfigChange;
     fn get_services(&self) -> &HashMap<String, Self::ServiceConfig>;
 }
 
 impl InstanceConfig for ServerConfig {
     type ServiceConfig = ServerServiceConfig;
-    fn equal_without_service(&self, rhs: &Self) -> bool {
-        let left = ServerConfig {
+    fn setting_changes(&self, rhs: &Self) -> Option<Vec<ConfigChange>> {
+        // `default_token` is already filled into every service when validating,
+        // so a change of it shows up as changes of services
+        let strip = |c: &Self| ServerConfig {
+            default_token: None,
             services: Default::default(),
-            ..self.clone()
-        };
-
-        let right = ServerConfig {
-            services: Default::default(),
-            ..rhs.clone()
+            clients: Default::default(),
+            transport: transport_type_only(&c.transport),
+            heartbeat_interval: 0,
+            grace_period: 0,
+            ..c.clone()
         };
+        if strip(self) != strip(rhs) {
+            return None;
+        }
 
-        left == right
+        let mut ret = vec![];
+        if self.heartbeat_interval != rhs.heartbeat_interval {
+            ret.push(ServerSettingChange::HeartbeatInterval(
+                rhs.heartbeat_interval,
+            ));
+        }
+        if self.grace_period != rhs.grace_period {
+            ret.push(ServerSettingChange::GracePeriod(rhs.grace_period));
+        }
+        if self.transport != rhs.transport {
+            ret.push(ServerSettingChange::Transport(Box::new(
+                rhs.transport.clone(),
+            )));
+        }
+        if self.clients != rhs.clients {
+            ret.push(ServerSettingChange::Clients(rhs.clients.clone()));
+        }
+        Some(
+            ret.into_iter()
+                .map(ConfigChange::ServerSettingChange)
+                .collect(),
+        )
     }
     fn service_delete_change(s: String) -> ConfigChange {
         ConfigChange::ServerChange(ServerServiceChange::Delete(s))
@@ -65,6 +127,9 @@ impl InstanceConfig for ServerConfig {
     fn service_add_change(cfg: Self::ServiceConfig) -> ConfigChange {
         ConfigChange::ServerChange(ServerServiceChange::Add(cfg))
     }
+    fn service_update_change(cfg: Self::ServiceConfig) -> ConfigChange {
+        ConfigChange::ServerChange(ServerServiceChange::Update(cfg))
+    }
     fn get_services(&self) -> &HashMap<String, Self::ServiceConfig> {
         &self.services
     }
@@ -72,18 +137,39 @@ impl InstanceConfig for ServerConfig {
 
 impl InstanceConfig for ClientConfig {
     type ServiceConfig = ClientServiceConfig;
-    fn equal_without_service(&self, rhs: &Self) -> bool {
-        let left = ClientConfig {
-            services: Default::default(),
-            ..self.clone()
-        };
-
-        let right = ClientConfig {
+    fn setting_changes(&self, rhs: &Self) -> Option<Vec<ConfigChange>> {
+        // `default_token` and `retry_interval` are already filled into every service
+        // when validating, so a change of them shows up as changes of services
+        let strip = |c: &Self| ClientConfig {
+            default_token: None,
             services: Default::default(),
-            ..rhs.clone()
+            transport: transport_type_only(&c.transport),
+            heartbeat_timeout: 0,
+            retry_interval: 0,
+            grace_period: 0,
+            ..c.clone()
         };
+        if strip(self) != strip(rhs) {
+            return None;
+        }
 
-        left == right
+        let mut ret = vec![];
+        if self.heartbeat_timeout != rhs.heartbeat_timeout {
+            ret.push(ClientSettingChange::HeartbeatTimeout(rhs.heartbeat_timeout));
+        }
+        if self.grace_period != rhs.grace_period {
+            ret.push(ClientSettingChange::GracePeriod(rhs.grace_period));
+        }
+        if self.transport != rhs.transport {
+            ret.push(ClientSettingChange::Transport(Box::new(
+                rhs.transport.clone(),
+            )));
+        }
+        Some(
+            ret.into_iter()
+                .map(ConfigChange::ClientSettingChange)
+                .collect(),
+        )
     }
     fn service_delete_change(s: String) -> ConfigChange {
         ConfigChange::ClientChange(ClientServiceChange::Delete(s))
@@ -91,11 +177,42 @@ impl InstanceConfig for ClientConfig {
     fn service_add_change(cfg: Self::ServiceConfig) -> ConfigChange {
         ConfigChange::ClientChange(ClientServiceChange::Add(cfg))
     }
+    fn service_update_change(cfg: Self::ServiceConfig) -> ConfigChange {
+        ConfigChange::ClientChange(ClientServiceChange::Update(cfg))
+    }
     fn get_services(&self) -> &HashMap<String, Self::ServiceConfig> {
         &self.services
     }
 }
 
+// Switching the transport type needs a rebind, while the options of it don't
+fn transport_type_only(c: &TransportConfig) -> TransportConfig {
+    TransportConfig {
+        transport_type: c.transport_type,
+        ..Default::default()
+    }
+}
+
+// How long the file must stay quiet before it's reloaded.
+// Editors and config management tools usually touch it several times in a row
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy)]
+enum ReloadTrigger {
+    #[cfg_attr(not(feature = "notify"), allow(dead_code))]
+    FileChanged,
+    Sighup,
+}
+
+impl fmt::Display for ReloadTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReloadTrigger::FileChanged => write!(f, "file change"),
+            ReloadTrigger::Sighup => write!(f, "SIGHUP"),
+        }
+    }
+}
+
 pub struct ConfigWatcherHandle {
     pub event_rx: mpsc::UnboundedReceiver<ConfigChange>,
 }
@@ -121,45 +238,56 @@ impl ConfigWatcherHandle {
     }
 }
 
-// Fake config watcher when compiling without `notify`
+// Config watcher when compiling without `notify`, which only reloads on SIGHUP
 #[cfg(not(feature = "notify"))]
 async fn config_watcher(
-    _path: PathBuf,
-    mut shutdown_rx: broadcast::Receiver<bool>,
-    _event_tx: mpsc::UnboundedSender<ConfigChange>,
-    _old: Config,
+    path: PathBuf,
+    shutdown_rx: broadcast::Receiver<bool>,
+    event_tx: mpsc::UnboundedSender<ConfigChange>,
+    old: Config,
 ) -> Result<()> {
-    // Do nothing except waiting for ctrl-c
-    let _ = shutdown_rx.recv().await;
-    Ok(())
+    let (trigger_tx, trigger_rx) = mpsc::unbounded_channel();
+    forward_sighup(trigger_tx)?;
+    reload_on_trigger(&path, trigger_rx, shutdown_rx, event_tx, old, |_| ()).await
 }
 
 #[cfg(feature = "notify")]
 #[instrument(skip(shutdown_rx, event_tx, old))]
 async fn config_watcher(
     path: PathBuf,
-    mut shutdown_rx: broadcast::Receiver<bool>,
+    shutdown_rx: broadcast::Receiver<bool>,
     event_tx: mpsc::UnboundedSender<ConfigChange>,
-    mut old: Config,
+    old: Config,
 ) -> Result<()> {
-    let (fevent_tx, mut fevent_rx) = mpsc::unbounded_channel();
+    let (fevent_tx, fevent_rx) = mpsc::unbounded_channel();
+    forward_sighup(fevent_tx.clone())?;
+    let cwd = env::current_dir()?;
     let path = if path.is_absolute() {
         path
     } else {
-        env::current_dir()?.join(path)
+        cwd.join(path)
     };
     let parent_path = path.parent().expect("config file should have a parent dir");
     let path_clone = path.clone();
+    // Directories of `include`. Any change in them triggers a reload
+    let include_dirs = Arc::new(Mutex::new(HashSet::<PathBuf>::new()));
+    let include_dirs_clone = include_dirs.clone();
     let mut watcher =
         notify::recommended_watcher(move |res: Result<notify::Event, _>| match res {
             Ok(e) => {
-                if matches!(e.kind, EventKind::Modify(_))
-                    && e.paths
-                        .iter()
-                        .map(|x| x.file_name())
-                        .any(|x| x == path_clone.file_name())
-                {
-                    let _ = fevent_tx.send(true);
+                // Atomic replacement shows up as create, rename or remove events.
+                // Kubernetes swaps the `..data` symlink of a ConfigMap volume instead
+                if matches!(
+                    e.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                ) && e.paths.iter().any(|x| {
+                    let name = x.file_name();
+                    name == path_clone.file_name()
+                        || name == Some(OsStr::new("..data"))
+                        || x.parent()
+                            .is_some_and(|p| include_dirs_clone.lock().unwrap().contains(p))
+                }) {
+                    let _ = fevent_tx.send(ReloadTrigger::FileChanged);
                 }
             }
             Err(e) => error!("watch error: {:#}", e),
@@ -198,32 +326,87 @@ async fn config_watcher(
         }
     }
 
+    // Keep watching the directories of `include` of the current config
+    let watch_included = move |cfg: &Config| {
+        let dirs: HashSet<PathBuf> = cfg.include_dirs.iter().map(|d| cwd.join(d)).collect();
+        // Don't hold the lock while (un)watching, which waits for the watcher thread
+        let watched = include_dirs.lock().unwrap().clone();
+        for d in watched.difference(&dirs).filter(|&d| d != parent_path) {
+            let _ = watcher.unwatch(d);
+        }
+        for d in dirs.difference(&watched).filter(|&d| d != parent_path) {
+            if let Err(e) = watcher.watch(d, RecursiveMode::NonRecursive) {
+                error!("Failed to watch {:?}: {:#}", d, e);
+            }
+        }
+        *include_dirs.lock().unwrap() = dirs;
+    };
+
+    reload_on_trigger(&path, fevent_rx, shutdown_rx, event_tx, old, watch_included).await
+}
+
+#[cfg(unix)]
+fn forward_sighup(trigger_tx: mpsc::UnboundedSender<ReloadTrigger>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sighup =
+        signal(SignalKind::hangup()).with_context(|| "Failed to listen for SIGHUP")?;
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            if trigger_tx.send(ReloadTrigger::Sighup).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn forward_sighup(_trigger_tx: mpsc::UnboundedSender<ReloadTrigger>) -> Result<()> {
+    Ok(())
+}
+
+async fn reload_on_trigger(
+    path: &Path,
+    mut trigger_rx: mpsc::UnboundedReceiver<ReloadTrigger>,
+    mut shutdown_rx: broadcast::Receiver<bool>,
+    event_tx: mpsc::UnboundedSender<ConfigChange>,
+    mut old: Config,
+    // Called with every config loaded
+    mut on_load: impl FnMut(&Config),
+) -> Result<()> {
+    on_load(&old);
     loop {
         tokio::select! {
-          e = fevent_rx.recv() => {
-            match e {
-              Some(_) => {
-                    info!("Rescan the configuration");
-                    let new = match Config::from_file(&path).await.with_context(|| "The changed configuration is invalid. Ignored") {
-                      Ok(v) => v,
-                      Err(e) => {
+            e = trigger_rx.recv() => {
+                let trigger = match e {
+                    Some(v) => v,
+                    None => break,
+                };
+
+                // Wait for the burst of events to settle
+                while let Ok(Some(_)) = time::timeout(RELOAD_DEBOUNCE, trigger_rx.recv()).await {}
+
+                info!("Rescan the configuration on {}", trigger);
+                let new = match Config::from_file(path).await.with_context(|| "The changed configuration is invalid. Ignored") {
+                    Ok(v) => v,
+                    Err(e) => {
                         error!("{:#}", e);
                         // If the config is invalid, just ignore it
                         continue;
-                      }
-                    };
-
-                    let events = calculate_events(&old, &new).into_iter().flatten();
-                    for event in events {
-                        event_tx.send(event)?;
                     }
+                };
 
-                    old = new;
-              },
-              None => break
-            }
-          },
-          _ = shutdown_rx.recv() => break
+                let events = calculate_events(&old, &new).unwrap_or_default();
+                info!("Configuration changes: {}", diff_summary(&events));
+                for event in events {
+                    event_tx.send(event)?;
+                }
+
+                on_load(&new);
+                old = new;
+            },
+            _ = shutdown_rx.recv() => break
         }
     }
 
@@ -232,6 +415,43 @@ async fn config_watcher(
     Ok(())
 }
 
+// A short description of the changes, like `+server.services.foo, client.transport`
+fn diff_summary(events: &[ConfigChange]) -> String {
+    if events.is_empty() {
+        return String::from("none");
+    }
+
+    let mut v: Vec<String> = events
+        .iter()
+        .map(|e| match e {
+            ConfigChange::General(_) => String::from("restart"),
+            ConfigChange::ServerChange(sc) => match sc {
+                ServerServiceChange::Add(c) => format!("+server.services.{}", c.name),
+                ServerServiceChange::Delete(s) => format!("-server.services.{}", s),
+                ServerServiceChange::Update(c) => format!("~server.services.{}", c.name),
+            },
+            ConfigChange::ClientChange(cc) => match cc {
+                ClientServiceChange::Add(c) => format!("+client.services.{}", c.name),
+                ClientServiceChange::Delete(s) => format!("-client.services.{}", s),
+                ClientServiceChange::Update(c) => format!("~client.services.{}", c.name),
+            },
+            ConfigChange::ServerSettingChange(sc) => String::from(match sc {
+                ServerSettingChange::HeartbeatInterval(_) => "server.heartbeat_interval",
+                ServerSettingChange::GracePeriod(_) => "server.grace_period",
+                ServerSettingChange::Transport(_) => "server.transport",
+                ServerSettingChange::Clients(_) => "server.clients",
+            }),
+            ConfigChange::ClientSettingChange(cc) => String::from(match cc {
+                ClientSettingChange::HeartbeatTimeout(_) => "client.heartbeat_timeout",
+                ClientSettingChange::GracePeriod(_) => "client.grace_period",
+                ClientSettingChange::Transport(_) => "client.transport",
+            }),
+        })
+        .collect();
+    v.sort();
+    v.join(", ")
+}
+
 fn calculate_events(old: &Config, new: &Config) -> Option<Vec<ConfigChange>> {
     if old == new {
         return None;
@@ -289,9 +509,7 @@ fn calculate_instance_config_events<T: InstanceConfig>(
     old: &T,
     new: &T,
 ) -> Option<Vec<ConfigChange>> {
-    if !old.equal_without_service(new) {
-        return None;
-    }
+    let settings = old.setting_changes(new)?;
 
     let old = old.get_services();
     let new = new.get_services();
@@ -303,15 +521,27 @@ fn calculate_instance_config_events<T: InstanceConfig>(
 
     let addition = new
         .iter()
-        .filter(|(name, c)| old.get(*name) != Some(*c))
+        .filter(|(name, _)| old.get(*name).is_none())
         .map(|(_, c)| T::service_add_change(c.clone()));
 
-    Some(deletions.chain(addition).collect())
+    let modification = new
+        .iter()
+        .filter(|(name, c)| matches!(old.get(*name), Some(o) if o != *c))
+        .map(|(_, c)| T::service_update_change(c.clone()));
+
+    Some(
+        settings
+            .into_iter()
+            .chain(deletions)
+            .chain(addition)
+            .chain(modification)
+            .collect(),
+    )
 }
 
 #[cfg(test)]
 mod test {
-    use crate::config::ServerConfig;
+    use crate::config::{MaskedString, ServerConfig, TcpConfig, TlsConfig, TransportType};
 
     use super::*;
 
@@ -336,10 +566,12 @@ mod test {
                 old: Config {
                     server: Some(Default::default()),
                     client: None,
+                    ..Default::default()
                 },
                 new: Config {
                     server: Some(Default::default()),
                     client: Some(Default::default()),
+                    ..Default::default()
                 },
             },
             Test {
@@ -349,6 +581,7 @@ mod test {
                         ..Default::default()
                     }),
                     client: None,
+                    ..Default::default()
                 },
                 new: Config {
                     server: Some(ServerConfig {
@@ -357,12 +590,14 @@ mod test {
                         ..Default::default()
                     }),
                     client: None,
+                    ..Default::default()
                 },
             },
             Test {
                 old: Config {
                     server: Some(Default::default()),
                     client: None,
+                    ..Default::default()
                 },
                 new: Config {
                     server: Some(ServerConfig {
@@ -370,6 +605,7 @@ mod test {
                         ..Default::default()
                     }),
                     client: None,
+                    ..Default::default()
                 },
             },
             Test {
@@ -379,10 +615,12 @@ mod test {
                         ..Default::default()
                     }),
                     client: None,
+                    ..Default::default()
                 },
                 new: Config {
                     server: Some(Default::default()),
                     client: None,
+                    ..Default::default()
                 },
             },
             Test {
@@ -395,6 +633,7 @@ mod test {
                         services: collection!(String::from("foo1") => ClientServiceConfig::with_name("foo1"), String::from("foo2") => ClientServiceConfig::with_name("foo2")),
                         ..Default::default()
                     }),
+                    ..Default::default()
                 },
                 new: Config {
                     server: Some(ServerConfig {
@@ -405,6 +644,196 @@ mod test {
                         services: collection!(String::from("bar1") => ClientServiceConfig::with_name("bar1"), String::from("bar2") => ClientServiceConfig::with_name("bar2")),
                         ..Default::default()
                     }),
+                    ..Default::default()
+                },
+            },
+            Test {
+                old: Config {
+                    server: Some(Default::default()),
+                    client: None,
+                    ..Default::default()
+                },
+                new: Config {
+                    server: Some(ServerConfig {
+                        heartbeat_interval: 10,
+                        grace_period: 5,
+                        ..Default::default()
+                    }),
+                    client: None,
+                    ..Default::default()
+                },
+            },
+            Test {
+                old: Config {
+                    server: None,
+                    client: Some(Default::default()),
+                    ..Default::default()
+                },
+                new: Config {
+                    server: None,
+                    client: Some(ClientConfig {
+                        heartbeat_timeout: 10,
+                        grace_period: 5,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            },
+            Test {
+                old: Config {
+                    server: Some(Default::default()),
+                    client: None,
+                    ..Default::default()
+                },
+                new: Config {
+                    server: Some(ServerConfig {
+                        transport: TransportConfig {
+                            tcp: TcpConfig {
+                                nodelay: false,
+                                ..Default::default()
+                            },
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
+                    client: None,
+                    ..Default::default()
+                },
+            },
+            Test {
+                old: Config {
+                    server: None,
+                    client: Some(ClientConfig {
+                        transport: TransportConfig {
+                            transport_type: TransportType::Tls,
+                            tls: Some(TlsConfig {
+                                hostname: None,
+                                trusted_root: Some(String::from("old.crt")),
+                                pkcs12: None,
+                                pkcs12_password: None,
+                                pkcs12_password_file: None,
+                                verify_hostname: true,
+                                pinned_spki_sha256: Vec::new(),
+                                pinned_cert_sha256: Vec::new(),
+                                min_version: None,
+                                max_version: None,
+                                cipher_suites: Vec::new(),
+                                alpn: Vec::new(),
+                            }),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                new: Config {
+                    server: None,
+                    client: Some(ClientConfig {
+                        transport: TransportConfig {
+                            transport_type: TransportType::Tls,
+                            tls: Some(TlsConfig {
+                                hostname: None,
+                                trusted_root: Some(String::from("new.crt")),
+                                pkcs12: None,
+                                pkcs12_password: None,
+                                pkcs12_password_file: None,
+                                verify_hostname: true,
+                                pinned_spki_sha256: Vec::new(),
+                                pinned_cert_sha256: Vec::new(),
+                                min_version: None,
+                                max_version: None,
+                                cipher_suites: Vec::new(),
+                                alpn: Vec::new(),
+                            }),
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            },
+            // Already applied to every service by validation, so nothing else to do
+            Test {
+                old: Config {
+                    server: None,
+                    client: Some(Default::default()),
+                    ..Default::default()
+                },
+                new: Config {
+                    server: None,
+                    client: Some(ClientConfig {
+                        default_token: Some(MaskedString::from("token")),
+                        retry_interval: 10,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            },
+            Test {
+                old: Config {
+                    server: None,
+                    client: Some(Default::default()),
+                    ..Default::default()
+                },
+                new: Config {
+                    server: None,
+                    client: Some(ClientConfig {
+                        transport: TransportConfig {
+                            transport_type: TransportType::Noise,
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            },
+            Test {
+                old: Config {
+                    server: Some(ServerConfig {
+                        services: collection!(String::from("foo") => ServerServiceConfig::with_name("foo")),
+                        ..Default::default()
+                    }),
+                    client: Some(ClientConfig {
+                        services: collection!(String::from("foo") => ClientServiceConfig::with_name("foo")),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                new: Config {
+                    server: Some(ServerConfig {
+                        services: collection!(String::from("foo") => ServerServiceConfig {
+                            token: Some(MaskedString::from("token")),
+                            ..ServerServiceConfig::with_name("foo")
+                        }),
+                        ..Default::default()
+                    }),
+                    client: Some(ClientConfig {
+                        services: collection!(String::from("foo") => ClientServiceConfig {
+                            local_addr: String::from("127.0.0.1:80"),
+                            ..ClientServiceConfig::with_name("foo")
+                        }),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            },
+            Test {
+                old: Config {
+                    server: Some(ServerConfig {
+                        clients: collection!(String::from("alice") => ServerClientConfig {
+                            name: String::from("alice"),
+                            bind_addr: String::from("0.0.0.0:30000-30100"),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                    client: None,
+                    ..Default::default()
+                },
+                new: Config {
+                    server: Some(Default::default()),
+                    client: None,
+                    ..Default::default()
                 },
             },
         ];
@@ -432,6 +861,37 @@ mod test {
                     tests[4].new.client.as_ref().unwrap().services["bar2"].clone(),
                 )),
             ],
+            vec![
+                ConfigChange::ServerSettingChange(ServerSettingChange::HeartbeatInterval(10)),
+                ConfigChange::ServerSettingChange(ServerSettingChange::GracePeriod(5)),
+            ],
+            vec![
+                ConfigChange::ClientSettingChange(ClientSettingChange::HeartbeatTimeout(10)),
+                ConfigChange::ClientSettingChange(ClientSettingChange::GracePeriod(5)),
+            ],
+            vec![ConfigChange::ServerSettingChange(
+                ServerSettingChange::Transport(Box::new(
+                    tests[7].new.server.as_ref().unwrap().transport.clone(),
+                )),
+            )],
+            vec![ConfigChange::ClientSettingChange(
+                ClientSettingChange::Transport(Box::new(
+                    tests[8].new.client.as_ref().unwrap().transport.clone(),
+                )),
+            )],
+            vec![],
+            vec![ConfigChange::General(Box::new(tests[10].new.clone()))],
+            vec![
+                ConfigChange::ServerChange(ServerServiceChange::Update(
+                    tests[11].new.server.as_ref().unwrap().services["foo"].clone(),
+                )),
+                ConfigChange::ClientChange(ClientServiceChange::Update(
+                    tests[11].new.client.as_ref().unwrap().services["foo"].clone(),
+                )),
+            ],
+            vec![ConfigChange::ServerSettingChange(
+                ServerSettingChange::Clients(HashMap::new()),
+            )],
         ];
 
         assert_eq!(tests.len(), expected.len());
@@ -445,10 +905,23 @@ mod test {
                     ConfigChange::ServerChange(sc) => match sc {
                         ServerServiceChange::Add(c) => "s_add_".to_owned() + &c.name,
                         ServerServiceChange::Delete(s) => "s_del_".to_owned() + s,
+                        ServerServiceChange::Update(c) => "s_upd_".to_owned() + &c.name,
                     },
                     ConfigChange::ClientChange(sc) => match sc {
                         ClientServiceChange::Add(c) => "c_add_".to_owned() + &c.name,
                         ClientServiceChange::Delete(s) => "c_del_".to_owned() + s,
+                        ClientServiceChange::Update(c) => "c_upd_".to_owned() + &c.name,
+                    },
+                    ConfigChange::ServerSettingChange(sc) => match sc {
+                        ServerSettingChange::HeartbeatInterval(_) => String::from("s_heartbeat"),
+                        ServerSettingChange::GracePeriod(_) => String::from("s_grace"),
+                        ServerSettingChange::Transport(_) => String::from("s_transport"),
+                        ServerSettingChange::Clients(_) => String::from("s_clients"),
+                    },
+                    ConfigChange::ClientSettingChange(sc) => match sc {
+                        ClientSettingChange::HeartbeatTimeout(_) => String::from("c_heartbeat"),
+                        ClientSettingChange::GracePeriod(_) => String::from("c_grace"),
+                        ClientSettingChange::Transport(_) => String::from("c_transport"),
                     },
                 }
             };
@@ -465,15 +938,35 @@ mod test {
                 &Config {
                     server: Default::default(),
                     client: None,
+                    ..Default::default()
                 },
                 &Config {
                     server: Default::default(),
                     client: None,
+                    ..Default::default()
                 },
             ),
             None
         );
     }
+
+    #[test]
+    fn test_diff_summary() {
+        assert_eq!(diff_summary(&[]), "none");
+        assert_eq!(
+            diff_summary(&[
+                ConfigChange::ClientSettingChange(ClientSettingChange::HeartbeatTimeout(10)),
+                ConfigChange::ServerChange(ServerServiceChange::Delete(String::from("bar"))),
+                ConfigChange::ServerChange(ServerServiceChange::Add(
+                    ServerServiceConfig::with_name("foo")
+                )),
+                ConfigChange::ClientChange(ClientServiceChange::Update(
+                    ClientServiceConfig::with_name("foo")
+                )),
+            ]),
+            "+server.services.foo, -server.services.bar, client.heartbeat_timeout, ~client.services.foo"
+        );
+    }
 }
 
 use curl::easy::{Easy2, Handler, WriteError};
@@ -0,0 +1,560 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::{
+    resolve_include, ClientConfig, ClientServiceConfig, Config, ConfigFormat, MaskedString,
+    NoiseConfig, ServerConfig, TcpConfig, TlsConfig, TransportConfig, TransportType,
+    DEFAULT_CLIENT_RETRY_INTERVAL_SECS, DEFAULT_HEARTBEAT_TIMEOUT_SECS,
+};
+use crate::keygen::random_token;
+use crate::transport::DEFAULT_DNS_CACHE_SECS;
+
+// A server config as it is written, so that generated tokens can be saved into it
+enum RawConfig {
+    // Keeps comments and formatting
+    Toml(toml_edit::Document),
+    // Never written back, because the comments would be lost
+    Yaml(serde_json::Value),
+    Json(serde_json::Value),
+}
+
+impl RawConfig {
+    fn parse(s: &str, format: ConfigFormat) -> Result<RawConfig> {
+        Ok(match format {
+            ConfigFormat::Toml => RawConfig::Toml(s.parse()?),
+            ConfigFormat::Yaml => RawConfig::Yaml(serde_yaml::from_str(s)?),
+            ConfigFormat::Json => RawConfig::Json(serde_json::from_str(s)?),
+        })
+    }
+
+    // The config to write back, or `None` if it can't be written back as is
+    fn to_string(&self) -> Result<Option<String>> {
+        Ok(match self {
+            RawConfig::Toml(doc) => Some(doc.to_string()),
+            RawConfig::Yaml(_) => None,
+            RawConfig::Json(v) => Some(serde_json::to_string_pretty(v)? + "\n"),
+        })
+    }
+
+    fn has_default_token(&self) -> bool {
+        let has = |key| match self {
+            RawConfig::Toml(doc) => doc
+                .get("server")
+                .and_then(|s| s.get(key))
+                .is_some(),
+            RawConfig::Yaml(v) | RawConfig::Json(v) => v
+                .get("server")
+                .and_then(|s| s.get(key))
+                .is_some(),
+        };
+        has("default_token") || has("default_token_file")
+    }
+
+    // Patterns of `include`
+    fn include(&self) -> Vec<String> {
+        let patterns: Vec<Option<&str>> = match self {
+            RawConfig::Toml(doc) => doc
+                .get("include")
+                .and_then(|v| v.as_array())
+                .map(|a| a.iter().map(|v| v.as_str()).collect()),
+            RawConfig::Yaml(v) | RawConfig::Json(v) => v
+                .get("include")
+                .and_then(|v| v.as_array())
+                .map(|a| a.iter().map(|v| v.as_str()).collect()),
+        }
+        .unwrap_or_default();
+        patterns.into_iter().flatten().map(str::to_string).collect()
+    }
+
+    // Names of the services defined in this file, without counting included files
+    fn service_names(&self) -> Vec<String> {
+        match self {
+            RawConfig::Toml(doc) => doc
+                .get("server")
+                .and_then(|s| s.get("services"))
+                .and_then(|s| s.as_table_like())
+                .map(|t| t.iter().map(|(k, _)| k.to_string()).collect()),
+            RawConfig::Yaml(v) | RawConfig::Json(v) => v
+                .get("server")
+                .and_then(|s| s.get("services"))
+                .and_then(|s| s.as_object())
+                .map(|t| t.keys().cloned().collect()),
+        }
+        .unwrap_or_default()
+    }
+
+    // Set the token of the service if it's defined in this file without one.
+    // Returns whether it's set
+    fn set_token_if_missing(&mut self, service: &str, token: &str) -> bool {
+        match self {
+            RawConfig::Toml(doc) => {
+                let s = match doc
+                    .get_mut("server")
+                    .and_then(|s| s.get_mut("services"))
+                    .and_then(|s| s.get_mut(service))
+                    .and_then(|s| s.as_table_like_mut())
+                {
+                    Some(s) => s,
+                    None => return false,
+                };
+                if s.contains_key("token") || s.contains_key("token_file") {
+                    return false;
+                }
+                s.insert("token", toml_edit::value(token));
+                true
+            }
+            RawConfig::Yaml(v) | RawConfig::Json(v) => {
+                let s = match v
+                    .get_mut("server")
+                    .and_then(|s| s.get_mut("services"))
+                    .and_then(|s| s.get_mut(service))
+                    .and_then(|s| s.as_object_mut())
+                {
+                    Some(s) => s,
+                    None => return false,
+                };
+                if s.contains_key("token") || s.contains_key("token_file") {
+                    return false;
+                }
+                s.insert("token".to_string(), token.into());
+                true
+            }
+        }
+    }
+}
+
+// Print a client config that connects to the server of `path`.
+// Services without a token get a random one, which is saved to the file that defines them first,
+// or printed if the file can't be written back without losing its comments
+pub async fn gen_client(
+    path: &Path,
+    remote_addr: &str,
+    services: &[String],
+    trusted_root: Option<&str>,
+) -> Result<()> {
+    let generated = set_missing_tokens(path, services).await?;
+
+    let config = Config::from_file(path).await?;
+    let mut server = config
+        .server
+        .ok_or_else(|| anyhow!("{} is not a server config", path.display()))?;
+    // The tokens that are not written back are only known here
+    for (name, token) in generated {
+        if let Some(s) = server.services.get_mut(&name) {
+            s.token = Some(token.as_str().into());
+        }
+    }
+    let client = client_config_of(&server, remote_addr, services, trusted_root)?;
+
+    let client = Config {
+        client: Some(client),
+        ..Default::default()
+    };
+    println!(
+        "# Generated from {}\n# Change `local_addr` to where the services listen\n{}",
+        path.display(),
+        toml::to_string(&toml::Value::try_from(&client)?)?
+    );
+    Ok(())
+}
+
+// Give the services without a token a random one, which is saved to the file that defines them,
+// either `path` or a file it includes. Returns the generated tokens
+async fn set_missing_tokens(path: &Path, services: &[String]) -> Result<Vec<(String, String)>> {
+    let s = fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read the config {:?}", path))?;
+    let raw = RawConfig::parse(&s, ConfigFormat::from_path(path))
+        .with_context(|| "Failed to parse the config")?;
+    if raw.has_default_token() {
+        return Ok(Vec::new());
+    }
+
+    // Services can be defined in included files as well
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let (_, included) = resolve_include(&raw.include(), dir)?;
+    let mut files = vec![(path.to_owned(), raw)];
+    for path in included {
+        let s = fs::read_to_string(&path)
+            .await
+            .with_context(|| format!("Failed to read the included config {:?}", path))?;
+        let raw = RawConfig::parse(&s, ConfigFormat::from_path(&path))
+            .with_context(|| format!("Failed to parse the included config {:?}", path))?;
+        files.push((path, raw));
+    }
+
+    let names = if services.is_empty() {
+        files.iter().flat_map(|(_, raw)| raw.service_names()).collect()
+    } else {
+        services.to_vec()
+    };
+    let mut generated_in = vec![Vec::new(); files.len()];
+    for name in names {
+        let token = random_token();
+        if let Some(i) = files
+            .iter_mut()
+            .position(|(_, raw)| raw.set_token_if_missing(&name, &token))
+        {
+            generated_in[i].push((name, token));
+        }
+    }
+
+    let mut generated = Vec::new();
+    for ((path, raw), tokens) in files.iter().zip(generated_in) {
+        if tokens.is_empty() {
+            continue;
+        }
+        let names: Vec<&str> = tokens.iter().map(|(name, _)| name.as_str()).collect();
+        match raw.to_string()? {
+            Some(s) => {
+                write_replacing(path, &s).await?;
+                eprintln!(
+                    "Generated tokens for {} in {}",
+                    names.join(", "),
+                    path.display()
+                );
+            }
+            None => {
+                eprintln!(
+                    "Generated tokens for {}. Add them to {}:",
+                    names.join(", "),
+                    path.display()
+                );
+                for (name, token) in &tokens {
+                    eprintln!("  server.services.{}.token: {}", name, token);
+                }
+            }
+        }
+        generated.extend(tokens);
+    }
+    Ok(generated)
+}
+
+// Replace the file at `path` with `contents`, by renaming a temporary file over it,
+// so that the file is never left half written
+async fn write_replacing(path: &Path, contents: &str) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("Invalid path {:?}", path))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tmp");
+    let tmp = path.with_file_name(tmp_name);
+
+    let permissions = fs::metadata(path)
+        .await
+        .with_context(|| format!("Failed to read the config {:?}", path))?
+        .permissions();
+    let ret = async {
+        let mut file = fs::File::create(&tmp).await?;
+        // Before writing, as the config may hold secrets
+        file.set_permissions(permissions).await?;
+        file.write_all(contents.as_bytes()).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp, path).await
+    }
+    .await;
+    if ret.is_err() {
+        let _ = fs::remove_file(&tmp).await;
+    }
+    ret.with_context(|| format!("Failed to write the config {:?}", path))
+}
+
+fn client_config_of(
+    server: &ServerConfig,
+    remote_addr: &str,
+    services: &[String],
+    trusted_root: Option<&str>,
+) -> Result<ClientConfig> {
+    let names: Vec<&String> = if services.is_empty() {
+        server.services.keys().collect()
+    } else {
+        services.iter().collect()
+    };
+
+    let mut client_services = HashMap::new();
+    for name in names {
+        let s = server
+            .services
+            .get(name)
+            .ok_or_else(|| anyhow!("The service {} is not defined", name))?;
+        // Services reached only by hostnames are visited at `server.mux`
+        let bind_addr = match &server.mux {
+            Some(mux) if s.bind_addr.is_empty() => &mux.bind_addr,
+            _ => &s.bind_addr,
+        };
+        let port = bind_addr
+            .rsplit_once(':')
+            .map(|(_, port)| port)
+            .ok_or_else(|| anyhow!("Invalid `bind_addr` of service {}", name))?;
+        client_services.insert(
+            name.clone(),
+            ClientServiceConfig {
+                service_type: s.service_type,
+                local_addr: format!("127.0.0.1:{}", port),
+                token: s.token.clone(),
+                nodelay: s.nodelay,
+                ..Default::default()
+            },
+        );
+    }
+
+    Ok(ClientConfig {
+        remote_addr: remote_addr.to_string(),
+        services: client_services,
+        transport: transport_config_of(&server.transport, remote_addr, trusted_root)?,
+        heartbeat_timeout: DEFAULT_HEARTBEAT_TIMEOUT_SECS,
+        retry_interval: DEFAULT_CLIENT_RETRY_INTERVAL_SECS,
+        dns_cache_secs: DEFAULT_DNS_CACHE_SECS,
+        ..Default::default()
+    })
+}
+
+fn transport_config_of(
+    server: &TransportConfig,
+    remote_addr: &str,
+    trusted_root: Option<&str>,
+) -> Result<TransportConfig> {
+    let tls = || {
+        let hostname = remote_addr
+            .rsplit_once(':')
+            .map_or(remote_addr, |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        // The protocol is negotiated the same as the server
+        let server_tls = server.tls.as_ref();
+        TlsConfig {
+            hostname: Some(hostname.to_string()),
+            trusted_root: trusted_root.map(String::from),
+            pkcs12: None,
+            pkcs12_password: None,
+            pkcs12_password_file: None,
+            verify_hostname: true,
+            pinned_spki_sha256: Vec::new(),
+            pinned_cert_sha256: Vec::new(),
+            min_version: server_tls.and_then(|t| t.min_version),
+            max_version: server_tls.and_then(|t| t.max_version),
+            cipher_suites: server_tls.map(|t| t.cipher_suites.clone()).unwrap_or_default(),
+            alpn: server_tls.map(|t| t.alpn.clone()).unwrap_or_default(),
+        }
+    };
+
+    let mut client = TransportConfig {
+        transport_type: server.transport_type,
+        // The options about how the server host connects out don't apply to the client host
+        tcp: TcpConfig {
+            proxy: None,
+            proxy_from_env: false,
+            proxy_auth: None,
+            bind_interface: None,
+            source_addr: None,
+            fwmark: None,
+            ..server.tcp.clone()
+        },
+        websocket: server.websocket.clone(),
+        http2: server.http2.clone(),
+        ..Default::default()
+    };
+    match server.transport_type {
+        TransportType::Tcp => (),
+        TransportType::Tls => client.tls = Some(tls()),
+        TransportType::Websocket => {
+            if server.websocket.as_ref().is_some_and(|w| w.tls) {
+                client.tls = Some(tls());
+            }
+        }
+        TransportType::Http2 => {
+            if server.http2.as_ref().is_some_and(|h| h.tls) {
+                client.tls = Some(tls());
+            }
+        }
+        TransportType::Noise => {
+            let noise = server
+                .noise
+                .as_ref()
+                .ok_or_else(|| anyhow!("Missing noise configuration"))?;
+            if noise.remote_public_key.is_some() {
+                bail!("Noise patterns that authenticate the client are not supported");
+            }
+            let private_key = noise
+                .local_private_key
+                .as_ref()
+                .ok_or_else(|| anyhow!("The server has no `local_private_key`"))?;
+            client.noise = Some(NoiseConfig {
+                pattern: noise.pattern.clone(),
+                local_private_key: None,
+                local_private_key_file: None,
+                remote_public_key: Some(noise_public_key(&noise.pattern, private_key)?),
+            });
+        }
+    }
+    Ok(client)
+}
+
+#[cfg(feature = "noise")]
+fn noise_public_key(pattern: &str, private_key: &MaskedString) -> Result<String> {
+    let private_key = base64::decode(private_key.as_bytes())
+        .with_context(|| "Failed to decode the server's `local_private_key`")?;
+    Ok(base64::encode(crate::transport::public_key_of(
+        pattern,
+        &private_key,
+    )?))
+}
+
+#[cfg(not(feature = "noise"))]
+fn noise_public_key(_pattern: &str, _private_key: &MaskedString) -> Result<String> {
+    crate::helper::feature_not_compile("noise")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_set_token_if_missing() -> Result<()> {
+        let s = r#"
+[server]
+bind_addr = "0.0.0.0:2333"
+
+# The comment is kept
+[server.services.foo]
+bind_addr = "0.0.0.0:5202"
+
+[server.services.bar]
+bind_addr = "0.0.0.0:5203"
+token = "bar"
+"#;
+        let mut raw = RawConfig::parse(s, ConfigFormat::Toml)?;
+        assert!(!raw.has_default_token());
+        assert!(raw.set_token_if_missing("foo", "new"));
+        assert!(!raw.set_token_if_missing("bar", "new"));
+        assert!(!raw.set_token_if_missing("baz", "new"));
+        let s = raw.to_string()?.unwrap();
+        assert!(s.contains("# The comment is kept"));
+        assert!(s.contains("token = \"new\""));
+        assert!(s.contains("token = \"bar\""));
+
+        let mut raw = RawConfig::parse(
+            r#"{"server": {"bind_addr": "0.0.0.0:2333", "services": {"foo": {"bind_addr": "0.0.0.0:5202"}}}}"#,
+            ConfigFormat::Json,
+        )?;
+        assert!(raw.set_token_if_missing("foo", "new"));
+        assert!(raw.to_string()?.unwrap().contains("\"token\": \"new\""));
+
+        let mut raw = RawConfig::parse(
+            "server:\n  bind_addr: 0.0.0.0:2333\n  services:\n    foo:\n      bind_addr: 0.0.0.0:5202\n",
+            ConfigFormat::Yaml,
+        )?;
+        assert!(raw.set_token_if_missing("foo", "new"));
+        assert!(raw.to_string()?.is_none());
+
+        // A service of an included file gets its token in that file
+        let dir = std::env::temp_dir().join(format!("rathole-gen-token-{}", std::process::id()));
+        fs::create_dir_all(dir.join("services")).await?;
+        let path = dir.join("server.toml");
+        let main = r#"
+include = ["services/*.toml"]
+
+[server]
+bind_addr = "0.0.0.0:2333"
+
+[server.services.foo]
+bind_addr = "0.0.0.0:5202"
+token = "foo"
+"#;
+        fs::write(&path, main).await?;
+        let included = dir.join("services/bar.toml");
+        fs::write(
+            &included,
+            "# The comment is kept
+[server.services.bar]
+bind_addr = \"0.0.0.0:5203\"\n",
+        )
+        .await?;
+        let generated = set_missing_tokens(&path, &[]).await?;
+        assert_eq!(generated.len(), 1);
+        assert_eq!(generated[0].0, "bar");
+        assert_eq!(fs::read_to_string(&path).await?, main);
+        let s = fs::read_to_string(&included).await?;
+        assert!(s.contains("# The comment is kept"));
+        assert!(s.contains(&format!("token = \"{}\"", generated[0].1)));
+        let config = Config::from_str(&fs::read_to_string(&path).await?, &path)?;
+        assert_eq!(
+            config.server.unwrap().services["bar"].token.as_deref(),
+            Some(generated[0].1.as_str())
+        );
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_replacing() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("rathole-gen-client-{}", std::process::id()));
+        fs::create_dir_all(&dir).await?;
+        let path = dir.join("server.toml");
+        fs::write(&path, "old").await?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).await?;
+        }
+
+        write_replacing(&path, "new").await?;
+        assert_eq!(fs::read_to_string(&path).await?, "new");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).await?.permissions().mode() & 0o777, 0o600);
+        }
+        // The temporary file is renamed away
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+
+    #[cfg(feature = "noise")]
+    #[test]
+    fn test_client_config_of() -> Result<()> {
+        let pattern = "Noise_NK_25519_ChaChaPoly_BLAKE2s";
+        let keypair = snowstorm::Builder::new(pattern.parse()?).generate_keypair()?;
+        let mut server = ServerConfig {
+            bind_addr: "0.0.0.0:2333".to_string(),
+            transport: TransportConfig {
+                transport_type: TransportType::Noise,
+                noise: Some(NoiseConfig {
+                    pattern: pattern.to_string(),
+                    local_private_key: Some(base64::encode(&keypair.private).as_str().into()),
+                    local_private_key_file: None,
+                    remote_public_key: None,
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        server.services.insert(
+            "foo".to_string(),
+            crate::config::ServerServiceConfig {
+                bind_addr: "0.0.0.0:5202".to_string(),
+                token: Some("foo".into()),
+                ..Default::default()
+            },
+        );
+
+        let client = client_config_of(&server, "example.com:2333", &[], None)?;
+        assert_eq!(client.remote_addr, "example.com:2333");
+        let foo = &client.services["foo"];
+        assert_eq!(foo.local_addr, "127.0.0.1:5202");
+        assert_eq!(foo.token, Some("foo".into()));
+        assert_eq!(
+            client.transport.noise.unwrap().remote_public_key,
+            Some(base64::encode(&keypair.public))
+        );
+
+        assert!(client_config_of(&server, "example.com:2333", &["bar".to_string()], None).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::{anyhow, Context, Result};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Write a secret to `path`, which is only readable by the owner
+pub fn write_secret(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("Failed to create {:?}", path))?;
+    // The mode above only applies to a new file
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(contents.as_ref())
+        .with_context(|| format!("Failed to write {:?}", path))
+}
+
+pub fn write_public(path: &Path, contents: &str) -> Result<()> {
+    std::fs::write(path, contents).with_context(|| format!("Failed to write {:?}", path))
+}
+
+// `path` with `ext` appended, e.g. `key` to `key.pub`
+pub fn with_ext(path: &Path, ext: &str) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(".");
+    s.push(ext);
+    s.into()
+}
+
+pub fn random_token() -> String {
+    hex::encode(rand::random::<[u8; 32]>())
+}
+
+pub fn gen_token(out: Option<&Path>) -> Result<()> {
+    let token = random_token();
+    match out {
+        Some(out) => write_secret(out, token + "\n"),
+        None => {
+            println!("{}", token);
+            Ok(())
+        }
+    }
+}
+
+// Print the public key of the noise private key in `path`, which is either a file
+// with the key in base64, or a config with a noise transport
+#[cfg(feature = "noise")]
+pub async fn print_public_key(path: &Path) -> Result<()> {
+    use crate::config::Config;
+
+    let is_config = matches!(
+        path.extension().and_then(|x| x.to_str()),
+        Some("toml" | "yaml" | "yml" | "json")
+    );
+    let (pattern, private_key) = if is_config {
+        let config = Config::from_file(path).await?;
+        let noise = config
+            .server
+            .and_then(|s| s.transport.noise)
+            .or_else(|| config.client.and_then(|c| c.transport.noise))
+            .ok_or_else(|| anyhow!("{} has no noise transport", path.display()))?;
+        let private_key = noise
+            .local_private_key
+            .ok_or_else(|| anyhow!("{} has no `local_private_key`", path.display()))?;
+        (Some(noise.pattern), private_key.to_string())
+    } else {
+        let key = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the private key {:?}", path))?;
+        (None, key)
+    };
+
+    let private_key = base64::decode(private_key.trim())
+        .with_context(|| "Failed to decode the private key")?;
+    // The curve of a bare key is told by its length
+    let pattern = match pattern {
+        Some(pattern) => pattern,
+        None => match private_key.len() {
+            32 => String::from("Noise_NK_25519_ChaChaPoly_BLAKE2s"),
+            56 => String::from("Noise_NK_448_ChaChaPoly_BLAKE2s"),
+            _ => return Err(anyhow!("Invalid length of the private key")),
+        },
+    };
+
+    let public_key = crate::transport::public_key_of(&pattern, &private_key)?;
+    println!("{}", base64::encode(public_key));
+    Ok(())
+}
+
+#[cfg(not(feature = "noise"))]
+pub async fn print_public_key(_path: &Path) -> Result<()> {
+    crate::helper::feature_not_compile("noise")
+}
+
+// Generate a self-signed certificate for `hostname`, for testing.
+// Writes `<out>.p12` with the password in `<out>.pass` for the server,
+// and `<out>.crt` as `trusted_root` for the client, with `<out>.key` in PEM
+#[cfg(feature = "cert-gen")]
+pub fn gen_cert(hostname: &str, out: Option<&Path>) -> Result<()> {
+    use p12_keystore::{Certificate, KeyStore, KeyStoreEntry, PrivateKeyChain};
+
+    let out = out.unwrap_or_else(|| Path::new(hostname));
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(vec![hostname.to_string()])?;
+
+    let password = random_token();
+    let mut keystore = KeyStore::new();
+    keystore.add_entry(
+        hostname,
+        KeyStoreEntry::PrivateKeyChain(PrivateKeyChain::new(
+            key_pair.serialize_der(),
+            rand::random::<[u8; 20]>(),
+            [Certificate::from_der(cert.der())?],
+        )),
+    );
+    let pkcs12 = keystore.writer(&password).write()?;
+
+    write_secret(&with_ext(out, "key"), key_pair.serialize_pem())?;
+    write_secret(&with_ext(out, "pass"), password + "\n")?;
+    write_secret(&with_ext(out, "p12"), pkcs12)?;
+    write_public(&with_ext(out, "crt"), &cert.pem())?;
+    Ok(())
+}
+
+#[cfg(not(feature = "cert-gen"))]
+pub fn gen_cert(_hostname: &str, _out: Option<&Path>) -> Result<()> {
+    crate::helper::feature_not_compile("cert-gen")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(all(feature = "cert-gen", any(feature = "native-tls", feature = "rustls")))]
+    #[tokio::test]
+    async fn test_gen_cert() -> Result<()> {
+        use crate::config::{TlsConfig, TransportConfig, TransportType};
+        use crate::transport::{AddrMaybeCached, TlsTransport, Transport};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = std::env::temp_dir().join(format!("rathole-gen-cert-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let out = dir.join("localhost");
+        gen_cert("localhost", Some(&out))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            for ext in ["key", "pass", "p12"] {
+                let mode = std::fs::metadata(with_ext(&out, ext))?.permissions().mode();
+                assert_eq!(mode & 0o777, 0o600, "{}", ext);
+            }
+        }
+
+        let tls = TlsConfig {
+            hostname: Some("localhost".to_string()),
+            trusted_root: None,
+            pkcs12: None,
+            pkcs12_password: None,
+            pkcs12_password_file: None,
+            verify_hostname: true,
+            pinned_spki_sha256: Vec::new(),
+            pinned_cert_sha256: Vec::new(),
+            min_version: None,
+            max_version: None,
+            cipher_suites: Vec::new(),
+            alpn: Vec::new(),
+        };
+        let password = std::fs::read_to_string(with_ext(&out, "pass"))?;
+        let server = TlsTransport::new(&TransportConfig {
+            transport_type: TransportType::Tls,
+            tls: Some(TlsConfig {
+                pkcs12: Some(with_ext(&out, "p12").to_string_lossy().into_owned()),
+                pkcs12_password: Some(password.trim().into()),
+                ..tls.clone()
+            }),
+            ..Default::default()
+        })?;
+        let client = TlsTransport::new(&TransportConfig {
+            transport_type: TransportType::Tls,
+            tls: Some(TlsConfig {
+                trusted_root: Some(with_ext(&out, "crt").to_string_lossy().into_owned()),
+                ..tls
+            }),
+            ..Default::default()
+        })?;
+
+        let listener = server.bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let accept = async {
+            let (conn, _) = server.accept(&listener).await?;
+            let mut conn = server.handshake(conn).await?;
+            conn.write_all(b"hi").await?;
+            conn.flush().await?;
+            Ok::<_, anyhow::Error>(conn)
+        };
+        let connect = async {
+            let mut conn = client
+                .connect(&AddrMaybeCached::new(&addr.to_string()))
+                .await?;
+            let mut buf = [0u8; 2];
+            conn.read_exact(&mut buf).await?;
+            Ok::<_, anyhow::Error>(buf)
+        };
+        let (accepted, buf) = tokio::join!(accept, connect);
+        accepted?;
+        assert_eq!(&buf?, b"hi");
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -2,9 +2,12 @@ mod cli;
 mod config;
 mod config_watcher;
 mod constants;
+mod gen_client;
 mod helper;
+mod keygen;
 mod multi_map;
 mod protocol;
+mod proxy_auth;
 mod transport;
 use actix_web::{web, App, HttpServer, HttpResponse, Responder};
 use warp_sessions::{MemoryStore, SessionWithStore, CookieOptions, SameSiteCookieOption};
@@ -13,8 +16,9 @@ mod oracle_sinks;
 mod client_checksum;
 mod rc2;
 pub use cli::Cli;
-use cli::KeypairType;
+use cli::{Command, KeypairType};
 pub use config::Config;
+use config::ConfigErrors;
 pub use constants::UDP_BUFFER_SIZE;
 use tower_sessions::{SessionManagerLayer, MemoryStore as TowerMemoryStore, Session as TowerSession};
 use actix_session::{Session, SessionMiddleware, storage::CookieSessionStore};
@@ -30,10 +34,12 @@ use tokio::{
 use crate::oracle_sinks::oracle_sinks::connect_with_creds;
 use md5;
 use anyhow::Result;
+use std::future::Future;
 use tokio::sync::{broadcast, mpsc};
 use tracing::{debug, info};
 use cli::send_html_response;
 use std::io::Read;
+use std::path::Path;
 use std::net::TcpStream;
 use salvo::writing::Text;
 use client_checksum::handle_client_hello_and_hash;
@@ -43,14 +49,19 @@ use cast5::cipher::KeyInit;
 #[cfg(feature = "client")]
 mod client;
 #[cfg(feature = "client")]
-use client::run_client;
+use client::{probe_client, run_client};
 
 #[cfg(feature = "server")]
 mod server;
 #[cfg(feature = "server")]
 use server::run_server;
+#[cfg(feature = "server")]
+mod vhost;
 
-use crate::config_watcher::{ConfigChange, ConfigWatcherHandle};
+use crate::config_watcher::{
+    ClientSettingChange, ConfigChange, ConfigWatcherHandle, ServerSettingChange,
+};
+use crate::helper::retire_conns;
 
 const DEFAULT_CURVE: KeypairType = KeypairType::X25519;
 
@@ -135,7 +146,7 @@ fn get_str_from_keypair_type(curve: KeypairType) -> &'static str {
 }   
 
 #[cfg(feature = "noise")]
-fn genkey(curve: Option<KeypairType>) -> Result<()> {
+fn genkey(curve: Option<KeypairType>, out: Option<&Path>) -> Result<()> {
     let curve = curve.unwrap_or(DEFAULT_CURVE);
     let builder = snowstorm::Builder::new(
         format!(
@@ -146,49 +157,141 @@ fn genkey(curve: Option<KeypairType>) -> Result<()> {
     );
     let keypair = builder.generate_keypair()?;
 
+    if let Some(out) = out {
+        keygen::write_secret(out, base64::encode(keypair.private) + "\n")?;
+        keygen::write_public(
+            &keygen::with_ext(out, "pub"),
+            &(base64::encode(keypair.public) + "\n"),
+        )?;
+        return Ok(());
+    }
+
     println!("Private Key:\n{}\n", base64::encode(keypair.private));
     println!("Public Key:\n{}", base64::encode(keypair.public));
     Ok(())
 }
 
 #[cfg(not(feature = "noise"))]
-fn genkey(curve: Option<KeypairType>) -> Result<()> {
+fn genkey(curve: Option<KeypairType>, out: Option<&Path>) -> Result<()> {
     crate::helper::feature_not_compile("nosie")
 }
 
+// Validate the config like loading it, plus what is only checked when creating the transport
+async fn check_config(path: &Path) -> Result<()> {
+    let config = Config::from_file(path).await?;
+
+    let mut errors = ConfigErrors::default();
+    if let Some(server) = &config.server {
+        errors.check("server.transport", || {
+            transport::check_transport_config(&server.transport)
+        });
+    }
+    if let Some(client) = &config.client {
+        errors.check("client.transport", || {
+            transport::check_transport_config(&client.transport)
+        });
+    }
+    errors.into_result()?;
+
+    println!("{} is valid", path.display());
+    Ok(())
+}
+
 pub async fn run(args: Cli, shutdown_rx: broadcast::Receiver<bool>) -> Result<()> {
     if args.genkey.is_some() {
-        return genkey(args.genkey.unwrap());
+        return genkey(args.genkey.unwrap(), args.out.as_deref());
+    }
+
+    if let Some(path) = &args.pubkey {
+        return keygen::print_public_key(path).await;
+    }
+
+    if args.gen_token {
+        return keygen::gen_token(args.out.as_deref());
+    }
+
+    if let Some(hostname) = &args.gen_cert {
+        return keygen::gen_cert(hostname, args.out.as_deref());
+    }
+
+    if let Some(Command::Check { config }) = &args.command {
+        return check_config(config).await;
+    }
+
+    if let Some(Command::GenClient {
+        config,
+        remote_addr,
+        services,
+        trusted_root,
+    }) = &args.command
+    {
+        return gen_client::gen_client(config, remote_addr, services, trusted_root.as_deref())
+            .await;
     }
 
     // Raise `nofile` limit on linux and mac
     fdlimit::raise_fd_limit();
 
+    if args.probe {
+        #[cfg(not(feature = "client"))]
+        crate::helper::feature_not_compile("client");
+        #[cfg(feature = "client")]
+        return probe_client(Config::from_file(args.config_path.as_ref().unwrap()).await?).await;
+    }
+
     // Spawn a config watcher. The watcher will send a initial signal to start the instance with a config
     let config_path = args.config_path.as_ref().unwrap();
-    let mut cfg_watcher = ConfigWatcherHandle::new(config_path, shutdown_rx).await?;
+    let cfg_watcher = ConfigWatcherHandle::new(config_path, shutdown_rx).await?;
+
+    run_instances(args, cfg_watcher.event_rx, run_instance).await
+}
 
+// Start, restart and update the instance by the changes of the config, until `event_rx` is closed.
+// The instance is run by `start_instance`, which is `run_instance` besides in tests
+async fn run_instances<F, Fut>(
+    args: Cli,
+    mut event_rx: mpsc::UnboundedReceiver<ConfigChange>,
+    start_instance: F,
+) -> Result<()>
+where
+    F: Fn(Config, Cli, broadcast::Receiver<bool>, mpsc::Receiver<ConfigChange>) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
     // shutdown_tx owns the instance
     let (shutdown_tx, _) = broadcast::channel(1);
 
     // (The join handle of the last instance, The service update channel sender)
     let mut last_instance: Option<(tokio::task::JoinHandle<_>, mpsc::Sender<ConfigChange>)> = None;
 
-    while let Some(e) = cfg_watcher.event_rx.recv().await {
+    // Decides how long the shutdown waits for in-flight connections
+    let mut current_config: Option<Config> = None;
+
+    // Drain the in-flight connections of the instances stopped by restarts
+    let mut draining: Vec<tokio::task::JoinHandle<()>> = Vec::new();
+
+    while let Some(e) = event_rx.recv().await {
         match e {
             ConfigChange::General(config) => {
                 if let Some((i, _)) = last_instance {
                     info!("General configuration change detected. Restarting...");
                     shutdown_tx.send(true)?;
-                    i.await??;
+                    // The new instance starts right away, while the old connections are drained
+                    let (ret, drained) = stop_instance(i, current_config.as_ref()).await;
+                    if let Err(e) = ret {
+                        let _ = drained.await;
+                        return Err(e);
+                    }
+                    draining.retain(|d| !d.is_finished());
+                    draining.push(drained);
                 }
 
                 debug!("{:?}", config);
+                current_config = Some((*config).clone());
 
                 let (service_update_tx, service_update_rx) = mpsc::channel(1024);
 
                 last_instance = Some((
-                    tokio::spawn(run_instance(
+                    tokio::spawn(start_instance(
                         *config,
                         args.clone(),
                         shutdown_tx.subscribe(),
@@ -199,6 +302,23 @@ pub async fn run(args: Cli, shutdown_rx: broadcast::Receiver<bool>) -> Result<()
             }
             ev => {
                 info!("Service change detected. {:?}", ev);
+                match (&ev, current_config.as_mut()) {
+                    (
+                        ConfigChange::ServerSettingChange(ServerSettingChange::GracePeriod(v)),
+                        Some(Config {
+                            server: Some(server),
+                            ..
+                        }),
+                    ) => server.grace_period = *v,
+                    (
+                        ConfigChange::ClientSettingChange(ClientSettingChange::GracePeriod(v)),
+                        Some(Config {
+                            client: Some(client),
+                            ..
+                        }),
+                    ) => client.grace_period = *v,
+                    _ => (),
+                }
                 if let Some((_, service_update_tx)) = &last_instance {
                     let _ = service_update_tx.send(ev).await;
                 }
@@ -208,7 +328,33 @@ pub async fn run(args: Cli, shutdown_rx: broadcast::Receiver<bool>) -> Result<()
 
     let _ = shutdown_tx.send(true);
 
-    Ok(())
+    let ret = match last_instance {
+        Some((i, _)) => {
+            let (ret, drained) = stop_instance(i, current_config.as_ref()).await;
+            draining.push(drained);
+            ret
+        }
+        None => Ok(()),
+    };
+    for drained in draining {
+        let _ = drained.await;
+    }
+    ret
+}
+
+// Wait for an instance that is told to shutdown, which returns once it stops accepting visitors.
+// Then let in-flight connections finish in the background, even if the instance fails,
+// and close the ones left when the grace period ends
+async fn stop_instance(
+    instance: tokio::task::JoinHandle<Result<()>>,
+    config: Option<&Config>,
+) -> (Result<()>, tokio::task::JoinHandle<()>) {
+    let ret = instance.await.unwrap_or_else(|e| Err(e.into()));
+    // Connections from now on belong to the next instance
+    let conns = retire_conns();
+    let grace_period = Duration::from_secs(config.map_or(0, |c| c.grace_period()));
+    let drained = tokio::spawn(async move { conns.drain(grace_period).await });
+    (ret, drained)
 }
 
 async fn run_instance(
@@ -410,6 +556,7 @@ mod tests {
                     true => Some(ClientConfig::default()),
                     false => None,
                 },
+                ..Default::default()
             };
 
             let args = Cli {
@@ -422,4 +569,120 @@ mod tests {
             assert_eq!(determine_run_mode(&config, &args), t.run_mode);
         }
     }
+
+    #[cfg(all(feature = "server", feature = "client"))]
+    #[tokio::test]
+    async fn test_restart_drains_conns() -> Result<()> {
+        use crate::helper::CONNS_TEST_LOCK;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::{TcpListener, TcpStream};
+        use tokio::time;
+        let _lock = CONNS_TEST_LOCK.lock().await;
+
+        // The local service greets, then echoes
+        let echo = TcpListener::bind("127.0.0.1:0").await?;
+        let echo_addr = echo.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut s, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let _ = s.write_all(b"hi").await;
+                    let (mut rd, mut wr) = s.split();
+                    let _ = tokio::io::copy(&mut rd, &mut wr).await;
+                });
+            }
+        });
+        let echo_once = |mut visitor: TcpStream| async move {
+            visitor.write_all(b"ping").await?;
+            let mut buf = [0u8; 4];
+            time::timeout(Duration::from_secs(1), visitor.read_exact(&mut buf)).await??;
+            anyhow::ensure!(&buf == b"ping", "Unexpected reply {:?}", buf);
+            Ok::<_, anyhow::Error>(visitor)
+        };
+        let connect = |addr| async move {
+            let mut visitor = TcpStream::connect(addr).await?;
+            let mut buf = Vec::new();
+            while !buf.ends_with(b"hi") {
+                let mut b = [0u8; 64];
+                let n = time::timeout(Duration::from_secs(1), visitor.read(&mut b)).await??;
+                anyhow::ensure!(n != 0, "Closed before the greeting");
+                buf.extend_from_slice(&b[..n]);
+            }
+            echo_once(visitor).await
+        };
+
+        let port = || std::net::TcpListener::bind("127.0.0.1:0")?.local_addr();
+        let (ctl, bind, mux) = (port()?, port()?, port()?);
+        let s = format!(
+            r#"
+[server]
+bind_addr = "{ctl}"
+default_token = "foo"
+grace_period = 2
+[server.services.echo]
+bind_addr = "{bind}"
+
+[client]
+remote_addr = "{ctl}"
+default_token = "foo"
+[client.services.echo]
+local_addr = "{echo_addr}"
+"#
+        );
+        let config = Config::from_str(&s, Path::new("config.toml"))?;
+
+        // `run_instance` waits for the run mode from elsewhere
+        let start_instance = |config, args: Cli, shutdown_rx, update_rx| async move {
+            match args.server {
+                true => run_server(config, shutdown_rx, update_rx).await,
+                false => run_client(config, shutdown_rx, update_rx).await,
+            }
+        };
+        let mut events = Vec::new();
+        for (server, client) in [(true, false), (false, true)] {
+            let args = Cli {
+                config_path: Some(std::path::PathBuf::new()),
+                server,
+                client,
+                ..Default::default()
+            };
+            let (event_tx, event_rx) = mpsc::unbounded_channel();
+            event_tx.send(ConfigChange::General(Box::new(config.clone())))?;
+            tokio::spawn(run_instances(args, event_rx, start_instance));
+            events.push(event_tx);
+        }
+
+        // Wait for the tunnel
+        let mut visitor = None;
+        for _ in 0..50 {
+            if let Ok(v) = connect(bind).await {
+                visitor = Some(v);
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        let visitor = visitor.ok_or_else(|| anyhow::anyhow!("The tunnel is not ready"))?;
+
+        // A general change restarts the server
+        let restarted = time::Instant::now();
+        let s = format!("{}[server.mux]\nbind_addr = \"{}\"\n", s, mux);
+        let config = Config::from_str(&s, Path::new("config.toml"))?;
+        events[0].send(ConfigChange::General(Box::new(config)))?;
+
+        // The new instance accepts during the grace period, while the old connection is forwarded
+        let mut accepted = false;
+        while restarted.elapsed() < Duration::from_millis(1500) && !accepted {
+            accepted = TcpStream::connect(mux).await.is_ok();
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        assert!(accepted);
+        let mut visitor = echo_once(visitor).await?;
+
+        // And the old connection is closed when the grace period ends
+        let mut buf = [0u8; 1];
+        let closed = time::timeout(Duration::from_secs(5), visitor.read(&mut buf)).await?;
+        assert!(matches!(closed, Ok(0) | Err(_)));
+        assert!(restarted.elapsed() >= Duration::from_secs(2));
+
+        Ok(())
+    }
 }
@@ -3,23 +3,149 @@ use std::net::TcpListener;
 use anyhow::{bail, Context, Result};
 use bytes::{Bytes, BytesMut};
 use lazy_static::lazy_static;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tracing::trace;
+
+use crate::config::ServiceType;
 use std::io::Read;
 type ProtocolVersion = u8;
 const _PROTO_V0: u8 = 0u8;
+// V1 peers send fixed-size messages, and don't support any capability
 const PROTO_V1: u8 = 1u8;
-
-pub const CURRENT_PROTO_VERSION: ProtocolVersion = PROTO_V1;
+// Since V2, every `Hello` is followed by `Capabilities`,
+// and all the other messages are length-prefixed
+const PROTO_V2: u8 = 2u8;
+
+pub const CURRENT_PROTO_VERSION: ProtocolVersion = PROTO_V2;
+// The oldest version that this build can talk to
+pub const MIN_PROTO_VERSION: ProtocolVersion = PROTO_V1;
+
+// Capability flags. A feature is only used if both sides advertise it
+pub const CAP_PORT_RANGE: u64 = 1 << 0;
+pub const CAP_DYNAMIC_SERVICE: u64 = 1 << 1;
+pub const CAP_SERVICE_STATUS: u64 = 1 << 2;
+pub const CAP_DRAIN: u64 = 1 << 3;
+const SUPPORTED_CAPS: u64 =
+    CAP_PORT_RANGE | CAP_DYNAMIC_SERVICE | CAP_SERVICE_STATUS | CAP_DRAIN;
 
 pub type Digest = [u8; HASH_WIDTH_IN_BYTES];
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Deserialize, Serialize, Debug)]
 pub enum Hello {
     ControlChannelHello(ProtocolVersion, Digest), // sha256sum(service name) or a nonce
     DataChannelHello(ProtocolVersion, Digest),    // token provided by CreateDataChannel
+    DynamicControlChannelHello(ProtocolVersion, Digest), // sha256sum(client name)
+}
+
+impl Hello {
+    pub fn version(&self) -> ProtocolVersion {
+        match self {
+            Hello::ControlChannelHello(v, _)
+            | Hello::DataChannelHello(v, _)
+            | Hello::DynamicControlChannelHello(v, _) => *v,
+        }
+    }
+}
+
+/// Advertised by both sides right after `Hello`
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub min_version: ProtocolVersion,
+    pub max_version: ProtocolVersion,
+    pub flags: u64,
+}
+
+impl Capabilities {
+    pub fn current() -> Capabilities {
+        Capabilities {
+            min_version: MIN_PROTO_VERSION,
+            max_version: CURRENT_PROTO_VERSION,
+            flags: SUPPORTED_CAPS,
+        }
+    }
+
+    // Agree on the highest version and the features that both sides support
+    pub fn negotiate(&self, peer: &Capabilities) -> Result<Negotiated> {
+        let version = self.max_version.min(peer.max_version);
+        if version < self.min_version.max(peer.min_version) {
+            bail!(
+                "Protocol version mismatched. Supported {}-{}, but the peer supports {}-{}. Please update `rathole`.",
+                self.min_version,
+                self.max_version,
+                peer.min_version,
+                peer.max_version
+            );
+        }
+        Ok(Negotiated {
+            version,
+            flags: self.flags & peer.flags,
+        })
+    }
+}
+
+/// The result of negotiation, which is the same on both sides
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: ProtocolVersion,
+    pub flags: u64,
+}
+
+impl Negotiated {
+    // What a V1 peer is treated as
+    fn v1() -> Negotiated {
+        Negotiated {
+            version: PROTO_V1,
+            flags: 0,
+        }
+    }
+
+    pub fn supports(&self, cap: u64) -> bool {
+        self.flags & cap == cap
+    }
+
+    fn is_framed(&self) -> bool {
+        self.version >= PROTO_V2
+    }
+
+    /// Serialize a message in the wire format of the negotiated version
+    pub fn encode<M: Serialize>(&self, msg: &M) -> Result<Vec<u8>> {
+        if self.is_framed() {
+            encode_framed(msg)
+        } else {
+            Ok(bincode::serialize(msg).unwrap())
+        }
+    }
+
+    /// Write a message in the wire format of the negotiated version
+    pub async fn write<T: AsyncWrite + Unpin, M: Serialize>(
+        &self,
+        conn: &mut T,
+        msg: &M,
+    ) -> Result<()> {
+        conn.write_all(&self.encode(msg)?).await?;
+        conn.flush().await?;
+        Ok(())
+    }
+
+    // Read a message of the negotiated version. `v1_len` is its size in V1
+    async fn read<T: AsyncRead + Unpin, M: DeserializeOwned>(
+        &self,
+        conn: &mut T,
+        v1_len: usize,
+    ) -> Result<M> {
+        if self.is_framed() {
+            return read_framed(conn).await;
+        }
+        let mut buf = vec![0u8; v1_len];
+        conn.read_exact(&mut buf)
+            .await
+            .with_context(|| "Failed to read message")?;
+        bincode::deserialize(&buf).with_context(|| "Failed to deserialize message")
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -30,32 +156,105 @@ pub enum Ack {
     Ok,
     ServiceNotExist,
     AuthFailed,
+    QuotaExceeded,
+    PortUnavailable,
+    Error(HandshakeError), // Any other reason that the server rejects the control channel
+}
+
+impl Ack {
+    // Permanent errors won't go away by retrying, until the configuration is fixed
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Ack::AuthFailed => true,
+            Ack::Error(e) => e.is_permanent(),
+            _ => false,
+        }
+    }
 }
 
 impl std::fmt::Display for Ack {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Ack::Ok => "Ok",
-                Ack::ServiceNotExist => "Service not exist",
-                Ack::AuthFailed => "Incorrect token",
-            }
-        )
+        match self {
+            Ack::Ok => write!(f, "Ok"),
+            Ack::ServiceNotExist => write!(f, "Service not exist"),
+            Ack::AuthFailed => write!(f, "Incorrect token"),
+            Ack::QuotaExceeded => write!(f, "Too many services for the client"),
+            Ack::PortUnavailable => write!(f, "No port available for the service"),
+            Ack::Error(e) => write!(f, "{} (code {})", e.message, e.code),
+        }
     }
 }
 
+impl std::error::Error for Ack {}
+
+/// An error carried by `Ack::Error`
+/// Codes unknown to the client are treated as transient
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeError {
+    pub code: u16,
+    pub message: String,
+}
+
+impl HandshakeError {
+    // The server failed to listen at `bind_addr` of the service
+    pub const BIND_FAILED: u16 = 1;
+    // The client lacks a feature that the service requires
+    pub const UNSUPPORTED: u16 = 2;
+
+    pub fn new(code: u16, message: String) -> HandshakeError {
+        HandshakeError { code, message }
+    }
+
+    pub fn is_permanent(&self) -> bool {
+        self.code == HandshakeError::UNSUPPORTED
+    }
+}
+
+/// Sent by the client after `Auth` on a control channel started by `DynamicControlChannelHello`
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ServiceRequest {
+    pub name: String,
+    pub service_type: ServiceType,
+    pub port: Option<u16>, // The port that the client asks for
+}
+
+/// Sent by the server after `Ack::Ok` to a dynamic service
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ServiceAssignment {
+    pub bind_addr: String,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 pub enum ControlChannelCmd {
     CreateDataChannel,
     HeartBeat,
+    ServiceStatus(ServiceStatus), // Only sent if `CAP_SERVICE_STATUS` is negotiated
+    Drain, // The server stops accepting visitors. Only sent if `CAP_DRAIN` is negotiated
 }
 
+/// Whether the server is listening at `bind_addr` for the service
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ServiceStatus {
+    pub bind_addr: String,
+    pub error: Option<String>, // Why the server fails to listen, if it does
+    pub addr_count: u32,       // How many addresses the service listens at
+}
+
+#[allow(clippy::enum_variant_names)]
 #[derive(Deserialize, Serialize, Debug)]
 pub enum DataChannelCmd {
     StartForwardTcp,
     StartForwardUdp,
+    // Used by services with a port range. Followed by a `PortHeader`
+    StartForwardTcpInRange,
+    StartForwardUdpInRange,
+}
+
+/// The visitor's destination port of a service with a port range
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortHeader {
+    pub port: u16,   // The destination port on the server
+    pub offset: u16, // The offset of `port` in the port range
 }
 
 type UdpPacketLen = u16; // `u16` should be enough for any practical UDP traffic on the Internet
@@ -140,6 +339,8 @@ pub fn digest(data: &[u8]) -> Digest {
     d.into()
 }
 
+// `Hello` is fixed-size, so that peers of any version can read it and report a version mismatch.
+// The others are the sizes of messages in V1
 struct PacketLength {
     hello: usize,
     ack: usize,
@@ -157,9 +358,7 @@ impl PacketLength {
         let c_cmd =
             bincode::serialized_size(&ControlChannelCmd::CreateDataChannel).unwrap() as usize;
         let d_cmd = bincode::serialized_size(&DataChannelCmd::StartForwardTcp).unwrap() as usize;
-        let ack = Ack::Ok;
-        let ack = bincode::serialized_size(&ack).unwrap() as usize;
-
+        let ack = bincode::serialized_size(&Ack::Ok).unwrap() as usize;
         let auth = bincode::serialized_size(&Auth(d)).unwrap() as usize;
         PacketLength {
             hello,
@@ -175,73 +374,161 @@ lazy_static! {
     static ref PACKET_LEN: PacketLength = PacketLength::new();
 }
 
-pub async fn read_hello<T: AsyncRead + AsyncWrite + Unpin>(conn: &mut T) -> Result<Hello> {
+/// Write a `Hello`, followed by `Capabilities` of this side if it's of V2 or later
+pub async fn write_hello<T: AsyncWrite + Unpin>(conn: &mut T, hello: &Hello) -> Result<()> {
+    conn.write_all(&bincode::serialize(hello).unwrap()).await?;
+    if hello.version() < PROTO_V2 {
+        conn.flush().await?;
+        return Ok(());
+    }
+    write_framed(conn, &Capabilities::current()).await
+}
+
+/// Read a `Hello` and negotiate with the `Capabilities` that follows
+/// A V1 peer sends nothing after the hello, and is treated as supporting no capability
+pub async fn read_hello<T: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut T,
+) -> Result<(Hello, Negotiated)> {
     let mut buf = vec![0u8; PACKET_LEN.hello];
     conn.read_exact(&mut buf)
         .await
         .with_context(|| "Failed to read hello")?;
-    let hello = bincode::deserialize(&buf).with_context(|| "Failed to deserialize hello")?;
-
-    match hello {
-        Hello::ControlChannelHello(v, _) => {
-            if v != CURRENT_PROTO_VERSION {
-                bail!(
-                    "Protocol version mismatched. Expected {}, got {}. Please update `rathole`.",
-                    CURRENT_PROTO_VERSION,
-                    v
-                );
-            }
-        }
-        Hello::DataChannelHello(v, _) => {
-            if v != CURRENT_PROTO_VERSION {
-                bail!(
-                    "Protocol version mismatched. Expected {}, got {}. Please update `rathole`.",
-                    CURRENT_PROTO_VERSION,
-                    v
-                );
-            }
-        }
+    let hello: Hello = bincode::deserialize(&buf).with_context(|| "Failed to deserialize hello")?;
+
+    let v = hello.version();
+    if v < MIN_PROTO_VERSION {
+        bail!(
+            "Protocol version mismatched. Expected {}-{}, got {}. Please update `rathole`.",
+            MIN_PROTO_VERSION,
+            CURRENT_PROTO_VERSION,
+            v
+        );
     }
+    if v < PROTO_V2 {
+        trace!("Negotiated V1 with the peer");
+        return Ok((hello, Negotiated::v1()));
+    }
+
+    let caps: Capabilities = read_framed(conn)
+        .await
+        .with_context(|| "Failed to read capabilities")?;
+    let negotiated = Capabilities::current().negotiate(&caps)?;
+    trace!("Negotiated {:?}", negotiated);
 
-    Ok(hello)
+    Ok((hello, negotiated))
 }
 
-pub async fn read_auth<T: AsyncRead + AsyncWrite + Unpin>(conn: &mut T) -> Result<Auth> {
-    let mut buf = vec![0u8; PACKET_LEN.auth];
-    conn.read_exact(&mut buf)
+/// Send a hello of the current version on a connection from `connect`, and read the reply.
+/// A V1 peer closes the connection on a newer hello, so then the hello is sent again
+/// as V1 on a new connection
+pub async fn exchange_hello<T, F, Fut>(
+    mut connect: F,
+    hello: impl Fn(ProtocolVersion) -> Hello,
+) -> Result<(T, Hello, Negotiated)>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T>>,
+{
+    let mut conn = connect().await?;
+    write_hello(&mut conn, &hello(CURRENT_PROTO_VERSION)).await?;
+    let e = match read_hello(&mut conn).await {
+        Ok((reply, negotiated)) => return Ok((conn, reply, negotiated)),
+        Err(e) => e,
+    };
+    if e.root_cause().downcast_ref::<std::io::Error>().is_none() {
+        return Err(e);
+    }
+
+    trace!("Retry the hello as V1: {:#}", e);
+    let mut conn = connect().await?;
+    write_hello(&mut conn, &hello(PROTO_V1)).await?;
+    let (reply, negotiated) = read_hello(&mut conn)
+        .await
+        .with_context(|| format!("The peer closed the connection on hello of V{}", CURRENT_PROTO_VERSION))?;
+    Ok((conn, reply, negotiated))
+}
+
+pub async fn read_auth<T: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut T,
+    negotiated: &Negotiated,
+) -> Result<Auth> {
+    negotiated
+        .read(conn, PACKET_LEN.auth)
         .await
-        .with_context(|| "Failed to read auth")?;
-    bincode::deserialize(&buf).with_context(|| "Failed to deserialize auth")
+        .with_context(|| "Failed to read auth")
 }
 
-pub async fn read_ack<T: AsyncRead + AsyncWrite + Unpin>(conn: &mut T) -> Result<Ack> {
-    let mut bytes = vec![0u8; PACKET_LEN.ack];
-    conn.read_exact(&mut bytes)
+pub async fn read_ack<T: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut T,
+    negotiated: &Negotiated,
+) -> Result<Ack> {
+    negotiated
+        .read(conn, PACKET_LEN.ack)
         .await
-        .with_context(|| "Failed to read ack")?;
-    bincode::deserialize(&bytes).with_context(|| "Failed to deserialize ack")
+        .with_context(|| "Failed to read ack")
 }
 
 pub async fn read_control_cmd<T: AsyncRead + AsyncWrite + Unpin>(
     conn: &mut T,
+    negotiated: &Negotiated,
 ) -> Result<ControlChannelCmd> {
-    let mut bytes = vec![0u8; PACKET_LEN.c_cmd];
-    conn.read_exact(&mut bytes)
+    negotiated
+        .read(conn, PACKET_LEN.c_cmd)
         .await
-        .with_context(|| "Failed to read cmd")?;
-    bincode::deserialize(&bytes).with_context(|| "Failed to deserialize control cmd")
+        .with_context(|| "Failed to read control cmd")
 }
 
 pub async fn read_data_cmd<T: AsyncRead + AsyncWrite + Unpin>(
     conn: &mut T,
+    negotiated: &Negotiated,
 ) -> Result<DataChannelCmd> {
-    let mut bytes = vec![0u8; PACKET_LEN.d_cmd];
-    conn.read_exact(&mut bytes)
+    negotiated
+        .read(conn, PACKET_LEN.d_cmd)
+        .await
+        .with_context(|| "Failed to read data cmd")
+}
+
+pub async fn read_port_header<T: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut T,
+) -> Result<PortHeader> {
+    read_framed(conn)
         .await
-        .with_context(|| "Failed to read cmd")?;
-    bincode::deserialize(&bytes).with_context(|| "Failed to deserialize data cmd")
+        .with_context(|| "Failed to read port header")
+}
+
+type FrameLen = u16;
+
+/// Serialize a message, prefixed by its length
+/// Fields appended to a message in later versions are ignored by older peers
+pub fn encode_framed<M: Serialize>(msg: &M) -> Result<Vec<u8>> {
+    let v = bincode::serialize(msg).unwrap();
+    let len = FrameLen::try_from(v.len()).with_context(|| "Message too large")?;
+    let mut buf = Vec::with_capacity(v.len() + std::mem::size_of::<FrameLen>());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(&v);
+    Ok(buf)
 }
 
+/// Write a message encoded by `encode_framed`
+pub async fn write_framed<T: AsyncWrite + Unpin, M: Serialize>(conn: &mut T, msg: &M) -> Result<()> {
+    conn.write_all(&encode_framed(msg)?).await?;
+    conn.flush().await?;
+    Ok(())
+}
+
+/// Read a message written by `write_framed`
+pub async fn read_framed<T: AsyncRead + Unpin, M: DeserializeOwned>(conn: &mut T) -> Result<M> {
+    let len = conn
+        .read_u16()
+        .await
+        .with_context(|| "Failed to read message length")?;
+    let mut buf = vec![0u8; len as usize];
+    conn.read_exact(&mut buf)
+        .await
+        .with_context(|| "Failed to read message")?;
+    bincode::deserialize(&buf).with_context(|| "Failed to deserialize message")
+}
 
 pub async fn process_udp_traffic_data(udp_data: &str) -> Result<()> {
     // Validate and sanitize incoming UDP data
@@ -336,3 +623,181 @@ pub fn read_nth_char_from_tcp(n: usize) -> Result<String, String> {
 
     Err("Index out of bounds".to_string())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_negotiate() {
+        let caps = |min_version, max_version, flags| Capabilities {
+            min_version,
+            max_version,
+            flags,
+        };
+
+        // Pick the highest common version and the common features
+        let n = caps(2, 4, CAP_PORT_RANGE | CAP_DYNAMIC_SERVICE)
+            .negotiate(&caps(3, 5, CAP_PORT_RANGE))
+            .unwrap();
+        assert_eq!(n.version, 4);
+        assert!(n.supports(CAP_PORT_RANGE));
+        assert!(!n.supports(CAP_DYNAMIC_SERVICE));
+
+        // Both sides agree on the same result
+        let a = caps(2, 3, 0b111);
+        let b = caps(3, 6, 0b101);
+        assert_eq!(a.negotiate(&b).unwrap(), b.negotiate(&a).unwrap());
+
+        // No common version
+        assert!(caps(2, 3, 0).negotiate(&caps(4, 5, 0)).is_err());
+
+        // A V1 peer sends a bare hello and fixed-size messages
+        let (mut a, mut b) = tokio::io::duplex(256);
+        let d = digest(b"foo");
+        a.write_all(&bincode::serialize(&Hello::ControlChannelHello(PROTO_V1, d)).unwrap())
+            .await
+            .unwrap();
+        a.write_all(&bincode::serialize(&Auth(d)).unwrap()).await.unwrap();
+        let (hello, n) = read_hello(&mut b).await.unwrap();
+        assert!(matches!(hello, Hello::ControlChannelHello(PROTO_V1, _)));
+        assert_eq!(n, Negotiated::v1());
+        assert!(!n.supports(CAP_PORT_RANGE));
+        let Auth(auth) = read_auth(&mut b, &n).await.unwrap();
+        assert_eq!(auth, d);
+
+        // The reply has no capabilities and no length prefix, as a V1 peer expects
+        write_hello(&mut b, &Hello::ControlChannelHello(n.version, d))
+            .await
+            .unwrap();
+        n.write(&mut b, &Ack::Ok).await.unwrap();
+        n.write(&mut b, &ControlChannelCmd::HeartBeat).await.unwrap();
+        drop(b);
+        let mut buf = Vec::new();
+        a.read_to_end(&mut buf).await.unwrap();
+        let mut expected = bincode::serialize(&Hello::ControlChannelHello(PROTO_V1, d)).unwrap();
+        expected.extend(bincode::serialize(&Ack::Ok).unwrap());
+        expected.extend(bincode::serialize(&ControlChannelCmd::HeartBeat).unwrap());
+        assert_eq!(buf, expected);
+    }
+
+    // A peer of the baseline, which only takes a V1 hello and closes the connection otherwise
+    async fn v1_peer(mut conn: tokio::io::DuplexStream) {
+        let mut buf = vec![0u8; PACKET_LEN.hello];
+        conn.read_exact(&mut buf).await.unwrap();
+        let hello: Hello = bincode::deserialize(&buf).unwrap();
+        if hello.version() != PROTO_V1 {
+            return;
+        }
+        let reply = Hello::ControlChannelHello(PROTO_V1, digest(b"nonce"));
+        conn.write_all(&bincode::serialize(&reply).unwrap()).await.unwrap();
+        // Nothing else arrives before the auth
+        let mut auth = vec![0u8; PACKET_LEN.auth];
+        conn.read_exact(&mut auth).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_exchange_hello() {
+        let d = digest(b"foo");
+        let mut connections = 0;
+        let connect = |connections: &mut i32, v2: bool| {
+            *connections += 1;
+            let (a, b) = tokio::io::duplex(1024);
+            tokio::spawn(async move {
+                match v2 {
+                    true => {
+                        let mut b = b;
+                        let (_, n) = read_hello(&mut b).await.unwrap();
+                        write_hello(&mut b, &Hello::ControlChannelHello(n.version, d))
+                            .await
+                            .unwrap();
+                    }
+                    false => v1_peer(b).await,
+                }
+            });
+            async move { Ok(a) }
+        };
+
+        // A V2 client falls back to V1 on a new connection
+        let (mut conn, reply, n) = exchange_hello(
+            || connect(&mut connections, false),
+            |v| Hello::ControlChannelHello(v, d),
+        )
+        .await
+        .unwrap();
+        assert_eq!(connections, 2);
+        assert!(matches!(reply, Hello::ControlChannelHello(PROTO_V1, _)));
+        assert_eq!(n, Negotiated::v1());
+        n.write(&mut conn, &Auth(d)).await.unwrap();
+
+        // And talks V2 with a V2 peer at once
+        connections = 0;
+        let (_, _, n) = exchange_hello(
+            || connect(&mut connections, true),
+            |v| Hello::ControlChannelHello(v, d),
+        )
+        .await
+        .unwrap();
+        assert_eq!(connections, 1);
+        assert_eq!(n.version, CURRENT_PROTO_VERSION);
+    }
+
+    #[tokio::test]
+    async fn test_ack_error() {
+        let n = Capabilities::current()
+            .negotiate(&Capabilities::current())
+            .unwrap();
+        let e = HandshakeError::new(HandshakeError::BIND_FAILED, "Address in use".into());
+        let (mut a, mut b) = tokio::io::duplex(256);
+        n.write(&mut a, &Ack::Error(e.clone())).await.unwrap();
+        n.write(&mut a, &Ack::AuthFailed).await.unwrap();
+
+        match read_ack(&mut b, &n).await.unwrap() {
+            Ack::Error(v) => {
+                assert_eq!(v, e);
+                assert!(!v.is_permanent());
+                assert_eq!(Ack::Error(v).to_string(), "Address in use (code 1)");
+            }
+            v => panic!("Unexpected {:?}", v),
+        }
+        let ack = read_ack(&mut b, &n).await.unwrap();
+        assert!(matches!(ack, Ack::AuthFailed));
+        assert!(ack.is_permanent());
+
+        // Codes unknown to this build are transient
+        assert!(!Ack::Error(HandshakeError::new(42, "foo".into())).is_permanent());
+        assert!(Ack::Error(HandshakeError::new(HandshakeError::UNSUPPORTED, "foo".into()))
+            .is_permanent());
+    }
+
+    #[tokio::test]
+    async fn test_framed_message_can_grow() {
+        // A newer peer appends a field to `PortHeader`
+        #[derive(Serialize)]
+        struct PortHeaderV3 {
+            port: u16,
+            offset: u16,
+            extra: u64,
+        }
+
+        let (mut a, mut b) = tokio::io::duplex(64);
+        let hdr = PortHeaderV3 {
+            port: 30001,
+            offset: 1,
+            extra: 42,
+        };
+        write_framed(&mut a, &hdr).await.unwrap();
+        write_framed(&mut a, &ControlChannelCmd::HeartBeat).await.unwrap();
+        let n = Capabilities::current()
+            .negotiate(&Capabilities::current())
+            .unwrap();
+
+        let hdr = read_port_header(&mut b).await.unwrap();
+        assert_eq!(hdr, PortHeader { port: 30001, offset: 1 });
+        // The stream stays in sync after the unknown field
+        assert!(matches!(
+            read_control_cmd(&mut b, &n).await.unwrap(),
+            ControlChannelCmd::HeartBeat
+        ));
+    }
+}
@@ -0,0 +1,259 @@
+// Kerberos through SPNEGO, by the system's GSSAPI library. It's loaded at runtime,
+// so that rathole runs on systems without it, and builds without its headers
+use anyhow::{anyhow, bail, Result};
+use lazy_static::lazy_static;
+use libloading::Library;
+use std::ffi::c_void;
+use std::ptr;
+
+// MIT Kerberos, then Heimdal
+const LIBRARIES: &[&str] = &["libgssapi_krb5.so.2", "libgssapi.so.3"];
+
+// 1.2.840.113554.1.2.1.4, the name type of `service@host`
+const NT_HOSTBASED_SERVICE: &[u8] = b"\x2a\x86\x48\x86\xf7\x12\x01\x02\x01\x04";
+// 1.3.6.1.5.5.2
+const SPNEGO: &[u8] = b"\x2b\x06\x01\x05\x05\x02";
+
+const S_COMPLETE: u32 = 0;
+const S_CONTINUE_NEEDED: u32 = 1;
+const GSS_CODE: i32 = 1;
+const MECH_CODE: i32 = 2;
+
+#[repr(C)]
+struct OidDesc {
+    length: u32,
+    elements: *const c_void,
+}
+
+#[repr(C)]
+struct BufferDesc {
+    length: usize,
+    value: *mut c_void,
+}
+
+impl BufferDesc {
+    fn empty() -> BufferDesc {
+        BufferDesc {
+            length: 0,
+            value: ptr::null_mut(),
+        }
+    }
+
+    fn from(s: &[u8]) -> BufferDesc {
+        BufferDesc {
+            length: s.len(),
+            value: s.as_ptr() as *mut c_void,
+        }
+    }
+}
+
+fn oid(elements: &'static [u8]) -> OidDesc {
+    OidDesc {
+        length: elements.len() as u32,
+        elements: elements.as_ptr() as *const c_void,
+    }
+}
+
+type Name = *mut c_void;
+type Ctx = *mut c_void;
+
+type ImportName = unsafe extern "C" fn(*mut u32, *const BufferDesc, *const OidDesc, *mut Name) -> u32;
+type InitSecContext = unsafe extern "C" fn(
+    *mut u32,
+    *mut c_void,
+    *mut Ctx,
+    Name,
+    *const OidDesc,
+    u32,
+    u32,
+    *mut c_void,
+    *const BufferDesc,
+    *mut *mut OidDesc,
+    *mut BufferDesc,
+    *mut u32,
+    *mut u32,
+) -> u32;
+type ReleaseBuffer = unsafe extern "C" fn(*mut u32, *mut BufferDesc) -> u32;
+type ReleaseName = unsafe extern "C" fn(*mut u32, *mut Name) -> u32;
+type DeleteSecContext = unsafe extern "C" fn(*mut u32, *mut Ctx, *mut BufferDesc) -> u32;
+type DisplayStatus =
+    unsafe extern "C" fn(*mut u32, u32, i32, *const OidDesc, *mut u32, *mut BufferDesc) -> u32;
+
+struct Gssapi {
+    import_name: ImportName,
+    init_sec_context: InitSecContext,
+    release_buffer: ReleaseBuffer,
+    release_name: ReleaseName,
+    delete_sec_context: DeleteSecContext,
+    display_status: DisplayStatus,
+    // Keeps the functions above loaded
+    _lib: Library,
+}
+
+impl Gssapi {
+    fn load() -> Result<Gssapi, String> {
+        let lib = LIBRARIES
+            .iter()
+            // Safety: the libraries run no initialization that has requirements
+            .find_map(|name| unsafe { Library::new(name) }.ok())
+            .ok_or_else(|| format!("No GSSAPI library is found. Tried {}", LIBRARIES.join(", ")))?;
+        // Safety: the types are the same as in gssapi.h
+        unsafe {
+            Ok(Gssapi {
+                import_name: *lib
+                    .get::<ImportName>(b"gss_import_name\0")
+                    .map_err(|e| e.to_string())?,
+                init_sec_context: *lib
+                    .get::<InitSecContext>(b"gss_init_sec_context\0")
+                    .map_err(|e| e.to_string())?,
+                release_buffer: *lib
+                    .get::<ReleaseBuffer>(b"gss_release_buffer\0")
+                    .map_err(|e| e.to_string())?,
+                release_name: *lib
+                    .get::<ReleaseName>(b"gss_release_name\0")
+                    .map_err(|e| e.to_string())?,
+                delete_sec_context: *lib
+                    .get::<DeleteSecContext>(b"gss_delete_sec_context\0")
+                    .map_err(|e| e.to_string())?,
+                display_status: *lib
+                    .get::<DisplayStatus>(b"gss_display_status\0")
+                    .map_err(|e| e.to_string())?,
+                _lib: lib,
+            })
+        }
+    }
+
+    // The messages of a status, which is either a major or a minor one
+    fn messages(&self, status: u32, status_type: i32) -> Vec<String> {
+        let mut messages = Vec::new();
+        let mut message_context = 0;
+        loop {
+            let (mut minor, mut buf) = (0, BufferDesc::empty());
+            // Safety: `buf` is released right after being copied
+            unsafe {
+                let major = (self.display_status)(
+                    &mut minor,
+                    status,
+                    status_type,
+                    ptr::null(),
+                    &mut message_context,
+                    &mut buf,
+                );
+                if major != S_COMPLETE {
+                    break;
+                }
+                let s = std::slice::from_raw_parts(buf.value as *const u8, buf.length);
+                messages.push(String::from_utf8_lossy(s).trim().to_string());
+                (self.release_buffer)(&mut minor, &mut buf);
+            }
+            if message_context == 0 {
+                break;
+            }
+        }
+        messages
+    }
+
+    fn error(&self, op: &str, major: u32, minor: u32) -> anyhow::Error {
+        let mut messages = self.messages(major, GSS_CODE);
+        messages.extend(self.messages(minor, MECH_CODE));
+        anyhow!("{} failed: {}", op, messages.join(". "))
+    }
+}
+
+lazy_static! {
+    static ref GSSAPI: Result<Gssapi, String> = Gssapi::load();
+}
+
+/// A security context with the proxy, which starts with the credentials of `kinit`
+pub struct Context {
+    lib: &'static Gssapi,
+    target: Name,
+    ctx: Ctx,
+    complete: bool,
+}
+
+// Safety: the context is only used by one task at a time
+unsafe impl Send for Context {}
+
+impl Context {
+    pub fn new(host: &str) -> Result<Context> {
+        let lib = GSSAPI.as_ref().map_err(|e| anyhow!("{}", e))?;
+        let service = format!("HTTP@{}", host);
+        let name_type = oid(NT_HOSTBASED_SERVICE);
+        let (mut minor, mut target) = (0, ptr::null_mut());
+        // Safety: the name is copied by GSSAPI
+        let major = unsafe {
+            (lib.import_name)(
+                &mut minor,
+                &BufferDesc::from(service.as_bytes()),
+                &name_type,
+                &mut target,
+            )
+        };
+        if major != S_COMPLETE {
+            return Err(lib.error("gss_import_name", major, minor));
+        }
+        Ok(Context {
+            lib,
+            target,
+            ctx: ptr::null_mut(),
+            complete: false,
+        })
+    }
+
+    /// The token to send, given the one from the proxy
+    pub fn step(&mut self, input: Option<&[u8]>) -> Result<Vec<u8>> {
+        if self.complete {
+            bail!("The GSSAPI context is complete");
+        }
+        let mech = oid(SPNEGO);
+        let input = input.map_or_else(BufferDesc::empty, BufferDesc::from);
+        let (mut minor, mut output) = (0, BufferDesc::empty());
+        // Safety: `output` is released right after being copied
+        unsafe {
+            let major = (self.lib.init_sec_context)(
+                &mut minor,
+                ptr::null_mut(),
+                &mut self.ctx,
+                self.target,
+                &mech,
+                0,
+                0,
+                ptr::null_mut(),
+                &input,
+                ptr::null_mut(),
+                &mut output,
+                ptr::null_mut(),
+                ptr::null_mut(),
+            );
+            let token = match output.value.is_null() {
+                true => Vec::new(),
+                false => std::slice::from_raw_parts(output.value as *const u8, output.length).to_vec(),
+            };
+            (self.lib.release_buffer)(&mut 0, &mut output);
+            match major {
+                S_COMPLETE => self.complete = true,
+                S_CONTINUE_NEEDED => (),
+                _ => return Err(self.lib.error("gss_init_sec_context", major, minor)),
+            }
+            Ok(token)
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        let mut minor = 0;
+        // Safety: they are created by GSSAPI, and not used after
+        unsafe {
+            if !self.ctx.is_null() {
+                (self.lib.delete_sec_context)(&mut minor, &mut self.ctx, ptr::null_mut());
+            }
+            (self.lib.release_name)(&mut minor, &mut self.target);
+        }
+    }
+}
@@ -0,0 +1,322 @@
+// NTLMv2, as in MS-NLMP. Only what's needed to authenticate with a proxy,
+// so there's no signing or sealing of the session
+use super::{Credentials, ProxyAuth};
+use crate::config::ProxyAuthScheme;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5_digest::Md5;
+use rand::RngCore;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SIGNATURE: &[u8] = b"NTLMSSP\0";
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NEGOTIATE_OEM: u32 = 0x0000_0002;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_FLAGS: u32 = NEGOTIATE_UNICODE
+    | NEGOTIATE_OEM
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY;
+
+// The AV pair of the server's time in the target info
+const MSV_AV_EOL: u16 = 0;
+const MSV_AV_TIMESTAMP: u16 = 7;
+
+// Seconds from 1601-01-01, where FILETIME starts, to the unix epoch
+const FILETIME_UNIX_EPOCH: u64 = 11_644_473_600;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Stage {
+    Start,
+    Negotiated,
+    Done,
+}
+
+/// NTLM, which is also sent as `Negotiate` when Kerberos is not available
+pub struct Ntlm {
+    scheme: ProxyAuthScheme,
+    username: String,
+    domain: String,
+    password: String,
+    stage: Stage,
+}
+
+impl Ntlm {
+    pub fn new(scheme: ProxyAuthScheme, credentials: &Credentials) -> Ntlm {
+        // `DOMAIN\user`, or just `user`
+        let (domain, username) = credentials
+            .username
+            .split_once('\\')
+            .unwrap_or(("", &credentials.username));
+        Ntlm {
+            scheme,
+            username: username.to_string(),
+            domain: domain.to_string(),
+            password: credentials.password.clone(),
+            stage: Stage::Start,
+        }
+    }
+}
+
+#[async_trait]
+impl ProxyAuth for Ntlm {
+    fn scheme(&self) -> ProxyAuthScheme {
+        self.scheme
+    }
+
+    async fn respond(&mut self, challenge: Option<&str>) -> Result<Option<String>> {
+        let msg = match (&self.stage, challenge) {
+            (Stage::Start, _) => {
+                self.stage = Stage::Negotiated;
+                negotiate_message()
+            }
+            (Stage::Negotiated, Some(challenge)) => {
+                self.stage = Stage::Done;
+                let challenge = base64::decode(challenge)
+                    .with_context(|| "Invalid NTLM challenge of the proxy")?;
+                let mut client_challenge = [0u8; 8];
+                rand::thread_rng().fill_bytes(&mut client_challenge);
+                authenticate_message(
+                    &challenge,
+                    &self.username,
+                    &self.domain,
+                    &self.password,
+                    client_challenge,
+                    now_filetime(),
+                )?
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(format!("{} {}", self.scheme, base64::encode(msg))))
+    }
+}
+
+fn now_filetime() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (since_epoch.as_secs() + FILETIME_UNIX_EPOCH) * 10_000_000
+        + since_epoch.subsec_nanos() as u64 / 100
+}
+
+fn utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = Hmac::<Md5>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn nt_hash(password: &str) -> [u8; 16] {
+    Md4::digest(utf16le(password)).into()
+}
+
+fn ntowf_v2(username: &str, domain: &str, password: &str) -> [u8; 16] {
+    hmac_md5(
+        &nt_hash(password),
+        &[&utf16le(&username.to_uppercase()), &utf16le(domain)],
+    )
+}
+
+fn negotiate_message() -> Vec<u8> {
+    let mut msg = SIGNATURE.to_vec();
+    msg.extend(1u32.to_le_bytes());
+    msg.extend(NEGOTIATE_FLAGS.to_le_bytes());
+    // Empty domain and workstation
+    msg.extend([0u8; 16]);
+    msg
+}
+
+// The server challenge, the flags and the target info in a CHALLENGE_MESSAGE
+fn parse_challenge(msg: &[u8]) -> Result<([u8; 8], u32, &[u8])> {
+    if msg.len() < 32 || &msg[..8] != SIGNATURE || msg[8..12] != 2u32.to_le_bytes() {
+        bail!("Not an NTLM challenge");
+    }
+    let u16_at = |i: usize| u16::from_le_bytes([msg[i], msg[i + 1]]) as usize;
+    let u32_at = |i: usize| u32::from_le_bytes([msg[i], msg[i + 1], msg[i + 2], msg[i + 3]]);
+
+    let flags = u32_at(20);
+    let server_challenge = msg[24..32].try_into()?;
+    let target_info = if msg.len() >= 48 {
+        let (len, offset) = (u16_at(40), u32_at(44) as usize);
+        msg.get(offset..offset + len)
+            .ok_or_else(|| anyhow::anyhow!("Invalid target info in the NTLM challenge"))?
+    } else {
+        &[]
+    };
+    Ok((server_challenge, flags, target_info))
+}
+
+// The server's time in the target info
+fn timestamp_of(mut target_info: &[u8]) -> Option<u64> {
+    while target_info.len() >= 4 {
+        let id = u16::from_le_bytes([target_info[0], target_info[1]]);
+        let len = u16::from_le_bytes([target_info[2], target_info[3]]) as usize;
+        let value = target_info.get(4..4 + len)?;
+        match id {
+            MSV_AV_EOL => break,
+            MSV_AV_TIMESTAMP => return Some(u64::from_le_bytes(value.try_into().ok()?)),
+            _ => target_info = &target_info[4 + len..],
+        }
+    }
+    None
+}
+
+// NTProofStr and the blob after it, which make up the NTLMv2 response
+fn nt_proof(
+    ntowf: &[u8; 16],
+    server_challenge: &[u8; 8],
+    client_challenge: [u8; 8],
+    timestamp: u64,
+    target_info: &[u8],
+) -> ([u8; 16], Vec<u8>) {
+    let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    blob.extend(timestamp.to_le_bytes());
+    blob.extend(client_challenge);
+    blob.extend([0u8; 4]);
+    blob.extend(target_info);
+    blob.extend([0u8; 4]);
+    (hmac_md5(ntowf, &[server_challenge, &blob]), blob)
+}
+
+fn authenticate_message(
+    challenge: &[u8],
+    username: &str,
+    domain: &str,
+    password: &str,
+    client_challenge: [u8; 8],
+    now: u64,
+) -> Result<Vec<u8>> {
+    let (server_challenge, flags, target_info) = parse_challenge(challenge)?;
+    let ntowf = ntowf_v2(username, domain, password);
+
+    let server_time = timestamp_of(target_info);
+    let (proof, blob) = nt_proof(
+        &ntowf,
+        &server_challenge,
+        client_challenge,
+        server_time.unwrap_or(now),
+        target_info,
+    );
+    let nt_response = [&proof[..], &blob].concat();
+    // Servers that send their time expect no LMv2 response
+    let lm_response = match server_time {
+        Some(_) => vec![0u8; 24],
+        None => [
+            &hmac_md5(&ntowf, &[&server_challenge, &client_challenge])[..],
+            &client_challenge,
+        ]
+        .concat(),
+    };
+
+    let fields = [
+        lm_response,
+        nt_response,
+        utf16le(domain),
+        utf16le(username),
+        // Workstation and session key
+        Vec::new(),
+        Vec::new(),
+    ];
+    let header_len = 64;
+    let mut msg = SIGNATURE.to_vec();
+    msg.extend(3u32.to_le_bytes());
+    let mut offset = header_len;
+    for field in &fields {
+        msg.extend((field.len() as u16).to_le_bytes());
+        msg.extend((field.len() as u16).to_le_bytes());
+        msg.extend((offset as u32).to_le_bytes());
+        offset += field.len();
+    }
+    msg.extend(((flags & NEGOTIATE_FLAGS) | NEGOTIATE_UNICODE).to_le_bytes());
+    for field in fields {
+        msg.extend(field);
+    }
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The NTLMv2 example in MS-NLMP 4.2.4
+    const TARGET_INFO: &str = "02000c0044006f006d00610069006e0001000c0053006500720076006500720000000000";
+
+    #[test]
+    fn test_ntlm_v2() {
+        assert_eq!(
+            hex::encode(nt_hash("Password")),
+            "a4f49c406510bdcab6824ee7c30fd852"
+        );
+        let ntowf = ntowf_v2("User", "Domain", "Password");
+        assert_eq!(hex::encode(ntowf), "0c868a403bfd7a93a3001ef22ef02e3f");
+
+        let server_challenge = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+        let (proof, _) = nt_proof(
+            &ntowf,
+            &server_challenge,
+            [0xaa; 8],
+            0,
+            &hex::decode(TARGET_INFO).unwrap(),
+        );
+        assert_eq!(hex::encode(proof), "68cd0ab851e51c96aabc927bebef6a1c");
+    }
+
+    #[tokio::test]
+    async fn test_messages() -> Result<()> {
+        let target_info = hex::decode(TARGET_INFO)?;
+        let mut challenge = SIGNATURE.to_vec();
+        challenge.extend(2u32.to_le_bytes());
+        // Empty target name
+        challenge.extend([0, 0, 0, 0, 48, 0, 0, 0]);
+        challenge.extend(NEGOTIATE_FLAGS.to_le_bytes());
+        challenge.extend([0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef]);
+        challenge.extend([0u8; 8]);
+        challenge.extend((target_info.len() as u16).to_le_bytes());
+        challenge.extend((target_info.len() as u16).to_le_bytes());
+        challenge.extend(48u32.to_le_bytes());
+        challenge.extend(&target_info);
+
+        let msg = authenticate_message(&challenge, "User", "Domain", "Password", [0xaa; 8], 0)?;
+        assert_eq!(&msg[..12], b"NTLMSSP\0\x03\0\0\0");
+        let field = |i: usize| {
+            let len = u16::from_le_bytes([msg[i], msg[i + 1]]) as usize;
+            let offset = u32::from_le_bytes(msg[i + 4..i + 8].try_into().unwrap()) as usize;
+            &msg[offset..offset + len]
+        };
+        assert_eq!(
+            hex::encode(&field(20)[..16]),
+            "68cd0ab851e51c96aabc927bebef6a1c"
+        );
+        assert_eq!(field(28), utf16le("Domain"));
+        assert_eq!(field(36), utf16le("User"));
+
+        let mut ntlm = Ntlm::new(
+            ProxyAuthScheme::Ntlm,
+            &Credentials {
+                username: String::from("Domain\\User"),
+                password: String::from("Password"),
+            },
+        );
+        assert_eq!(ntlm.domain, "Domain");
+        let negotiate = ntlm.respond(None).await?.unwrap();
+        assert_eq!(
+            negotiate,
+            format!("NTLM {}", base64::encode(negotiate_message()))
+        );
+        assert!(ntlm.respond(Some(&base64::encode(&challenge))).await?.is_some());
+        assert!(ntlm.respond(Some(&base64::encode(&challenge))).await?.is_none());
+        Ok(())
+    }
+}
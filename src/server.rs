@@ -1,14 +1,22 @@
-use crate::config::{Config, ServerConfig, ServerServiceConfig, ServiceType, TransportType};
-use crate::config_watcher::{ConfigChange, ServerServiceChange};
-use crate::constants::{listen_backoff, UDP_BUFFER_SIZE};
-use crate::helper::{retry_notify_with_deadline, write_and_flush};
+use crate::config::{
+    Config, ServerClientConfig, ServerConfig, ServerServiceConfig, ServiceType, TransportType,
+};
+use crate::config_watcher::{ConfigChange, ServerServiceChange, ServerSettingChange};
+use crate::constants::{listen_backoff, UDP_BUFFER_SIZE, UDP_TIMEOUT};
+use crate::helper::{
+    current_conns, forward_conn, host_port_range, retry_notify_with_deadline, write_and_flush,
+    ConnGuard, Conns,
+};
 use crate::multi_map::MultiMap;
-use crate::protocol::Hello::{ControlChannelHello, DataChannelHello};
+use crate::protocol::Hello::{ControlChannelHello, DataChannelHello, DynamicControlChannelHello};
 use crate::protocol::{
-    self, read_auth, read_hello, Ack, ControlChannelCmd, DataChannelCmd, Hello, UdpTraffic,
-    HASH_WIDTH_IN_BYTES,
+    self, encode_framed, read_auth, read_framed, read_hello, write_framed, write_hello, Ack,
+    ControlChannelCmd, DataChannelCmd, HandshakeError, Hello, Negotiated, PortHeader,
+    ServiceAssignment, ServiceRequest, ServiceStatus, UdpTraffic, CAP_DRAIN, CAP_DYNAMIC_SERVICE,
+    CAP_PORT_RANGE, CAP_SERVICE_STATUS, HASH_WIDTH_IN_BYTES,
 };
-use crate::transport::{SocketOpts, TcpTransport, Transport};
+use crate::transport::{NotTunnel, SocketOpts, TcpTransport, Transport};
+use crate::vhost;
 use anyhow::{anyhow, bail, Context, Result};
 use backoff::backoff::Backoff;
 use backoff::ExponentialBackoff;
@@ -17,9 +25,9 @@ use rand::RngCore;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{self, copy_bidirectional, AsyncReadExt, AsyncWriteExt};
+use tokio::io::{self, copy_bidirectional, AsyncReadExt};
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
-use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::sync::{broadcast, mpsc, watch, Mutex, RwLock};
 use tokio::time;
 use tracing::{debug, error, info, info_span, instrument, warn, Instrument, Span};
 use std::fs::File;
@@ -32,6 +40,8 @@ use crate::transport::NoiseTransport;
 use crate::transport::TlsTransport;
 #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
 use crate::transport::WebsocketTransport;
+#[cfg(any(feature = "http2-native-tls", feature = "http2-rustls"))]
+use crate::transport::Http2Transport;
 
 type ServiceDigest = protocol::Digest; // SHA256 of a service name
 type Nonce = protocol::Digest; // Also called `session_key`
@@ -114,6 +124,15 @@ pub async fn run_server(
             #[cfg(not(any(feature = "websocket-native-tls", feature = "websocket-rustls")))]
             crate::helper::feature_neither_compile("websocket-native-tls", "websocket-rustls")
         }
+        TransportType::Http2 => {
+            #[cfg(any(feature = "http2-native-tls", feature = "http2-rustls"))]
+            {
+                let mut server = Server::<Http2Transport>::from(config).await?;
+                server.run(shutdown_rx, update_rx).await?;
+            }
+            #[cfg(not(any(feature = "http2-native-tls", feature = "http2-rustls")))]
+            crate::helper::feature_neither_compile("http2-native-tls", "http2-rustls")
+        }
     }
 
     Ok(())
@@ -123,6 +142,14 @@ pub async fn run_server(
 // See also MultiMap
 type ControlChannelMap<T> = MultiMap<ServiceDigest, Nonce, ControlChannelHandle<T>>;
 
+// Ports assigned to services declared by clients, indexed by ServiceDigest
+type DynamicServiceMap = HashMap<ServiceDigest, DynamicService>;
+
+struct DynamicService {
+    client: String, // The name of the client that declares the service
+    port: u16,      // The port assigned by the server
+}
+
 // Server holds all states of running a server
 struct Server<T: Transport> {
     // `[server]` config
@@ -132,10 +159,28 @@ struct Server<T: Transport> {
     services: Arc<RwLock<HashMap<ServiceDigest, ServerServiceConfig>>>,
     // Collection of contorl channels
     control_channels: Arc<RwLock<ControlChannelMap<T>>>,
+    // Services declared by `[server.clients]`
+    dynamic_services: Arc<RwLock<DynamicServiceMap>>,
+    // Shared by all control channels
+    shared: ControlChannelShared,
+    // Session keys of control channels that have exited
+    closed_rx: mpsc::UnboundedReceiver<Nonce>,
+    // Updates `server.heartbeat_interval` of running control channels
+    heartbeat_tx: watch::Sender<u64>,
+    // Updates `server.grace_period` of running control channels
+    grace_tx: watch::Sender<u64>,
     // Wrapper around the transport layer
     transport: Arc<T>,
 }
 
+// What every control channel needs from the server
+#[derive(Clone)]
+struct ControlChannelShared {
+    closed_tx: mpsc::UnboundedSender<Nonce>, // Reports the session key of an exited control channel
+    heartbeat_interval: watch::Receiver<u64>, // Application-layer heartbeat interval in secs
+    grace_period: watch::Receiver<u64>, // How long to wait for in-flight connections in secs
+}
+
 // Generate a hash map of services which is indexed by ServiceDigest
 fn generate_service_hashmap(
     server_config: &ServerConfig,
@@ -153,11 +198,25 @@ impl<T: 'static + Transport> Server<T> {
         let config = Arc::new(config);
         let services = Arc::new(RwLock::new(generate_service_hashmap(&config)));
         let control_channels = Arc::new(RwLock::new(ControlChannelMap::new()));
+        let dynamic_services = Arc::new(RwLock::new(DynamicServiceMap::new()));
+        let (closed_tx, closed_rx) = mpsc::unbounded_channel();
+        let (heartbeat_tx, heartbeat_interval) = watch::channel(config.heartbeat_interval);
+        let (grace_tx, grace_period) = watch::channel(config.grace_period);
+        let shared = ControlChannelShared {
+            closed_tx,
+            heartbeat_interval,
+            grace_period,
+        };
         let transport = Arc::new(T::new(&config.transport)?);
         Ok(Server {
             config,
             services,
             control_channels,
+            dynamic_services,
+            shared,
+            closed_rx,
+            heartbeat_tx,
+            grace_tx,
             transport,
         })
     }
@@ -176,6 +235,23 @@ impl<T: 'static + Transport> Server<T> {
             .with_context(|| "Failed to listen at `server.bind_addr`")?;
         info!("Listening at {}", self.config.bind_addr);
 
+        if let Some(mux) = &self.config.mux {
+            let l = TcpListener::bind(&mux.bind_addr)
+                .await
+                .with_context(|| "Failed to listen at `server.mux.bind_addr`")?;
+            info!("Listening at {} for services by hostnames", mux.bind_addr);
+            tokio::spawn(
+                run_mux(
+                    l,
+                    mux.default_service.clone(),
+                    self.services.clone(),
+                    self.control_channels.clone(),
+                    shutdown_rx.resubscribe(),
+                )
+                .instrument(info_span!("mux")),
+            );
+        }
+
         // Retry at least every 100ms
         let mut backoff = ExponentialBackoff {
             max_interval: Duration::from_millis(100),
@@ -217,14 +293,20 @@ impl<T: 'static + Transport> Server<T> {
                                         Ok(conn) => {
                                             let services = self.services.clone();
                                             let control_channels = self.control_channels.clone();
+                                            let dynamic_services = self.dynamic_services.clone();
+                                            let shared = self.shared.clone();
                                             let server_config = self.config.clone();
                                             tokio::spawn(async move {
-                                                if let Err(err) = handle_connection(conn, services, control_channels, server_config).await {
+                                                if let Err(err) = handle_connection(conn, services, control_channels, dynamic_services, shared, server_config).await {
                                                     error!("{:#}", err);
                                                 }
                                             }.instrument(info_span!("connection", %addr)));
                                         }, Err(e) => {
-                                            error!("{:#}", e);
+                                            if let Some(e) = e.downcast_ref::<NotTunnel>() {
+                                                debug!("{}", e);
+                                            } else {
+                                                error!("{:#}", e);
+                                            }
                                         }
                                     }
                                 },
@@ -244,6 +326,10 @@ impl<T: 'static + Transport> Server<T> {
                     if let Some(e) = e {
                         self.handle_hot_reload(e).await;
                     }
+                },
+                // Clean up control channels that have exited
+                Some(session_key) = self.closed_rx.recv() => {
+                    self.handle_closed_control_channel(session_key).await;
                 }
             }
         }
@@ -271,10 +357,94 @@ impl<T: 'static + Transport> Server<T> {
                     let mut wg = self.control_channels.write().await;
                     let _ = wg.remove1(&hash);
                 }
+                ServerServiceChange::Update(cfg) => {
+                    let hash = protocol::digest(cfg.name.as_bytes());
+                    let old = self.services.write().await.insert(hash, cfg.clone());
+
+                    // Keep the control channel unless the client has to authenticate again
+                    // or the port has to be bound again
+                    match old {
+                        Some(old) if !needs_restart(&old, &cfg) => {
+                            info!("Service {} updated in place", cfg.name);
+                            if let Some(handle) = self.control_channels.read().await.get1(&hash) {
+                                handle.update(cfg);
+                            }
+                        }
+                        _ => {
+                            let mut wg = self.control_channels.write().await;
+                            let _ = wg.remove1(&hash);
+                        }
+                    }
+                }
             },
+            ConfigChange::ServerSettingChange(setting_change) => {
+                let mut config = (*self.config).clone();
+                match setting_change {
+                    ServerSettingChange::HeartbeatInterval(v) => {
+                        info!("Heartbeat interval changed to {}s", v);
+                        let _ = self.heartbeat_tx.send(v);
+                        config.heartbeat_interval = v;
+                    }
+                    // Drained by `lib::run` on exit
+                    ServerSettingChange::GracePeriod(v) => {
+                        let _ = self.grace_tx.send(v);
+                        config.grace_period = v;
+                    }
+                    ServerSettingChange::Clients(clients) => {
+                        // New handshakes look up the new clients. The services of a client that
+                        // is changed or removed are dropped, so that it declares them again
+                        let mut dynamic_services = self.dynamic_services.write().await;
+                        let mut control_channels = self.control_channels.write().await;
+                        dynamic_services.retain(|digest, s| {
+                            let keep = config.clients.get(&s.client) == clients.get(&s.client);
+                            if !keep {
+                                info!("Dropped the service on port {} declared by client {}", s.port, s.client);
+                                let _ = control_channels.remove1(digest);
+                            }
+                            keep
+                        });
+                        config.clients = clients;
+                    }
+                    // The listener is kept. Only connections accepted from now on
+                    // go through the new transport
+                    ServerSettingChange::Transport(transport) => match T::new(&transport) {
+                        Ok(v) => {
+                            info!("Transport settings reloaded");
+                            self.transport = Arc::new(v);
+                            config.transport = *transport;
+                        }
+                        Err(e) => {
+                            error!("{:#}", e.context("Failed to reload the transport. Ignored"));
+                            return;
+                        }
+                    },
+                }
+                self.config = Arc::new(config);
+            }
             ignored => warn!("Ignored {:?} since running as a server", ignored),
         }
     }
+
+    async fn handle_closed_control_channel(&mut self, session_key: Nonce) {
+        let handle = self.control_channels.write().await.remove2(&session_key);
+        if let Some(handle) = handle {
+            let name = handle.service.borrow().name.clone();
+            let digest = protocol::digest(name.as_bytes());
+            if let Some(s) = self.dynamic_services.write().await.remove(&digest) {
+                info!(
+                    "Released port {} of service {} declared by client {}",
+                    s.port, name, s.client
+                );
+            }
+        }
+    }
+}
+
+// Whether a change of the service can't be applied to its running control channel
+fn needs_restart(old: &ServerServiceConfig, new: &ServerServiceConfig) -> bool {
+    old.token != new.token
+        || old.bind_addr != new.bind_addr
+        || old.service_type != new.service_type
 }
 
 // Handle connections to `server.bind_addr`
@@ -282,10 +452,12 @@ async fn handle_connection<T: 'static + Transport>(
     mut conn: T::Stream,
     services: Arc<RwLock<HashMap<ServiceDigest, ServerServiceConfig>>>,
     control_channels: Arc<RwLock<ControlChannelMap<T>>>,
+    dynamic_services: Arc<RwLock<DynamicServiceMap>>,
+    shared: ControlChannelShared,
     server_config: Arc<ServerConfig>,
 ) -> Result<()> {
     // Read hello
-    let hello = read_hello(&mut conn).await?;
+    let (hello, negotiated) = read_hello(&mut conn).await?;
     match hello {
         ControlChannelHello(_, service_digest) => {
             do_control_channel_handshake(
@@ -293,6 +465,19 @@ async fn handle_connection<T: 'static + Transport>(
                 services,
                 control_channels,
                 service_digest,
+                negotiated,
+                shared,
+            )
+            .await?;
+        }
+        DynamicControlChannelHello(_, client_digest) => {
+            do_dynamic_control_channel_handshake(
+                conn,
+                control_channels,
+                dynamic_services,
+                client_digest,
+                negotiated,
+                shared,
                 server_config,
             )
             .await?;
@@ -309,7 +494,8 @@ async fn do_control_channel_handshake<T: 'static + Transport>(
     services: Arc<RwLock<HashMap<ServiceDigest, ServerServiceConfig>>>,
     control_channels: Arc<RwLock<ControlChannelMap<T>>>,
     service_digest: ServiceDigest,
-    server_config: Arc<ServerConfig>,
+    negotiated: Negotiated,
+    shared: ControlChannelShared,
 ) -> Result<()> {
     info!("Try to handshake a control channel");
 
@@ -321,19 +507,16 @@ async fn do_control_channel_handshake<T: 'static + Transport>(
 
     // Send hello
     let hello_send = Hello::ControlChannelHello(
-        protocol::CURRENT_PROTO_VERSION,
+        negotiated.version,
         nonce.clone().try_into().unwrap(),
     );
-    conn.write_all(&bincode::serialize(&hello_send).unwrap())
-        .await?;
-    conn.flush().await?;
+    write_hello(&mut conn, &hello_send).await?;
 
     // Lookup the service
     let service_config = match services.read().await.get(&service_digest) {
         Some(v) => v,
         None => {
-            conn.write_all(&bincode::serialize(&Ack::ServiceNotExist).unwrap())
-                .await?;
+            negotiated.write(&mut conn, &Ack::ServiceNotExist).await?;
             bail!("No such a service {}", hex::encode(service_digest));
         }
     }
@@ -346,13 +529,12 @@ async fn do_control_channel_handshake<T: 'static + Transport>(
     concat.append(&mut nonce);
 
     // Read auth
-    let protocol::Auth(d) = read_auth(&mut conn).await?;
+    let protocol::Auth(d) = read_auth(&mut conn, &negotiated).await?;
 
     // Validate
     let session_key = protocol::digest(&concat);
     if session_key != d {
-        conn.write_all(&bincode::serialize(&Ack::AuthFailed).unwrap())
-            .await?;
+        negotiated.write(&mut conn, &Ack::AuthFailed).await?;
         debug!(
             "Expect {}, but got {}",
             hex::encode(session_key),
@@ -360,6 +542,39 @@ async fn do_control_channel_handshake<T: 'static + Transport>(
         );
         bail!("Service {} failed the authentication", service_name);
     } else {
+        if service_listen_addrs(&service_config.bind_addr).len() > 1
+            && !negotiated.supports(CAP_PORT_RANGE)
+        {
+            let e = HandshakeError::new(
+                HandshakeError::UNSUPPORTED,
+                "The service has a port range, which the client doesn't support".to_string(),
+            );
+            negotiated.write(&mut conn, &Ack::Error(e)).await?;
+            bail!(
+                "The client of service {} doesn't support port ranges",
+                service_name
+            );
+        }
+
+        // Listen for a new service before taking the lock, and hand the sockets to the pools.
+        // A reconnecting service keeps the ports of its previous control channel
+        let exists = control_channels.read().await.get1(&service_digest).is_some();
+        let bound = match exists {
+            true => Vec::new(),
+            false => match bind_service(&service_config).await {
+                Ok(v) => v,
+                Err(e) => {
+                    // Report the failure to the client, rather than retrying in the connection pool
+                    let e = HandshakeError::new(
+                        HandshakeError::BIND_FAILED,
+                        format!("Failed to listen at {}: {}", service_config.bind_addr, e),
+                    );
+                    negotiated.write(&mut conn, &Ack::Error(e.clone())).await?;
+                    bail!("Service {}: {}", service_name, e.message);
+                }
+            },
+        };
+
         let mut h = control_channels.write().await;
 
         // If there's already a control channel for the service, then drop the old one.
@@ -374,13 +589,17 @@ async fn do_control_channel_handshake<T: 'static + Transport>(
         }
 
         // Send ack
-        conn.write_all(&bincode::serialize(&Ack::Ok).unwrap())
-            .await?;
-        conn.flush().await?;
+        negotiated.write(&mut conn, &Ack::Ok).await?;
 
         info!(service = %service_config.name, "Control channel established");
-        let handle =
-            ControlChannelHandle::new(conn, service_config, server_config.heartbeat_interval);
+        let handle = ControlChannelHandle::new(
+            conn,
+            service_config,
+            negotiated,
+            shared,
+            session_key,
+            bound,
+        );
 
         // Insert the new handle
         let _ = h.insert(service_digest, session_key, handle);
@@ -389,6 +608,229 @@ async fn do_control_channel_handshake<T: 'static + Transport>(
     Ok(())
 }
 
+// Listen at every address of a service, in the order of `service_listen_addrs`
+async fn bind_service(service: &ServerServiceConfig) -> io::Result<Vec<BoundSocket>> {
+    let mut bound = Vec::new();
+    for (addr, _) in service_listen_addrs(&service.bind_addr) {
+        bound.push(match service.service_type {
+            ServiceType::Tcp => BoundSocket::Tcp(TcpListener::bind(&addr).await?),
+            ServiceType::Udp => BoundSocket::Udp(UdpSocket::bind(&addr).await?),
+        });
+    }
+    Ok(bound)
+}
+
+// Handshake a control channel for a service declared by the client,
+// and assign it a port from the range granted to the client
+async fn do_dynamic_control_channel_handshake<T: 'static + Transport>(
+    mut conn: T::Stream,
+    control_channels: Arc<RwLock<ControlChannelMap<T>>>,
+    dynamic_services: Arc<RwLock<DynamicServiceMap>>,
+    client_digest: protocol::Digest,
+    negotiated: Negotiated,
+    shared: ControlChannelShared,
+    server_config: Arc<ServerConfig>,
+) -> Result<()> {
+    info!("Try to handshake a dynamic control channel");
+    if !negotiated.supports(CAP_DYNAMIC_SERVICE) {
+        bail!("The client doesn't support dynamic services");
+    }
+
+    T::hint(&conn, SocketOpts::for_control_channel());
+
+    // Generate a nonce
+    let mut nonce = vec![0u8; HASH_WIDTH_IN_BYTES];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    // Send hello
+    let hello_send = Hello::ControlChannelHello(
+        negotiated.version,
+        nonce.clone().try_into().unwrap(),
+    );
+    write_hello(&mut conn, &hello_send).await?;
+
+    // Lookup the client
+    let client_config = match server_config
+        .clients
+        .values()
+        .find(|c| protocol::digest(c.name.as_bytes()) == client_digest)
+    {
+        Some(v) => v,
+        None => {
+            negotiated.write(&mut conn, &Ack::ServiceNotExist).await?;
+            bail!("No such a client {}", hex::encode(client_digest));
+        }
+    };
+
+    // Calculate the checksum
+    let mut concat = Vec::from(client_config.token.as_ref().unwrap().as_bytes());
+    concat.append(&mut nonce);
+
+    // Read auth and the service declared by the client
+    let protocol::Auth(d) = read_auth(&mut conn, &negotiated).await?;
+    let req: ServiceRequest = read_framed(&mut conn).await?;
+
+    // Validate
+    let session_key = protocol::digest(&concat);
+    if session_key != d {
+        negotiated.write(&mut conn, &Ack::AuthFailed).await?;
+        debug!(
+            "Expect {}, but got {}",
+            hex::encode(session_key),
+            hex::encode(d)
+        );
+        bail!("Client {} failed the authentication", client_config.name);
+    }
+
+    let service_name = format!("{}/{}", client_config.name, req.name);
+    let service_digest = protocol::digest(service_name.as_bytes());
+
+    let assigned = allocate_port(client_config, &dynamic_services, &service_digest, &req).await;
+    let (port, bound) = {
+        let mut dynamic_services = dynamic_services.write().await;
+        // Another service of the client may have taken the quota during the scan
+        let assigned = assigned.and_then(|v| match v {
+            (_, Some(_)) if over_quota(client_config, &dynamic_services, &service_digest) => {
+                Err(Ack::QuotaExceeded)
+            }
+            v => Ok(v),
+        });
+        match assigned {
+            Ok(v) => {
+                dynamic_services.insert(
+                    service_digest,
+                    DynamicService {
+                        client: client_config.name.clone(),
+                        port: v.0,
+                    },
+                );
+                v
+            }
+            Err(ack) => {
+                drop(dynamic_services);
+                negotiated.write(&mut conn, &ack).await?;
+                bail!("Failed to assign a port to service {}: {}", service_name, ack);
+            }
+        }
+    };
+
+    let (host, _) = host_port_range(&client_config.bind_addr)?;
+    let service_config = ServerServiceConfig {
+        service_type: req.service_type,
+        name: service_name,
+        bind_addr: format!("{}:{}", host, port),
+        token: client_config.token.clone(),
+        token_file: None,
+        nodelay: None,
+        hostnames: Vec::new(),
+    };
+
+    let mut h = control_channels.write().await;
+
+    // Drop the previous control channel of the service, if any
+    if h.remove1(&service_digest).is_some() {
+        warn!(
+            "Dropping previous control channel for service {}",
+            service_config.name
+        );
+    }
+
+    // Send ack and the assigned address
+    negotiated.write(&mut conn, &Ack::Ok).await?;
+    write_framed(
+        &mut conn,
+        &ServiceAssignment {
+            bind_addr: service_config.bind_addr.clone(),
+        },
+    )
+    .await?;
+
+    info!(
+        service = %service_config.name,
+        "Control channel established. Exposed at {}", service_config.bind_addr
+    );
+    let handle = ControlChannelHandle::new(
+        conn,
+        service_config,
+        negotiated,
+        shared,
+        session_key,
+        bound.into_iter().collect(),
+    );
+
+    // Insert the new handle
+    let _ = h.insert(service_digest, session_key, handle);
+
+    Ok(())
+}
+
+// A socket bound before the control channel is established, which the connection pool
+// takes over, so that nothing else can take the port in between
+enum BoundSocket {
+    Tcp(TcpListener),
+    Udp(UdpSocket),
+}
+
+// Whether the client already has as many other services as `max_services`
+fn over_quota(
+    client: &ServerClientConfig,
+    dynamic_services: &DynamicServiceMap,
+    service_digest: &ServiceDigest,
+) -> bool {
+    client.max_services.is_some_and(|max| {
+        dynamic_services
+            .iter()
+            .filter(|(k, s)| *k != service_digest && s.client == client.name)
+            .count()
+            >= max
+    })
+}
+
+// Pick a port for a dynamic service from the range granted to the client.
+// The port is bound while scanning, without holding the lock of `dynamic_services`.
+// A reconnecting service keeps its port, which is still held by its previous control channel
+async fn allocate_port(
+    client: &ServerClientConfig,
+    dynamic_services: &RwLock<DynamicServiceMap>,
+    service_digest: &ServiceDigest,
+    req: &ServiceRequest,
+) -> Result<(u16, Option<BoundSocket>), Ack> {
+    let taken: Vec<u16> = {
+        let dynamic_services = dynamic_services.read().await;
+        if let Some(s) = dynamic_services.get(service_digest) {
+            if req.port.is_none() || req.port == Some(s.port) {
+                return Ok((s.port, None));
+            }
+        }
+        if over_quota(client, &dynamic_services, service_digest) {
+            return Err(Ack::QuotaExceeded);
+        }
+        dynamic_services
+            .iter()
+            .filter(|(k, _)| *k != service_digest)
+            .map(|(_, s)| s.port)
+            .collect()
+    };
+
+    let (host, range) = host_port_range(&client.bind_addr).map_err(|_| Ack::PortUnavailable)?;
+    let candidates: Vec<u16> = match req.port {
+        Some(port) if range.contains(&port) => vec![port],
+        Some(_) => Vec::new(),
+        None => range.collect(),
+    };
+    for port in candidates.into_iter().filter(|p| !taken.contains(p)) {
+        let addr = format!("{}:{}", host, port);
+        let bound = match req.service_type {
+            ServiceType::Tcp => TcpListener::bind(&addr).await.map(BoundSocket::Tcp),
+            ServiceType::Udp => UdpSocket::bind(&addr).await.map(BoundSocket::Udp),
+        };
+        if let Ok(bound) = bound {
+            return Ok((port, Some(bound)));
+        }
+    }
+    Err(Ack::PortUnavailable)
+}
+
 async fn do_data_channel_handshake<T: 'static + Transport>(
     conn: T::Stream,
     control_channels: Arc<RwLock<ControlChannelMap<T>>>,
@@ -400,7 +842,7 @@ async fn do_data_channel_handshake<T: 'static + Transport>(
     let control_channels_guard = control_channels.read().await;
     match control_channels_guard.get2(&nonce) {
         Some(handle) => {
-            T::hint(&conn, SocketOpts::from_server_cfg(&handle.service));
+            T::hint(&conn, SocketOpts::from_server_cfg(&handle.service.borrow()));
 
             // Send the data channel to the corresponding control channel
             handle
@@ -420,20 +862,31 @@ pub struct ControlChannelHandle<T: Transport> {
     // Shutdown the control channel by dropping it
     _shutdown_tx: broadcast::Sender<bool>,
     data_ch_tx: mpsc::Sender<T::Stream>,
-    service: ServerServiceConfig,
+    data_ch_req_tx: mpsc::UnboundedSender<bool>,
+    // Visitors accepted elsewhere, e.g. by `server.mux`. Only for TCP services
+    visitor_tx: Option<VisitorSender>,
+    // Updated by hot reload, if the change doesn't need to restart the control channel
+    service: watch::Sender<ServerServiceConfig>,
 }
 
+type VisitorSender = mpsc::Sender<(TcpStream, Option<PortHeader>)>;
+
 impl<T> ControlChannelHandle<T>
 where
     T: 'static + Transport,
 {
     // Create a control channel handle, where the control channel handling task
     // and the connection pool task are created.
+    // `bound` are the sockets of `listen_addrs` bound in the handshake, which are listened at
+    // instead of binding again. If empty, the pools bind by themselves
     #[instrument(name = "handle", skip_all, fields(service = %service.name))]
     fn new(
         conn: T::Stream,
         service: ServerServiceConfig,
-        heartbeat_interval: u64,
+        negotiated: Negotiated,
+        shared: ControlChannelShared,
+        session_key: Nonce,
+        bound: Vec<BoundSocket>,
     ) -> ControlChannelHandle<T> {
         // Create a shutdown channel
         let (shutdown_tx, shutdown_rx) = broadcast::channel::<bool>(1);
@@ -444,7 +897,14 @@ where
         // Store data channel creation requests
         let (data_ch_req_tx, data_ch_req_rx) = mpsc::unbounded_channel();
 
-        // Cache some data channels for later use
+        // Store bind status of the service, which is reported to the client
+        let (status_tx, status_rx) = mpsc::unbounded_channel();
+
+        let listen_addrs = service_listen_addrs(&service.bind_addr);
+        let addr_count = listen_addrs.len() as u32;
+
+        // Cache some data channels for later use.
+        // Ports of an UDP service ask for their own data channels once they receive traffic
         let pool_size = match service.service_type {
             ServiceType::Tcp => TCP_POOL_SIZE,
             ServiceType::Udp => UDP_POOL_SIZE,
@@ -457,40 +917,73 @@ where
         }
 
         let shutdown_rx_clone = shutdown_tx.subscribe();
-        let bind_addr = service.bind_addr.clone();
+        let mut visitor_tx = None;
+        let data_ch_req_tx_clone = data_ch_req_tx.clone();
         match service.service_type {
-            ServiceType::Tcp => tokio::spawn(
-                async move {
-                    if let Err(e) = run_tcp_connection_pool::<T>(
-                        bind_addr,
-                        data_ch_rx,
-                        data_ch_req_tx,
-                        shutdown_rx_clone,
-                    )
-                    .await
-                    .with_context(|| "Failed to run TCP connection pool")
-                    {
-                        error!("{:#}", e);
-                    }
-                }
-                .instrument(Span::current()),
-            ),
-            ServiceType::Udp => tokio::spawn(
-                async move {
-                    if let Err(e) = run_udp_connection_pool::<T>(
-                        bind_addr,
-                        data_ch_rx,
-                        data_ch_req_tx,
-                        shutdown_rx_clone,
-                    )
-                    .await
-                    .with_context(|| "Failed to run TCP connection pool")
-                    {
-                        error!("{:#}", e);
+            ServiceType::Tcp => {
+                let (tx, visitor_rx) = mpsc::channel(CHAN_SIZE);
+                visitor_tx = Some(tx.clone());
+                let listeners = bound
+                    .into_iter()
+                    .filter_map(|b| match b {
+                        BoundSocket::Tcp(l) => Some(l),
+                        BoundSocket::Udp(_) => None,
+                    })
+                    .collect();
+                tokio::spawn(
+                    async move {
+                        if let Err(e) = run_tcp_connection_pool::<T>(
+                            (listen_addrs, listeners),
+                            negotiated,
+                            (tx, visitor_rx),
+                            data_ch_rx,
+                            data_ch_req_tx,
+                            status_tx,
+                            shutdown_rx_clone,
+                        )
+                        .await
+                        .with_context(|| "Failed to run TCP connection pool")
+                        {
+                            error!("{:#}", e);
+                        }
                     }
+                    .instrument(Span::current()),
+                );
+            }
+            ServiceType::Udp => {
+                // Run a pool for every port, sharing the data channels
+                let data_ch_rx = Arc::new(Mutex::new(data_ch_rx));
+                let mut sockets = bound.into_iter().filter_map(|b| match b {
+                    BoundSocket::Udp(s) => Some(s),
+                    BoundSocket::Tcp(_) => None,
+                });
+                for (bind_addr, port) in listen_addrs {
+                    let socket = sockets.next();
+                    let data_ch_rx = data_ch_rx.clone();
+                    let data_ch_req_tx = data_ch_req_tx.clone();
+                    let status_tx = status_tx.clone();
+                    let shutdown_rx = shutdown_rx_clone.resubscribe();
+                    tokio::spawn(
+                        async move {
+                            if let Err(e) = run_udp_connection_pool::<T>(
+                                bind_addr,
+                                socket,
+                                data_channel_cmd(ServiceType::Udp, port, &negotiated),
+                                data_ch_rx,
+                                data_ch_req_tx,
+                                status_tx,
+                                shutdown_rx,
+                            )
+                            .await
+                            .with_context(|| "Failed to run UDP connection pool")
+                            {
+                                error!("{:#}", e);
+                            }
+                        }
+                        .instrument(Span::current()),
+                    );
                 }
-                .instrument(Span::current()),
-            ),
+            }
         };
 
         // Create the control channel
@@ -498,7 +991,12 @@ where
             conn,
             shutdown_rx,
             data_ch_req_rx,
-            heartbeat_interval,
+            status_rx,
+            addr_count,
+            heartbeat_interval: shared.heartbeat_interval,
+            grace_period: shared.grace_period,
+            conns: current_conns(),
+            negotiated,
         };
 
         // Run the control channel
@@ -507,6 +1005,8 @@ where
                 if let Err(err) = ch.run().await {
                     error!("{:#}", err);
                 }
+                // Notify the server so the handle can be cleaned up
+                let _ = shared.closed_tx.send(session_key);
             }
             .instrument(Span::current()),
         );
@@ -514,17 +1014,50 @@ where
         ControlChannelHandle {
             _shutdown_tx: shutdown_tx,
             data_ch_tx,
-            service,
+            data_ch_req_tx: data_ch_req_tx_clone,
+            visitor_tx,
+            service: watch::channel(service).0,
         }
     }
+
+    // Apply a change that doesn't need to restart the control channel
+    fn update(&self, service: ServerServiceConfig) {
+        self.service.send_replace(service);
+    }
+
+    // Forward a visitor that is accepted elsewhere
+    fn send_visitor(&self, visitor: TcpStream) -> Result<()> {
+        let visitor_tx = self
+            .visitor_tx
+            .as_ref()
+            .ok_or_else(|| anyhow!("Service {} is not a TCP service", self.service.borrow().name))?;
+        // A visitor that is turned away doesn't need a data channel
+        visitor_tx
+            .try_send((visitor, None))
+            .map_err(|_| anyhow!("Too many visitors of service {}", self.service.borrow().name))?;
+        self.data_ch_req_tx
+            .send(true)
+            .with_context(|| "Failed to send data chan create request")
+    }
 }
 
 // Control channel, using T as the transport layer. P is TcpStream or UdpTraffic
 struct ControlChannel<T: Transport> {
-    conn: T::Stream,                               // The connection of control channel
-    shutdown_rx: broadcast::Receiver<bool>,        // Receives the shutdown signal
-    data_ch_req_rx: mpsc::UnboundedReceiver<bool>, // Receives visitor connections
-    heartbeat_interval: u64,                       // Application-layer heartbeat interval in secs
+    conn: T::Stream,                                   // The connection of control channel
+    shutdown_rx: broadcast::Receiver<bool>,            // Receives the shutdown signal
+    data_ch_req_rx: mpsc::UnboundedReceiver<bool>,     // Receives visitor connections
+    status_rx: mpsc::UnboundedReceiver<BindStatus>,    // Receives bind status of the service
+    addr_count: u32,                                   // How many addresses the service listens at
+    heartbeat_interval: watch::Receiver<u64>,          // Application-layer heartbeat interval in secs
+    grace_period: watch::Receiver<u64>,                // How long to wait for in-flight connections in secs
+    conns: Arc<Conns>,                                 // The in-flight connections of this instance
+    negotiated: Negotiated,                            // The result of protocol negotiation
+}
+
+// Reported by a connection pool when it starts or fails to listen
+struct BindStatus {
+    bind_addr: String,
+    error: Option<String>,
 }
 
 impl<T: Transport> ControlChannel<T> {
@@ -537,11 +1070,13 @@ impl<T: Transport> ControlChannel<T> {
     // Run a control channel
     #[instrument(skip_all)]
     async fn run(mut self) -> Result<()> {
-        let create_ch_cmd = bincode::serialize(&ControlChannelCmd::CreateDataChannel).unwrap();
-        let heartbeat = bincode::serialize(&ControlChannelCmd::HeartBeat).unwrap();
+        let create_ch_cmd = self.negotiated.encode(&ControlChannelCmd::CreateDataChannel)?;
+        let heartbeat = self.negotiated.encode(&ControlChannelCmd::HeartBeat)?;
 
         // Wait for data channel requests and the shutdown signal
         loop {
+            // Pick up the interval changed by hot reload
+            let heartbeat_interval = *self.heartbeat_interval.borrow();
             tokio::select! {
                 val = self.data_ch_req_rx.recv() => {
                     match val {
@@ -556,7 +1091,17 @@ impl<T: Transport> ControlChannel<T> {
                         }
                     }
                 },
-                _ = time::sleep(Duration::from_secs(self.heartbeat_interval)), if self.heartbeat_interval != 0 => {
+                Some(BindStatus { bind_addr, error }) = self.status_rx.recv() => {
+                    if self.negotiated.supports(CAP_SERVICE_STATUS) {
+                        let status = ServiceStatus { bind_addr, error, addr_count: self.addr_count };
+                        let cmd = self.negotiated.encode(&ControlChannelCmd::ServiceStatus(status))?;
+                        if let Err(e) = self.write_and_flush(&cmd).await {
+                            error!("{:#}", e);
+                            break;
+                        }
+                    }
+                },
+                _ = time::sleep(Duration::from_secs(heartbeat_interval)), if heartbeat_interval != 0 => {
                             if let Err(e) = self.write_and_flush(&heartbeat).await {
                                 error!("{:#}", e);
                                 break;
@@ -564,6 +1109,13 @@ impl<T: Transport> ControlChannel<T> {
                 }
                 // Wait for the shutdown signal
                 _ = self.shutdown_rx.recv() => {
+                    // Tell the client that no more data channels are needed
+                    if self.negotiated.supports(CAP_DRAIN) {
+                        let cmd = self.negotiated.encode(&ControlChannelCmd::Drain)?;
+                        if self.write_and_flush(&cmd).await.is_ok() {
+                            self.linger(&heartbeat).await;
+                        }
+                    }
                     break;
                 }
             }
@@ -573,102 +1125,254 @@ impl<T: Transport> ControlChannel<T> {
 
         Ok(())
     }
+
+    // Keep the control channel open until in-flight connections finish or the grace period ends,
+    // so that the client doesn't reconnect in the meantime
+    async fn linger(&mut self, heartbeat: &[u8]) {
+        let grace_period = Duration::from_secs(*self.grace_period.borrow());
+        let conns = self.conns.clone();
+        let drained = time::timeout(grace_period, conns.closed());
+        tokio::pin!(drained);
+        loop {
+            let heartbeat_interval = *self.heartbeat_interval.borrow();
+            tokio::select! {
+                _ = &mut drained => break,
+                _ = time::sleep(Duration::from_secs(heartbeat_interval)), if heartbeat_interval != 0 => {
+                    if self.write_and_flush(heartbeat).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
 }
 
-fn tcp_listen_and_send(
-    addr: String,
-    data_ch_req_tx: mpsc::UnboundedSender<bool>,
+// Accept visitors at `server.mux`, and send them to the services of the hostnames they ask for
+async fn run_mux<T: 'static + Transport>(
+    l: TcpListener,
+    default_service: Option<String>,
+    services: Arc<RwLock<HashMap<ServiceDigest, ServerServiceConfig>>>,
+    control_channels: Arc<RwLock<ControlChannelMap<T>>>,
     mut shutdown_rx: broadcast::Receiver<bool>,
-) -> mpsc::Receiver<TcpStream> {
-    let (tx, rx) = mpsc::channel(CHAN_SIZE);
-
-    tokio::spawn(async move {
-        let l = retry_notify_with_deadline(listen_backoff(),  || async {
-            Ok(TcpListener::bind(&addr).await?)
-        }, |e, duration| {
-            error!("{:#}. Retry in {:?}", e, duration);
-        }, &mut shutdown_rx).await
-        .with_context(|| "Failed to listen for the service");
-
-        let l: TcpListener = match l {
-            Ok(v) => v,
-            Err(e) => {
-                error!("{:#}", e);
-                return;
+) {
+    // Retry at least every 1s
+    let mut backoff = ExponentialBackoff {
+        max_interval: Duration::from_secs(1),
+        max_elapsed_time: None,
+        ..Default::default()
+    };
+
+    loop {
+        tokio::select! {
+            val = l.accept() => {
+                match val {
+                    Err(e) => {
+                        // Possibly a EMFILE. So sleep for a while
+                        error!("{}. Sleep for a while", e);
+                        if let Some(d) = backoff.next_backoff() {
+                            time::sleep(d).await;
+                        } else {
+                            // This branch will never be reached for current backoff policy
+                            error!("Too many retries. Aborting...");
+                            break;
+                        }
+                    }
+                    Ok((visitor, addr)) => {
+                        backoff.reset();
+                        let default_service = default_service.clone();
+                        let services = services.clone();
+                        let control_channels = control_channels.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = route_visitor(visitor, default_service, services, control_channels).await {
+                                warn!("{:#}", e);
+                            }
+                        }.instrument(info_span!("visitor", %addr)));
+                    }
+                }
+            },
+            _ = shutdown_rx.recv() => {
+                break;
             }
-        };
+        }
+    }
 
-        info!("Listening at {}", &addr);
+    info!("Mux shutdown");
+}
 
-        // Retry at least every 1s
-        let mut backoff = ExponentialBackoff {
-            max_interval: Duration::from_secs(1),
-            max_elapsed_time: None,
-            ..Default::default()
-        };
+async fn route_visitor<T: 'static + Transport>(
+    visitor: TcpStream,
+    default_service: Option<String>,
+    services: Arc<RwLock<HashMap<ServiceDigest, ServerServiceConfig>>>,
+    control_channels: Arc<RwLock<ControlChannelMap<T>>>,
+) -> Result<()> {
+    let host = vhost::peek_hostname(&visitor, Duration::from_secs(HANDSHAKE_TIMEOUT))
+        .await
+        .with_context(|| "Failed to read the hostname")?;
+    let host_str = host.as_deref().unwrap_or("(none)");
+
+    let service_name = {
+        let services = services.read().await;
+        match host.as_deref().and_then(|h| vhost::find_service(&services, h)) {
+            Some(s) => s.name.clone(),
+            None => default_service
+                .ok_or_else(|| anyhow!("No service for hostname {}", host_str))?,
+        }
+    };
+    debug!("New visitor for {} to service {}", host_str, service_name);
 
-        // Wait for visitors and the shutdown signal
-        loop {
-            tokio::select! {
-                val = l.accept() => {
-                    match val {
-                        Err(e) => {
-                            // `l` is a TCP listener so this must be a IO error
-                            // Possibly a EMFILE. So sleep for a while
-                            error!("{}. Sleep for a while", e);
-                            if let Some(d) = backoff.next_backoff() {
-                                time::sleep(d).await;
-                            } else {
-                                // This branch will never be reached for current backoff policy
-                                error!("Too many retries. Aborting...");
-                                break;
-                            }
-                        }
-                        Ok((incoming, addr)) => {
-                            // For every visitor, request to create a data channel
-                            if data_ch_req_tx.send(true).with_context(|| "Failed to send data chan create request").is_err() {
-                                // An error indicates the control channel is broken
-                                // So break the loop
-                                break;
+    match control_channels
+        .read()
+        .await
+        .get1(&protocol::digest(service_name.as_bytes()))
+    {
+        Some(handle) => handle.send_visitor(visitor),
+        None => bail!("Service {} is not connected", service_name),
+    }
+}
+
+// The addresses that a service listens at. For a service with a port range,
+// every address comes with the `PortHeader` sent along with the data channel command
+fn service_listen_addrs(bind_addr: &str) -> Vec<(String, Option<PortHeader>)> {
+    // The service is only reached by `server.mux`
+    if bind_addr.is_empty() {
+        return Vec::new();
+    }
+    match host_port_range(bind_addr) {
+        Ok((host, ports)) if ports.start() != ports.end() => {
+            let start = *ports.start();
+            ports
+                .map(|port| {
+                    let hdr = PortHeader {
+                        port,
+                        offset: port - start,
+                    };
+                    (format!("{}:{}", host, port), Some(hdr))
+                })
+                .collect()
+        }
+        _ => vec![(bind_addr.to_string(), None)],
+    }
+}
+
+// `listener` is already bound at the first address, if any
+// `listeners` are bound at `addrs` in advance, if not empty
+fn tcp_listen_and_send(
+    addrs: Vec<(String, Option<PortHeader>)>,
+    listeners: Vec<TcpListener>,
+    tx: VisitorSender,
+    data_ch_req_tx: mpsc::UnboundedSender<bool>,
+    status_tx: mpsc::UnboundedSender<BindStatus>,
+    shutdown_rx: broadcast::Receiver<bool>,
+) {
+    let mut listeners = listeners.into_iter();
+    for (addr, port) in addrs {
+        let tx = tx.clone();
+        let data_ch_req_tx = data_ch_req_tx.clone();
+        let status_tx = status_tx.clone();
+        let mut shutdown_rx = shutdown_rx.resubscribe();
+        let listener = listeners.next();
+        tokio::spawn(async move {
+            let l = match listener {
+                Some(l) => Ok(l),
+                None => retry_notify_with_deadline(listen_backoff(),  || async {
+                    Ok(TcpListener::bind(&addr).await?)
+                }, |e, duration| {
+                    error!("{:#}. Retry in {:?}", e, duration);
+                    let _ = status_tx.send(BindStatus { bind_addr: addr.clone(), error: Some(format!("{:#}", e)) });
+                }, &mut shutdown_rx).await
+                .with_context(|| "Failed to listen for the service"),
+            };
+
+            let l: TcpListener = match l {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("{:#}", e);
+                    return;
+                }
+            };
+
+            info!("Listening at {}", &addr);
+            let _ = status_tx.send(BindStatus { bind_addr: addr.clone(), error: None });
+
+            // Retry at least every 1s
+            let mut backoff = ExponentialBackoff {
+                max_interval: Duration::from_secs(1),
+                max_elapsed_time: None,
+                ..Default::default()
+            };
+
+            // Wait for visitors and the shutdown signal
+            loop {
+                tokio::select! {
+                    val = l.accept() => {
+                        match val {
+                            Err(e) => {
+                                // `l` is a TCP listener so this must be a IO error
+                                // Possibly a EMFILE. So sleep for a while
+                                error!("{}. Sleep for a while", e);
+                                if let Some(d) = backoff.next_backoff() {
+                                    time::sleep(d).await;
+                                } else {
+                                    // This branch will never be reached for current backoff policy
+                                    error!("Too many retries. Aborting...");
+                                    break;
+                                }
                             }
+                            Ok((incoming, addr)) => {
+                                // For every visitor, request to create a data channel
+                                if data_ch_req_tx.send(true).with_context(|| "Failed to send data chan create request").is_err() {
+                                    // An error indicates the control channel is broken
+                                    // So break the loop
+                                    break;
+                                }
 
-                            backoff.reset();
+                                backoff.reset();
 
-                            debug!("New visitor from {}", addr);
+                                debug!("New visitor from {}", addr);
 
-                            // Send the visitor to the connection pool
-                            let _ = tx.send(incoming).await;
+                                // Send the visitor to the connection pool
+                                let _ = tx.send((incoming, port)).await;
+                            }
                         }
+                    },
+                    _ = shutdown_rx.recv() => {
+                        break;
                     }
-                },
-                _ = shutdown_rx.recv() => {
-                    break;
                 }
             }
-        }
 
-        info!("TCPListener shutdown");
-    }.instrument(Span::current()));
-
-    rx
+            info!("TCPListener shutdown");
+        }.instrument(Span::current()));
+    }
 }
 
 #[instrument(skip_all)]
 async fn run_tcp_connection_pool<T: Transport>(
-    bind_addr: String,
+    (listen_addrs, listeners): (Vec<(String, Option<PortHeader>)>, Vec<TcpListener>),
+    negotiated: Negotiated,
+    (visitor_tx, mut visitor_rx): (VisitorSender, mpsc::Receiver<(TcpStream, Option<PortHeader>)>),
     mut data_ch_rx: mpsc::Receiver<T::Stream>,
     data_ch_req_tx: mpsc::UnboundedSender<bool>,
+    status_tx: mpsc::UnboundedSender<BindStatus>,
     shutdown_rx: broadcast::Receiver<bool>,
 ) -> Result<()> {
-    let mut visitor_rx = tcp_listen_and_send(bind_addr, data_ch_req_tx.clone(), shutdown_rx);
-    let cmd = bincode::serialize(&DataChannelCmd::StartForwardTcp).unwrap();
+    // Visitors also come from the handle, so the pool runs until it's dropped
+    tcp_listen_and_send(
+        listen_addrs,
+        listeners,
+        visitor_tx,
+        data_ch_req_tx.clone(),
+        status_tx,
+        shutdown_rx,
+    );
 
-    'pool: while let Some(mut visitor) = visitor_rx.recv().await {
+    'pool: while let Some((mut visitor, port)) = visitor_rx.recv().await {
+        let cmd = data_channel_cmd(ServiceType::Tcp, port, &negotiated);
         loop {
             if let Some(mut ch) = data_ch_rx.recv().await {
                 if write_and_flush(&mut ch, &cmd).await.is_ok() {
                     tokio::spawn(async move {
-                        let _ = copy_bidirectional(&mut ch, &mut visitor).await;
+                        let _ = forward_conn(copy_bidirectional(&mut ch, &mut visitor)).await;
                     });
                     break;
                 } else {
@@ -687,37 +1391,86 @@ async fn run_tcp_connection_pool<T: Transport>(
     Ok(())
 }
 
+// Serialize the command that starts forwarding on a data channel
+fn data_channel_cmd(
+    service_type: ServiceType,
+    port: Option<PortHeader>,
+    negotiated: &Negotiated,
+) -> Vec<u8> {
+    match (service_type, port) {
+        (ServiceType::Tcp, None) => negotiated.encode(&DataChannelCmd::StartForwardTcp).unwrap(),
+        (ServiceType::Udp, None) => negotiated.encode(&DataChannelCmd::StartForwardUdp).unwrap(),
+        (service_type, Some(hdr)) => {
+            let cmd = match service_type {
+                ServiceType::Tcp => DataChannelCmd::StartForwardTcpInRange,
+                ServiceType::Udp => DataChannelCmd::StartForwardUdpInRange,
+            };
+            // Port ranges are only used since V2
+            let mut v = encode_framed(&cmd).unwrap();
+            v.append(&mut encode_framed(&hdr).unwrap());
+            v
+        }
+    }
+}
+
 #[instrument(skip_all)]
 async fn run_udp_connection_pool<T: Transport>(
     bind_addr: String,
-    mut data_ch_rx: mpsc::Receiver<T::Stream>,
-    _data_ch_req_tx: mpsc::UnboundedSender<bool>,
+    socket: Option<UdpSocket>,
+    cmd: Vec<u8>, // Sent on the data channel to start forwarding
+    data_ch_rx: Arc<Mutex<mpsc::Receiver<T::Stream>>>,
+    data_ch_req_tx: mpsc::UnboundedSender<bool>,
+    status_tx: mpsc::UnboundedSender<BindStatus>,
     mut shutdown_rx: broadcast::Receiver<bool>,
 ) -> Result<()> {
     // TODO: Load balance
 
-    let l = retry_notify_with_deadline(
-        listen_backoff(),
-        || async { Ok(UdpSocket::bind(&bind_addr).await?) },
-        |e, duration| {
-            warn!("{:#}. Retry in {:?}", e, duration);
-        },
-        &mut shutdown_rx,
-    )
-    .await
-    .with_context(|| "Failed to listen for the service")?;
+    let l = match socket {
+        Some(s) => s,
+        None => retry_notify_with_deadline(
+            listen_backoff(),
+            || async { Ok(UdpSocket::bind(&bind_addr).await?) },
+            |e, duration| {
+                warn!("{:#}. Retry in {:?}", e, duration);
+                let _ = status_tx.send(BindStatus {
+                    bind_addr: bind_addr.clone(),
+                    error: Some(format!("{:#}", e)),
+                });
+            },
+            &mut shutdown_rx,
+        )
+        .await
+        .with_context(|| "Failed to listen for the service")?,
+    };
 
     info!("Listening at {}", &bind_addr);
-
-    let cmd = bincode::serialize(&DataChannelCmd::StartForwardUdp).unwrap();
-
-    // Receive one data channel
-    let mut conn = data_ch_rx
-        .recv()
-        .await
-        .ok_or_else(|| anyhow!("No available data channels"))?;
+    let _ = status_tx.send(BindStatus {
+        bind_addr: bind_addr.clone(),
+        error: None,
+    });
+
+    // Take a data channel only when the port receives traffic,
+    // so that the unused ports of a large range don't open tunnels
+    tokio::select! {
+        val = l.readable() => val?,
+        _ = shutdown_rx.recv() => return Ok(()),
+    }
+    data_ch_req_tx
+        .send(true)
+        .with_context(|| "Failed to send data chan create request")?;
+    let mut conn = tokio::select! {
+        val = async { data_ch_rx.lock().await.recv().await } => {
+            val.ok_or_else(|| anyhow!("No available data channels"))?
+        },
+        _ = shutdown_rx.recv() => return Ok(()),
+    };
     write_and_flush(&mut conn, &cmd).await?;
 
+    // Set on shutdown. Then the traffic is forwarded until it's idle,
+    // and waited for as an in-flight connection of this instance
+    let conns = current_conns();
+    let mut draining: Option<ConnGuard> = None;
+
     let mut buf = [0u8; UDP_BUFFER_SIZE];
     loop {
         tokio::select! {
@@ -742,7 +1495,16 @@ async fn run_udp_connection_pool<T: Transport>(
                 l.send_to(&t.data, t.from).await?;
             }
 
-            _ = shutdown_rx.recv() => {
+            _ = shutdown_rx.recv(), if draining.is_none() => {
+                draining = Some(conns.track());
+            }
+
+            _ = time::sleep(Duration::from_secs(UDP_TIMEOUT)), if draining.is_some() => {
+                break;
+            }
+
+            // The grace period has ended
+            _ = async { draining.as_mut().unwrap().cancelled().await }, if draining.is_some() => {
                 break;
             }
         }
@@ -806,3 +1568,257 @@ fn save_uploaded_file(data: &[u8], filename: &str) -> Result<()> {
     info!("Wrote {} bytes to {}", data.len(), file_path);
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind_service() -> Result<()> {
+        let port = TcpListener::bind("127.0.0.1:0").await?.local_addr()?.port();
+        let service = ServerServiceConfig {
+            name: String::from("foo"),
+            bind_addr: format!("127.0.0.1:{}", port),
+            ..Default::default()
+        };
+        // The sockets are kept, so the port can't be taken before the pool listens
+        let bound = bind_service(&service).await?;
+        match bound.as_slice() {
+            [BoundSocket::Tcp(l)] => assert_eq!(l.local_addr()?.port(), port),
+            _ => panic!("Expect a TCP listener"),
+        }
+        assert!(bind_service(&service).await.is_err());
+        drop(bound);
+
+        let udp = ServerServiceConfig {
+            service_type: ServiceType::Udp,
+            ..service
+        };
+        assert!(matches!(bind_service(&udp).await?.as_slice(), [BoundSocket::Udp(_)]));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_allocate_port() -> Result<()> {
+        // The first port of the range is taken by someone else
+        let taken = TcpListener::bind("127.0.0.1:0").await?;
+        let start = taken.local_addr()?.port();
+        let client = ServerClientConfig {
+            name: String::from("alice"),
+            bind_addr: format!("127.0.0.1:{}-{}", start, start + 2),
+            max_services: Some(2),
+            ..Default::default()
+        };
+        let dynamic_services = RwLock::new(DynamicServiceMap::new());
+        let req = |name: &str, port| ServiceRequest {
+            name: name.to_string(),
+            service_type: ServiceType::Tcp,
+            port,
+        };
+        let allocate = |name: &str, port| {
+            let req = req(name, port);
+            let digest = protocol::digest(format!("alice/{}", name).as_bytes());
+            let (client, dynamic_services) = (&client, &dynamic_services);
+            async move {
+                let ret = allocate_port(client, dynamic_services, &digest, &req).await;
+                if let Ok((port, _)) = &ret {
+                    dynamic_services.write().await.insert(
+                        digest,
+                        DynamicService {
+                            client: client.name.clone(),
+                            port: *port,
+                        },
+                    );
+                }
+                ret
+            }
+        };
+
+        let (a, bound_a) = allocate("a", None).await?;
+        assert_eq!(a, start + 1);
+        assert!(matches!(bound_a, Some(BoundSocket::Tcp(_))));
+        let (b, _bound_b) = allocate("b", None).await?;
+        assert_eq!(b, start + 2);
+        assert!(matches!(allocate("c", None).await, Err(Ack::QuotaExceeded)));
+
+        // A reconnecting service keeps its port, which its previous pool still listens at
+        let (port, bound) = allocate("a", None).await?;
+        assert_eq!((port, bound.is_none()), (a, true));
+        let (port, _) = allocate("a", Some(a)).await?;
+        assert_eq!(port, a);
+
+        // Ports out of the range, or held by others
+        dynamic_services.write().await.clear();
+        drop(bound_a);
+        assert!(matches!(allocate("d", Some(start + 10)).await, Err(Ack::PortUnavailable)));
+        assert!(matches!(allocate("e", Some(start)).await, Err(Ack::PortUnavailable)));
+        let (port, _) = allocate("f", Some(a)).await?;
+        assert_eq!(port, a);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_update_in_place() -> Result<()> {
+        use crate::protocol::Capabilities;
+        use crate::transport::TcpConn;
+        use std::os::fd::{AsRawFd, BorrowedFd};
+
+        let s = r#"
+[server]
+bind_addr = "127.0.0.1:0"
+default_token = "foo"
+[server.transport]
+type = "tcp"
+[server.services.foo]
+bind_addr = "127.0.0.1:0"
+nodelay = false
+"#;
+        let config = Config::from_str(s, Path::new("config.toml"))?.server.unwrap();
+        let mut service = config.services["foo"].clone();
+        let mut server = Server::<TcpTransport>::from(config).await?;
+        let pair = || async {
+            let l = TcpListener::bind("127.0.0.1:0").await?;
+            let peer = TcpStream::connect(l.local_addr()?).await?;
+            Ok::<_, anyhow::Error>((l.accept().await?.0, peer))
+        };
+
+        // The service has a running control channel
+        let (conn, _peer) = pair().await?;
+        let digest = protocol::digest(b"foo");
+        let session_key = [1u8; HASH_WIDTH_IN_BYTES];
+        let handle = ControlChannelHandle::new(
+            TcpConn::Direct(conn),
+            service.clone(),
+            Capabilities::current().negotiate(&Capabilities::current())?,
+            server.shared.clone(),
+            session_key,
+            Vec::new(),
+        );
+        let _ = server
+            .control_channels
+            .write()
+            .await
+            .insert(digest, session_key, handle);
+
+        service.nodelay = Some(true);
+        server
+            .handle_hot_reload(ConfigChange::ServerChange(ServerServiceChange::Update(
+                service,
+            )))
+            .await;
+        assert!(server.control_channels.read().await.get1(&digest).is_some());
+
+        // A new data channel picks up the new `nodelay`
+        let (data, _peer) = pair().await?;
+        assert!(!data.nodelay()?);
+        let fd = data.as_raw_fd();
+        do_data_channel_handshake::<TcpTransport>(
+            TcpConn::Direct(data),
+            server.control_channels.clone(),
+            session_key,
+        )
+        .await?;
+        // The data channel is kept by the pool until a visitor comes
+        let data = unsafe { BorrowedFd::borrow_raw(fd) };
+        assert!(socket2::SockRef::from(&data).nodelay()?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drain_in_flight_conn() -> Result<()> {
+        use crate::helper::CONNS_TEST_LOCK;
+        use tokio::io::AsyncWriteExt;
+        let _lock = CONNS_TEST_LOCK.lock().await;
+
+        // The local service greets, then echoes
+        let echo = TcpListener::bind("127.0.0.1:0").await?;
+        let echo_addr = echo.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut s, _)) = echo.accept().await {
+                tokio::spawn(async move {
+                    let _ = s.write_all(b"hi").await;
+                    let (mut rd, mut wr) = s.split();
+                    let _ = io::copy(&mut rd, &mut wr).await;
+                });
+            }
+        });
+        let greeted = |mut visitor: TcpStream| async move {
+            let mut buf = Vec::new();
+            while !buf.ends_with(b"hi") {
+                let mut b = [0u8; 64];
+                let n = time::timeout(Duration::from_secs(1), visitor.read(&mut b)).await??;
+                anyhow::ensure!(n != 0, "Closed before the greeting");
+                buf.extend_from_slice(&b[..n]);
+            }
+            Ok::<_, anyhow::Error>(visitor)
+        };
+        let echo_once = |mut visitor: TcpStream| async move {
+            visitor.write_all(b"ping").await?;
+            let mut buf = [0u8; 4];
+            time::timeout(Duration::from_secs(1), visitor.read_exact(&mut buf)).await??;
+            anyhow::ensure!(&buf == b"ping", "Unexpected reply {:?}", buf);
+            Ok::<_, anyhow::Error>(visitor)
+        };
+
+        let port = || std::net::TcpListener::bind("127.0.0.1:0")?.local_addr();
+        let (ctl, bind) = (port()?, port()?);
+        let s = format!(
+            r#"
+[server]
+bind_addr = "{ctl}"
+default_token = "foo"
+grace_period = 10
+[server.transport]
+type = "tcp"
+[server.services.echo]
+bind_addr = "{bind}"
+
+[client]
+remote_addr = "{ctl}"
+default_token = "foo"
+[client.transport]
+type = "tcp"
+[client.services.echo]
+local_addr = "{echo_addr}"
+"#
+        );
+        let config = Config::from_str(&s, Path::new("config.toml"))?;
+
+        let (shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (_update_tx, update_rx) = mpsc::channel(1);
+        let server = tokio::spawn(run_server(config.clone(), shutdown_rx, update_rx));
+        let (_client_shutdown_tx, shutdown_rx) = broadcast::channel(1);
+        let (_client_update_tx, update_rx) = mpsc::channel(1);
+        tokio::spawn(crate::client::run_client(config, shutdown_rx, update_rx));
+
+        // Wait for the tunnel
+        let mut visitor = None;
+        for _ in 0..50 {
+            let ready = async { echo_once(greeted(TcpStream::connect(bind).await?).await?).await };
+            if let Ok(v) = ready.await {
+                visitor = Some(v);
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+        }
+        let visitor = visitor.ok_or_else(|| anyhow!("The tunnel is not ready"))?;
+
+        // The server stops accepting visitors
+        let _ = shutdown_tx.send(true);
+        server.await??;
+        assert!(TcpStream::connect(bind).await.is_err());
+
+        // But the in-flight connection is still forwarded, and waited for
+        let conns = current_conns();
+        let drained = tokio::spawn(async move { conns.drain(Duration::from_secs(10)).await });
+        let visitor = echo_once(visitor).await?;
+        time::sleep(Duration::from_millis(500)).await;
+        let visitor = echo_once(visitor).await?;
+        assert!(!drained.is_finished());
+
+        drop(visitor);
+        time::timeout(Duration::from_secs(5), drained).await??;
+        Ok(())
+    }
+}
@@ -0,0 +1,440 @@
+use std::io::{Error, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use super::{AddrMaybeCached, SocketOpts, TcpTransport, TlsTransport, Transport};
+use crate::config::TransportConfig;
+use anyhow::{anyhow, bail};
+use async_trait::async_trait;
+use bytes::Bytes;
+use h2::{Reason, RecvStream, SendStream};
+use http::{Method, Request, Response, StatusCode};
+use socket2::{SockRef, Socket};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tracing::debug;
+
+use super::tls::get_tcpstream;
+
+// The default window of HTTP/2 is 64KiB, which limits the throughput of a tunnel
+const WINDOW_SIZE: u32 = 1024 * 1024;
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// A long-lived POST request, whose bodies in both directions carry the tunnel
+#[derive(Debug)]
+pub struct Http2Tunnel {
+    send: SendStream<Bytes>,
+    recv: RecvStream,
+    // Received but not yet read
+    buf: Bytes,
+    // The connection is driven by another task, so socket options go to a duplicate of the socket
+    socket: Socket,
+}
+
+fn h2_to_io_error(e: h2::Error) -> Error {
+    if e.is_io() {
+        e.into_io().unwrap()
+    } else {
+        Error::other(e)
+    }
+}
+
+impl AsyncRead for Http2Tunnel {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        while this.buf.is_empty() {
+            match ready!(this.recv.poll_data(cx)) {
+                None => return Poll::Ready(Ok(())),
+                Some(Ok(data)) => {
+                    let _ = this.recv.flow_control().release_capacity(data.len());
+                    this.buf = data;
+                }
+                Some(Err(e)) if e.reason() == Some(Reason::NO_ERROR) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Some(Err(e)) => return Poll::Ready(Err(h2_to_io_error(e))),
+            }
+        }
+        let n = this.buf.len().min(buf.remaining());
+        buf.put_slice(&this.buf.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Http2Tunnel {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, Error>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let this = self.get_mut();
+        this.send.reserve_capacity(buf.len());
+        loop {
+            match ready!(this.send.poll_capacity(cx)) {
+                None => return Poll::Ready(Err(ErrorKind::BrokenPipe.into())),
+                Some(Err(e)) => return Poll::Ready(Err(h2_to_io_error(e))),
+                Some(Ok(0)) => continue,
+                Some(Ok(n)) => {
+                    let n = n.min(buf.len());
+                    this.send
+                        .send_data(Bytes::copy_from_slice(&buf[..n]), false)
+                        .map_err(h2_to_io_error)?;
+                    return Poll::Ready(Ok(n));
+                }
+            }
+        }
+    }
+
+    // Data is flushed by the task that drives the connection
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(
+            self.get_mut()
+                .send
+                .send_data(Bytes::new(), true)
+                .map_err(h2_to_io_error),
+        )
+    }
+}
+
+#[derive(Debug)]
+enum SubTransport {
+    Secure(Box<TlsTransport>),
+    Insecure(TcpTransport),
+}
+
+#[derive(Debug)]
+pub struct Http2Transport {
+    sub: SubTransport,
+    path: Option<String>,
+    host: Option<String>,
+}
+
+impl Http2Transport {
+    // Returns the stream for HTTP/2, and a duplicate of the socket under it
+    async fn handshake_sub(&self, conn: TcpStream) -> anyhow::Result<(Box<dyn Io>, Socket)> {
+        Ok(match &self.sub {
+            SubTransport::Insecure(t) => {
+                let conn = t.handshake(conn).await?;
+                let socket = SockRef::from(conn.get_tcpstream()).try_clone()?;
+                (Box::new(conn), socket)
+            }
+            SubTransport::Secure(t) => {
+                let conn = t.handshake(conn).await?;
+                let socket = SockRef::from(get_tcpstream(&conn)).try_clone()?;
+                (Box::new(conn), socket)
+            }
+        })
+    }
+
+    async fn connect_sub(&self, addr: &AddrMaybeCached) -> anyhow::Result<(Box<dyn Io>, Socket)> {
+        Ok(match &self.sub {
+            SubTransport::Insecure(t) => {
+                let conn = t.connect(addr).await?;
+                let socket = SockRef::from(conn.get_tcpstream()).try_clone()?;
+                (Box::new(conn), socket)
+            }
+            SubTransport::Secure(t) => {
+                let conn = t.connect(addr).await?;
+                let socket = SockRef::from(get_tcpstream(&conn)).try_clone()?;
+                (Box::new(conn), socket)
+            }
+        })
+    }
+}
+
+// Proxies and load balancers that terminate TLS tell HTTP/2 by ALPN, so it's "h2" by default.
+// Except for the server of native-tls, which can't set ALPN
+fn with_h2_alpn(config: &TransportConfig) -> anyhow::Result<TransportConfig> {
+    let mut config = config.clone();
+    if let Some(tls) = config.tls.as_mut() {
+        if tls.alpn.is_empty() {
+            if cfg!(feature = "rustls") || tls.pkcs12.is_none() {
+                tls.alpn.push("h2".to_string());
+            }
+        } else if !tls.alpn.iter().any(|p| p == "h2") {
+            bail!("`alpn` of TLS must contain \"h2\" for HTTP/2");
+        }
+    }
+    Ok(config)
+}
+
+#[async_trait]
+impl Transport for Http2Transport {
+    type Acceptor = TcpListener;
+    type RawStream = TcpStream;
+    type Stream = Http2Tunnel;
+
+    fn new(config: &TransportConfig) -> anyhow::Result<Self> {
+        let h2config = config
+            .http2
+            .as_ref()
+            .ok_or_else(|| anyhow!("Missing HTTP/2 config"))?;
+
+        if let Some(path) = &h2config.path {
+            if !path.starts_with('/') {
+                bail!("`path` of HTTP/2 must start with '/'");
+            }
+        }
+
+        let sub = match h2config.tls {
+            true => SubTransport::Secure(Box::new(TlsTransport::new(&with_h2_alpn(config)?)?)),
+            false => SubTransport::Insecure(TcpTransport::new(config)?),
+        };
+        Ok(Http2Transport {
+            sub,
+            path: h2config.path.clone(),
+            host: h2config.host.clone(),
+        })
+    }
+
+    fn hint(conn: &Self::Stream, opt: SocketOpts) {
+        opt.apply_to_socket(&conn.socket)
+    }
+
+    async fn bind<A: ToSocketAddrs + Send + Sync>(
+        &self,
+        addr: A,
+    ) -> anyhow::Result<Self::Acceptor> {
+        TcpListener::bind(addr).await.map_err(Into::into)
+    }
+
+    async fn accept(&self, a: &Self::Acceptor) -> anyhow::Result<(Self::RawStream, SocketAddr)> {
+        let (s, addr) = match &self.sub {
+            SubTransport::Insecure(t) => t.accept(a).await?,
+            SubTransport::Secure(t) => t.accept(a).await?,
+        };
+        Ok((s, addr))
+    }
+
+    async fn handshake(&self, conn: Self::RawStream) -> anyhow::Result<Self::Stream> {
+        let (io, socket) = self.handshake_sub(conn).await?;
+        let mut conn = h2::server::Builder::new()
+            .initial_window_size(WINDOW_SIZE)
+            .initial_connection_window_size(WINDOW_SIZE)
+            .handshake(io)
+            .await?;
+
+        let (req, mut respond) = conn
+            .accept()
+            .await
+            .ok_or_else(|| anyhow!("Connection closed before the request"))??;
+
+        // Drive the connection. It carries only one tunnel, so more requests are refused
+        tokio::spawn(async move {
+            while let Some(req) = conn.accept().await {
+                match req {
+                    Ok((_, mut respond)) => respond.send_reset(Reason::REFUSED_STREAM),
+                    Err(e) => {
+                        debug!("HTTP/2 connection error: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        let status = if req.method() != Method::POST {
+            StatusCode::METHOD_NOT_ALLOWED
+        } else if self.path.as_ref().is_some_and(|p| req.uri().path() != p) {
+            StatusCode::NOT_FOUND
+        } else {
+            StatusCode::OK
+        };
+        if status != StatusCode::OK {
+            respond.send_response(Response::builder().status(status).body(())?, true)?;
+            bail!("Rejected HTTP/2 request {} {}", req.method(), req.uri());
+        }
+
+        let send = respond.send_response(Response::new(()), false)?;
+        Ok(Http2Tunnel {
+            send,
+            recv: req.into_body(),
+            buf: Bytes::new(),
+            socket,
+        })
+    }
+
+    async fn connect(&self, addr: &AddrMaybeCached) -> anyhow::Result<Self::Stream> {
+        let (io, socket) = self.connect_sub(addr).await?;
+        let (client, conn) = h2::client::Builder::new()
+            .initial_window_size(WINDOW_SIZE)
+            .initial_connection_window_size(WINDOW_SIZE)
+            .handshake(io)
+            .await?;
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                debug!("HTTP/2 connection error: {}", e);
+            }
+        });
+
+        let scheme = match self.sub {
+            SubTransport::Secure(_) => "https",
+            SubTransport::Insecure(_) => "http",
+        };
+        let uri = format!(
+            "{}://{}{}",
+            scheme,
+            self.host.as_deref().unwrap_or(addr.addr.as_str()),
+            self.path.as_deref().unwrap_or("/")
+        );
+        let req = Request::builder().method(Method::POST).uri(uri).body(())?;
+
+        let (resp, send) = client.ready().await?.send_request(req, false)?;
+        let resp = resp.await?;
+        if resp.status() != StatusCode::OK {
+            bail!("The server responds with {}", resp.status());
+        }
+        Ok(Http2Tunnel {
+            send,
+            recv: resp.into_body(),
+            buf: Bytes::new(),
+            socket,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{Http2Config, TlsConfig, TransportType};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn http2_config(tls: Option<TlsConfig>) -> TransportConfig {
+        TransportConfig {
+            transport_type: TransportType::Http2,
+            http2: Some(Http2Config {
+                tls: tls.is_some(),
+                path: None,
+                host: None,
+            }),
+            tls,
+            ..Default::default()
+        }
+    }
+
+    // Send through a tunnel from `client` to `server`, which echoes it back
+    async fn echo(server: &Http2Transport, client: &Http2Transport) -> anyhow::Result<()> {
+        let listener = server.bind("127.0.0.1:0").await?;
+        let addr = AddrMaybeCached::new(&format!("localhost:{}", listener.local_addr()?.port()));
+
+        let accept = async {
+            let (conn, _) = server.accept(&listener).await?;
+            let mut conn = server.handshake(conn).await?;
+            let mut buf = [0u8; 4];
+            conn.read_exact(&mut buf).await?;
+            conn.write_all(&buf).await?;
+            conn.flush().await?;
+            Ok::<_, anyhow::Error>(conn)
+        };
+        let connect = async {
+            let mut conn = client.connect(&addr).await?;
+            conn.write_all(b"ping").await?;
+            conn.flush().await?;
+            let mut buf = [0u8; 4];
+            conn.read_exact(&mut buf).await?;
+            Ok::<_, anyhow::Error>(buf)
+        };
+        let (accepted, buf) = tokio::join!(accept, connect);
+        accepted?;
+        assert_eq!(&buf?, b"ping");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_echo() -> anyhow::Result<()> {
+        let t = Http2Transport::new(&http2_config(None))?;
+        echo(&t, &t).await
+    }
+
+    #[cfg(feature = "cert-gen")]
+    #[tokio::test]
+    async fn test_echo_tls() -> anyhow::Result<()> {
+        use crate::keygen::{gen_cert, with_ext};
+
+        let dir = std::env::temp_dir().join(format!("rathole-http2-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let out = dir.join("localhost");
+        gen_cert("localhost", Some(&out))?;
+
+        let tls = TlsConfig {
+            hostname: Some("localhost".to_string()),
+            trusted_root: None,
+            pkcs12: None,
+            pkcs12_password: None,
+            pkcs12_password_file: None,
+            verify_hostname: true,
+            pinned_spki_sha256: Vec::new(),
+            pinned_cert_sha256: Vec::new(),
+            min_version: None,
+            max_version: None,
+            cipher_suites: Vec::new(),
+            alpn: Vec::new(),
+        };
+        let password = std::fs::read_to_string(with_ext(&out, "pass"))?;
+        let server = TlsConfig {
+            pkcs12: Some(with_ext(&out, "p12").to_string_lossy().into_owned()),
+            pkcs12_password: Some(password.trim().into()),
+            ..tls.clone()
+        };
+        let client = TlsConfig {
+            trusted_root: Some(with_ext(&out, "crt").to_string_lossy().into_owned()),
+            ..tls
+        };
+
+        // ALPN is "h2" by default
+        let config = with_h2_alpn(&http2_config(Some(client.clone())))?;
+        assert_eq!(config.tls.unwrap().alpn, ["h2"]);
+        echo(
+            &Http2Transport::new(&http2_config(Some(server)))?,
+            &Http2Transport::new(&http2_config(Some(client.clone())))?,
+        )
+        .await?;
+
+        // And must be offered if configured
+        let client = TlsConfig {
+            alpn: vec!["http/1.1".to_string()],
+            ..client
+        };
+        assert!(Http2Transport::new(&http2_config(Some(client))).is_err());
+
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() -> anyhow::Result<()> {
+        let t = Http2Transport::new(&http2_config(None))?;
+        let listener = t.bind("127.0.0.1:0").await?;
+        let addr = AddrMaybeCached::new(&listener.local_addr()?.to_string());
+
+        let accept = async {
+            let (conn, _) = t.accept(&listener).await?;
+            t.handshake(conn).await
+        };
+        let (server, client) = tokio::join!(accept, t.connect(&addr));
+        let (mut server, mut client) = (server?, client?);
+
+        client.write_all(b"hi").await?;
+        client.shutdown().await?;
+        let mut buf = Vec::new();
+        server.read_to_end(&mut buf).await?;
+        assert_eq!(buf, b"hi");
+
+        // The end of the stream is already sent
+        assert!(client.shutdown().await.is_err());
+        Ok(())
+    }
+}
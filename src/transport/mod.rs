@@ -1,12 +1,16 @@
-use crate::config::{ClientServiceConfig, ServerServiceConfig, TcpConfig, TransportConfig};
-use crate::helper::{to_socket_addr, try_set_tcp_keepalive};
-use anyhow::{Context, Result};
+use crate::config::{
+    ClientServiceConfig, ServerServiceConfig, TcpConfig, TransportConfig, TransportType,
+};
+use crate::helper::{try_bind_device, try_set_fwmark, try_set_tcp_keepalive};
+use socket2::{SockRef, Socket};
+use anyhow::{bail, Context, Result};
 use async_trait::async_trait;
 use std::fmt::{Debug, Display};
-use std::net::SocketAddr;
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::net::{lookup_host, TcpSocket, TcpStream, ToSocketAddrs};
 use tracing::{error, trace};
 
 pub const DEFAULT_NODELAY: bool = true;
@@ -14,40 +18,77 @@ pub const DEFAULT_NODELAY: bool = true;
 pub const DEFAULT_KEEPALIVE_SECS: u64 = 20;
 pub const DEFAULT_KEEPALIVE_INTERVAL: u64 = 8;
 
+pub const DEFAULT_DNS_CACHE_SECS: u64 = 60;
+
+// The addresses resolved, and when
+type ResolvedAddrs = Option<(Instant, Vec<SocketAddr>)>;
+
+/// The address of the server, whose resolved addresses are cached for at most `max_age`.
+/// Clones share the cache, so that data channels reuse what the control channel resolved
 #[derive(Clone)]
 pub struct AddrMaybeCached {
     pub addr: String,
-    pub socket_addr: Option<SocketAddr>,
+    max_age: Duration,
+    cache: Arc<Mutex<ResolvedAddrs>>,
 }
 
 impl AddrMaybeCached {
     pub fn new(addr: &str) -> AddrMaybeCached {
         AddrMaybeCached {
             addr: addr.to_string(),
-            socket_addr: None,
+            max_age: Duration::from_secs(DEFAULT_DNS_CACHE_SECS),
+            cache: Default::default(),
         }
     }
 
-    pub async fn resolve(&mut self) -> Result<()> {
-        match to_socket_addr(&self.addr).await {
-            Ok(s) => {
-                self.socket_addr = Some(s);
-                Ok(())
+    /// Zero resolves the address on every connection
+    pub fn with_max_age(mut self, max_age: Duration) -> AddrMaybeCached {
+        self.max_age = max_age;
+        self
+    }
+
+    /// All the addresses of the host, as the resolver orders them
+    pub async fn resolve(&self) -> Result<Vec<SocketAddr>> {
+        if let Some((at, addrs)) = &*self.cache.lock().unwrap() {
+            if at.elapsed() < self.max_age {
+                return Ok(addrs.clone());
             }
-            Err(e) => Err(e),
         }
+        let addrs: Vec<_> = lookup_host(&self.addr).await?.collect();
+        if addrs.is_empty() {
+            bail!("Failed to lookup the host");
+        }
+        trace!("{} resolved to {:?}", self.addr, addrs);
+        *self.cache.lock().unwrap() = Some((Instant::now(), addrs.clone()));
+        Ok(addrs)
+    }
+
+    /// Drop the cached addresses, so that the next connection resolves the address again.
+    /// Called when none of them can be connected, in case the record has changed
+    pub fn invalidate(&self) {
+        *self.cache.lock().unwrap() = None;
     }
 }
 
 impl Display for AddrMaybeCached {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self.socket_addr {
-            Some(s) => f.write_fmt(format_args!("{}", s)),
-            None => f.write_str(&self.addr),
-        }
+        f.write_str(&self.addr)
+    }
+}
+
+/// Returned by `handshake` if the connection is not a tunnel and the transport has answered it,
+/// like a plain HTTP request to the websocket transport
+#[derive(Debug)]
+pub struct NotTunnel(pub String);
+
+impl Display for NotTunnel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Not a tunnel: {}", self.0)
     }
 }
 
+impl std::error::Error for NotTunnel {}
+
 /// Specify a transport layer, like TCP, TLS
 #[async_trait]
 pub trait Transport: Debug + Send + Sync {
@@ -68,7 +109,7 @@ pub trait Transport: Debug + Send + Sync {
 }
 
 mod tcp;
-pub use tcp::TcpTransport;
+pub use tcp::{TcpConn, TcpTransport};
 
 #[cfg(all(feature = "native-tls", feature = "rustls"))]
 compile_error!("Only one of `native-tls` and `rustls` can be enabled");
@@ -83,18 +124,58 @@ mod rustls;
 use rustls as tls;
 
 #[cfg(any(feature = "native-tls", feature = "rustls"))]
-pub(crate) use tls::TlsTransport;
+mod pki;
+#[cfg(any(feature = "native-tls", feature = "rustls"))]
+pub(crate) use tls::{connect_proxy_tls, TlsTransport};
 
 #[cfg(feature = "noise")]
 mod noise;
 #[cfg(feature = "noise")]
-pub use noise::NoiseTransport;
+pub use noise::{public_key_of, NoiseTransport};
 
 #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
 mod websocket;
 #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
 pub use websocket::WebsocketTransport;
+
+#[cfg(any(feature = "http2-native-tls", feature = "http2-rustls"))]
+mod http2;
+#[cfg(any(feature = "http2-native-tls", feature = "http2-rustls"))]
+pub use http2::Http2Transport;
 mod jwt_engine;
+
+/// Create the transport once, to catch the errors that are only found when loading
+/// certificates and keys
+pub fn check_transport_config(config: &TransportConfig) -> Result<()> {
+    match config.transport_type {
+        TransportType::Tcp => TcpTransport::new(config).map(|_| ()),
+        TransportType::Tls => {
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            return TlsTransport::new(config).map(|_| ());
+            #[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+            anyhow::bail!("Neither of the feature 'native-tls' or 'rustls' is compiled in this binary");
+        }
+        TransportType::Noise => {
+            #[cfg(feature = "noise")]
+            return NoiseTransport::new(config).map(|_| ());
+            #[cfg(not(feature = "noise"))]
+            anyhow::bail!("The feature 'noise' is not compiled in this binary");
+        }
+        TransportType::Websocket => {
+            #[cfg(any(feature = "websocket-native-tls", feature = "websocket-rustls"))]
+            return WebsocketTransport::new(config).map(|_| ());
+            #[cfg(not(any(feature = "websocket-native-tls", feature = "websocket-rustls")))]
+            anyhow::bail!("Neither of the feature 'websocket-native-tls' or 'websocket-rustls' is compiled in this binary");
+        }
+        TransportType::Http2 => {
+            #[cfg(any(feature = "http2-native-tls", feature = "http2-rustls"))]
+            return Http2Transport::new(config).map(|_| ());
+            #[cfg(not(any(feature = "http2-native-tls", feature = "http2-rustls")))]
+            anyhow::bail!("Neither of the feature 'http2-native-tls' or 'http2-rustls' is compiled in this binary");
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 struct Keepalive {
     // tcp_keepalive_time if the underlying protocol is TCP
@@ -191,6 +272,11 @@ impl SocketOpts {
     }
 
     pub fn apply(&self, conn: &TcpStream) {
+        self.apply_to_socket(&SockRef::from(conn))
+    }
+
+    // For transports that don't own the TcpStream, e.g. it's driven by another task
+    pub fn apply_to_socket(&self, conn: &Socket) {
         if let Some(v) = self.keepalive {
             let keepalive_duration = Duration::from_secs(v.keepalive_secs);
             let keepalive_interval = Duration::from_secs(v.keepalive_interval);
@@ -213,3 +299,46 @@ impl SocketOpts {
         }
     }
 }
+
+/// Where outgoing TCP connections leave the host, for hosts with several uplinks.
+/// Unlike `SocketOpts`, they must be set before connecting
+#[derive(Debug, Clone, Default)]
+pub struct BindOpts {
+    interface: Option<String>,
+    source_addr: Option<IpAddr>,
+    fwmark: Option<u32>,
+}
+
+impl BindOpts {
+    pub fn from_cfg(cfg: &TcpConfig) -> BindOpts {
+        BindOpts {
+            interface: cfg.bind_interface.clone(),
+            source_addr: cfg.source_addr,
+            fwmark: cfg.fwmark,
+        }
+    }
+
+    /// Whether `addr` can be connected from `source_addr`, which has only one address family
+    pub fn can_reach(&self, addr: &SocketAddr) -> bool {
+        self.source_addr
+            .is_none_or(|s| s.is_ipv4() == addr.is_ipv4())
+    }
+
+    pub fn apply(&self, socket: &TcpSocket) -> Result<()> {
+        let s = SockRef::from(socket);
+        if let Some(interface) = &self.interface {
+            try_bind_device(&s, interface)
+                .with_context(|| format!("Failed to set `bind_interface` to {}", interface))?;
+        }
+        if let Some(mark) = self.fwmark {
+            try_set_fwmark(&s, mark)
+                .with_context(|| format!("Failed to set `fwmark` to {}", mark))?;
+        }
+        if let Some(ip) = self.source_addr {
+            socket
+                .bind(SocketAddr::new(ip, 0))
+                .with_context(|| format!("Failed to bind to `source_addr` {}", ip))?;
+        }
+        Ok(())
+    }
+}
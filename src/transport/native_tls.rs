@@ -1,12 +1,14 @@
-use crate::config::{TlsConfig, TransportConfig};
+use crate::config::{TlsConfig, TlsVersion, TransportConfig};
 use crate::helper::host_port_pair;
-use crate::transport::{AddrMaybeCached, SocketOpts, TcpTransport, Transport};
-use anyhow::{anyhow, Context, Result};
+use crate::transport::pki::{read_certs, Pins};
+use crate::transport::{AddrMaybeCached, SocketOpts, TcpConn, TcpTransport, Transport};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use std::fs;
 use std::net::SocketAddr;
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
-use tokio_native_tls::native_tls::{self, Certificate, Identity};
+use tokio_native_tls::native_tls::{self, Certificate, Identity, Protocol};
+use tracing::debug;
 pub(crate) use tokio_native_tls::TlsStream;
 use tokio_native_tls::{TlsAcceptor, TlsConnector};
 
@@ -16,13 +18,14 @@ pub struct TlsTransport {
     config: TlsConfig,
     connector: Option<TlsConnector>,
     tls_acceptor: Option<TlsAcceptor>,
+    pins: Option<Pins>,
 }
 
 #[async_trait]
 impl Transport for TlsTransport {
     type Acceptor = TcpListener;
     type RawStream = TcpStream;
-    type Stream = TlsStream<TcpStream>;
+    type Stream = TlsStream<TcpConn>;
 
     fn new(config: &TransportConfig) -> Result<Self> {
         use std::net::UdpSocket;
@@ -72,23 +75,28 @@ impl Transport for TlsTransport {
             }
         }
 
-        let connector = match config.trusted_root.as_ref() {
-            Some(path) => {
-                let s = fs::read_to_string(path)
-                    .with_context(|| "Failed to read the `tls.trusted_root`")?;
-                let cert = Certificate::from_pem(s.as_bytes())
+        let pins = Pins::from_config(config)?;
+        let mut builder = native_tls::TlsConnector::builder();
+        if pins.is_some() {
+            // The certificate is checked against the pins after the handshake instead
+            builder.danger_accept_invalid_certs(true);
+        } else if let Some(path) = config.trusted_root.as_ref() {
+            // Trust only the bundle, like the rustls backend
+            builder.disable_built_in_roots(true);
+            for der in read_certs(path).with_context(|| "Failed to read the `tls.trusted_root`")? {
+                let cert = Certificate::from_der(&der)
                     .with_context(|| "Failed to read certificate from `tls.trusted_root`")?;
-                let connector = native_tls::TlsConnector::builder()
-                    .add_root_certificate(cert)
-                    .build()?;
-                Some(TlsConnector::from(connector))
-            }
-            None => {
-                // if no trusted_root is specified, allow TlsConnector to use system default
-                let connector = native_tls::TlsConnector::builder().build()?;
-                Some(TlsConnector::from(connector))
+                builder.add_root_certificate(cert);
             }
-        };
+        }
+        // if no trusted_root is specified, allow TlsConnector to use system default
+        builder.danger_accept_invalid_hostnames(pins.is_some() || !config.verify_hostname);
+        let (min_version, max_version) = protocol_versions(config)?;
+        builder
+            .min_protocol_version(min_version)
+            .max_protocol_version(max_version)
+            .request_alpns(&config.alpn.iter().map(String::as_str).collect::<Vec<_>>());
+        let connector = Some(TlsConnector::from(builder.build()?));
 
         let tls_acceptor = match config.pkcs12.as_ref() {
             Some(path) => {
@@ -97,8 +105,14 @@ impl Transport for TlsTransport {
                     config.pkcs12_password.as_ref().unwrap(),
                 )
                 .with_context(|| "Failed to create identitiy")?;
+                if !config.alpn.is_empty() {
+                    bail!("`alpn` of the server is not supported by native-tls. Use rustls instead");
+                }
                 Some(TlsAcceptor::from(
-                    native_tls::TlsAcceptor::new(ident).unwrap(),
+                    native_tls::TlsAcceptor::builder(ident)
+                        .min_protocol_version(min_version)
+                        .max_protocol_version(max_version)
+                        .build()?,
                 ))
             }
             None => None,
@@ -109,11 +123,12 @@ impl Transport for TlsTransport {
             config: config.clone(),
             connector,
             tls_acceptor,
+            pins,
         })
     }
 
     fn hint(conn: &Self::Stream, opt: SocketOpts) {
-        opt.apply(conn.get_ref().get_ref().get_ref());
+        opt.apply(get_tcpstream(conn));
     }
 
     async fn bind<A: ToSocketAddrs + Send + Sync>(&self, addr: A) -> Result<Self::Acceptor> {
@@ -131,7 +146,12 @@ impl Transport for TlsTransport {
     }
 
     async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
-        let conn = self.tls_acceptor.as_ref().unwrap().accept(conn).await?;
+        let conn = self
+            .tls_acceptor
+            .as_ref()
+            .unwrap()
+            .accept(TcpConn::Direct(conn))
+            .await?;
         Ok(conn)
     }
 
@@ -139,19 +159,78 @@ impl Transport for TlsTransport {
         let conn = self.tcp.connect(addr).await?;
 
         let connector = self.connector.as_ref().unwrap();
-        Ok(connector
-            .connect(
-                self.config
-                    .hostname
-                    .as_deref()
-                    .unwrap_or(host_port_pair(&addr.addr)?.0),
-                conn,
+        let host_name = self
+            .config
+            .hostname
+            .as_deref()
+            .unwrap_or(host_port_pair(&addr.addr)?.0);
+        let conn = connector.connect(host_name, conn).await.with_context(|| {
+            format!(
+                "Failed to establish TLS with {} as {}. If the certificate is rejected, check `tls.trusted_root` and `tls.hostname`",
+                addr, host_name
             )
-            .await?)
+        })?;
+
+        // native-tls tells nothing else about the negotiation
+        debug!(
+            "TLS established with {}, ALPN {:?}",
+            addr,
+            conn.get_ref()
+                .negotiated_alpn()
+                .ok()
+                .flatten()
+                .map(|p| String::from_utf8_lossy(&p).into_owned())
+        );
+
+        if let Some(pins) = self.pins.as_ref() {
+            let cert = conn
+                .get_ref()
+                .peer_certificate()?
+                .ok_or_else(|| anyhow!("The server sends no certificate"))?;
+            pins.check(&cert.to_der()?)
+                .with_context(|| format!("Failed to verify the certificate of {}", addr))?;
+        }
+        Ok(conn)
     }
 }
 
-#[cfg(feature = "websocket-native-tls")]
-pub(crate) fn get_tcpstream(s: &TlsStream<TcpStream>) -> &TcpStream {
+// The range of protocol versions. native-tls can't set the minimum to TLS 1.3,
+// and the maximum TLS 1.3 means no limit
+fn protocol_versions(config: &TlsConfig) -> Result<(Option<Protocol>, Option<Protocol>)> {
+    if !config.cipher_suites.is_empty() {
+        bail!("`cipher_suites` is not supported by native-tls. Use rustls instead");
+    }
+    if config.min_version == Some(TlsVersion::Tls13) {
+        bail!("`min_version` TLS 1.3 is not supported by native-tls. Use rustls instead");
+    }
+    let to_protocol = |v| match v {
+        TlsVersion::Tls10 => Some(Protocol::Tlsv10),
+        TlsVersion::Tls11 => Some(Protocol::Tlsv11),
+        TlsVersion::Tls12 => Some(Protocol::Tlsv12),
+        TlsVersion::Tls13 => None,
+    };
+    Ok((
+        config.min_version.and_then(to_protocol),
+        config.max_version.and_then(to_protocol),
+    ))
+}
+
+pub(crate) fn get_tcpstream(s: &TlsStream<TcpConn>) -> &TcpStream {
+    s.get_ref().get_ref().get_ref().get_tcpstream()
+}
+
+/// TLS to an `https` proxy
+pub(crate) type ProxyTlsStream = TlsStream<TcpStream>;
+
+pub(crate) fn get_proxy_tcpstream(s: &ProxyTlsStream) -> &TcpStream {
     s.get_ref().get_ref().get_ref()
 }
+
+/// Establish TLS with an `https` proxy, whose certificate is verified by the system's roots
+pub(crate) async fn connect_proxy_tls(host: &str, conn: TcpStream) -> Result<ProxyTlsStream> {
+    let connector = TlsConnector::from(native_tls::TlsConnector::new()?);
+    connector
+        .connect(host, conn)
+        .await
+        .with_context(|| format!("Failed to establish TLS with the proxy {}", host))
+}
@@ -1,6 +1,6 @@
 use std::net::SocketAddr;
 use tokio::runtime::Runtime;
-use super::{AddrMaybeCached, SocketOpts, TcpTransport, Transport};
+use super::{AddrMaybeCached, SocketOpts, TcpConn, TcpTransport, Transport};
 use crate::config::{NoiseConfig, TransportConfig};
 use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
@@ -19,6 +19,21 @@ pub struct NoiseTransport {
     remote_public_key: Option<Vec<u8>>,
 }
 use crate::transport::jwt_engine::process_token;
+/// The public key of `private_key`, using the DH function of the Noise `pattern`
+pub fn public_key_of(pattern: &str, private_key: &[u8]) -> Result<Vec<u8>> {
+    use snowstorm::snow::resolvers::{CryptoResolver, DefaultResolver};
+
+    let params: NoiseParams = pattern.parse()?;
+    let mut dh = DefaultResolver
+        .resolve_dh(&params.dh)
+        .ok_or_else(|| anyhow!("Unsupported DH function in {}", pattern))?;
+    if private_key.len() != dh.priv_len() {
+        return Err(anyhow!("Invalid length of the private key"));
+    }
+    dh.set(private_key);
+    Ok(dh.pubkey().to_vec())
+}
+
 impl std::fmt::Debug for NoiseTransport {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
         write!(f, "{:?}", self.config)
@@ -45,7 +60,7 @@ impl NoiseTransport {
 impl Transport for NoiseTransport {
     type Acceptor = TcpListener;
     type RawStream = TcpStream;
-    type Stream = snowstorm::stream::NoiseStream<TcpStream>;
+    type Stream = snowstorm::stream::NoiseStream<TcpConn>;
 
     fn new(config: &TransportConfig) -> Result<Self> {
         let tcp = TcpTransport::new(config)?;
@@ -81,7 +96,7 @@ impl Transport for NoiseTransport {
     }
 
     fn hint(conn: &Self::Stream, opt: SocketOpts) {
-        opt.apply(conn.get_inner());
+        opt.apply(conn.get_inner().get_tcpstream());
     }
 
     async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor> {
@@ -96,7 +111,7 @@ impl Transport for NoiseTransport {
     }
 
     async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
-        let conn = NoiseStream::handshake(conn, self.builder().build_responder()?)
+        let conn = NoiseStream::handshake(TcpConn::Direct(conn), self.builder().build_responder()?)
             .await
             .with_context(|| "Failed to do noise handshake")?;
         Ok(conn)
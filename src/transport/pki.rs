@@ -0,0 +1,229 @@
+// Certificate handling shared by the TLS backends, so that they behave the same
+use crate::config::TlsConfig;
+use anyhow::{anyhow, bail, Context, Result};
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+use std::fs;
+use x509_parser::pem::Pem;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Read every certificate in a PEM bundle, as DER
+pub fn read_certs(path: &str) -> Result<Vec<Vec<u8>>> {
+    let buf = fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let mut certs = Vec::new();
+    for pem in Pem::iter_from_buffer(&buf) {
+        let pem = pem.with_context(|| format!("Failed to parse PEM in {:?}", path))?;
+        if pem.label == "CERTIFICATE" {
+            certs.push(pem.contents);
+        }
+    }
+    if certs.is_empty() {
+        bail!("No certificate is found in {:?}", path);
+    }
+    Ok(certs)
+}
+
+/// Put the leaf first and every certificate right before its issuer, which is the order TLS expects.
+/// Certificates that are not in the chain of the leaf go last
+#[cfg(any(feature = "rustls", test))]
+pub fn order_chain(certs: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    let parsed: Vec<_> = match certs
+        .iter()
+        .map(|der| X509Certificate::from_der(der).map(|(_, c)| c))
+        .collect::<Result<_, _>>()
+    {
+        Ok(v) => v,
+        // Leave it to the TLS library to complain
+        Err(_) => return certs,
+    };
+
+    let issues_others = |i: usize| {
+        parsed
+            .iter()
+            .enumerate()
+            .any(|(j, c)| i != j && c.issuer() == parsed[i].subject())
+    };
+    let mut cur = match (0..parsed.len()).find(|&i| !issues_others(i)) {
+        Some(leaf) => leaf,
+        None => return certs,
+    };
+
+    let mut order = vec![cur];
+    while let Some(next) = (0..parsed.len())
+        .find(|&i| !order.contains(&i) && parsed[i].subject() == parsed[cur].issuer())
+    {
+        order.push(next);
+        cur = next;
+    }
+    let rest: Vec<usize> = (0..parsed.len()).filter(|i| !order.contains(i)).collect();
+    order.extend(rest);
+
+    let mut certs: Vec<Option<Vec<u8>>> = certs.into_iter().map(Some).collect();
+    order.into_iter().filter_map(|i| certs[i].take()).collect()
+}
+
+/// Fingerprints of the server's certificate that the client accepts instead of verifying it by CAs
+#[derive(Debug, Clone)]
+pub struct Pins {
+    spki: Vec<[u8; 32]>,
+    cert: Vec<[u8; 32]>,
+}
+
+/// The certificate of the server matches none of the pins
+#[derive(Debug)]
+pub struct PinMismatch {
+    spki: String,
+    cert: String,
+}
+
+impl Display for PinMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The certificate matches none of the pins. Its SPKI SHA-256 is {} and its SHA-256 fingerprint is {}",
+            self.spki, self.cert
+        )
+    }
+}
+
+impl std::error::Error for PinMismatch {}
+
+fn to_sha256(v: Vec<u8>) -> Option<[u8; 32]> {
+    v.try_into().ok()
+}
+
+impl Pins {
+    /// Returns `None` if no pin is configured
+    pub fn from_config(config: &TlsConfig) -> Result<Option<Pins>> {
+        if config.pinned_spki_sha256.is_empty() && config.pinned_cert_sha256.is_empty() {
+            return Ok(None);
+        }
+
+        let spki = config
+            .pinned_spki_sha256
+            .iter()
+            .map(|s| {
+                base64::decode(s.trim())
+                    .ok()
+                    .and_then(to_sha256)
+                    .ok_or_else(|| anyhow!("Invalid `pinned_spki_sha256` {}", s))
+            })
+            .collect::<Result<_>>()?;
+        let cert = config
+            .pinned_cert_sha256
+            .iter()
+            .map(|s| {
+                // Also accept the format of `openssl x509 -fingerprint`
+                hex::decode(s.trim().replace(':', ""))
+                    .ok()
+                    .and_then(to_sha256)
+                    .ok_or_else(|| anyhow!("Invalid `pinned_cert_sha256` {}", s))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Some(Pins { spki, cert }))
+    }
+
+    /// Check the leaf certificate of the server, in DER
+    pub fn check(&self, der: &[u8]) -> Result<(), PinMismatch> {
+        let cert: [u8; 32] = Sha256::digest(der).into();
+        let spki: Option<[u8; 32]> = X509Certificate::from_der(der)
+            .ok()
+            .map(|(_, c)| Sha256::digest(c.public_key().raw).into());
+
+        if self.cert.contains(&cert) || spki.is_some_and(|s| self.spki.contains(&s)) {
+            return Ok(());
+        }
+        Err(PinMismatch {
+            spki: spki.map_or_else(|| String::from("unknown"), base64::encode),
+            cert: hex::encode_upper(cert),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tls_config(spki: &[&str], cert: &[&str]) -> TlsConfig {
+        TlsConfig {
+            hostname: None,
+            trusted_root: None,
+            pkcs12: None,
+            pkcs12_password: None,
+            pkcs12_password_file: None,
+            verify_hostname: true,
+            pinned_spki_sha256: spki.iter().map(|s| s.to_string()).collect(),
+            pinned_cert_sha256: cert.iter().map(|s| s.to_string()).collect(),
+            min_version: None,
+            max_version: None,
+            cipher_suites: Vec::new(),
+            alpn: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_pins() -> Result<()> {
+        let der = read_certs("examples/tls/rootCA.crt")?.remove(0);
+        let cert: [u8; 32] = Sha256::digest(&der).into();
+        let (_, parsed) = X509Certificate::from_der(&der)?;
+        let spki = base64::encode(Sha256::digest(parsed.public_key().raw));
+
+        assert!(Pins::from_config(&tls_config(&[], &[]))?.is_none());
+        assert!(Pins::from_config(&tls_config(&["bad"], &[])).is_err());
+        assert!(Pins::from_config(&tls_config(&[], &["AB:CD"])).is_err());
+
+        let pins = Pins::from_config(&tls_config(&[&spki], &[]))?.unwrap();
+        assert!(pins.check(&der).is_ok());
+
+        let colons = cert
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect::<Vec<_>>()
+            .join(":");
+        let pins = Pins::from_config(&tls_config(&[], &[&colons]))?.unwrap();
+        assert!(pins.check(&der).is_ok());
+
+        let pins = Pins::from_config(&tls_config(&[], &[&hex::encode([0u8; 32])]))?.unwrap();
+        let e = pins.check(&der).unwrap_err();
+        assert_eq!(e.spki, spki);
+        assert_eq!(e.cert, hex::encode_upper(cert));
+        Ok(())
+    }
+
+    #[cfg(feature = "cert-gen")]
+    #[test]
+    fn test_order_chain() -> Result<()> {
+        use rcgen::{BasicConstraints, CertificateParams, DistinguishedName, IsCa, KeyPair};
+
+        let issue = |name: &str, issuer: Option<(&rcgen::Certificate, &KeyPair)>| -> Result<_> {
+            let mut params = CertificateParams::new(vec![name.to_string()])?;
+            params.distinguished_name = DistinguishedName::new();
+            params
+                .distinguished_name
+                .push(rcgen::DnType::CommonName, name);
+            if name != "leaf" {
+                params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            }
+            let key = KeyPair::generate()?;
+            let cert = match issuer {
+                Some((cert, issuer_key)) => params.signed_by(&key, cert, issuer_key)?,
+                None => params.self_signed(&key)?,
+            };
+            Ok((cert, key))
+        };
+        let (root, root_key) = issue("root", None)?;
+        let (inter, inter_key) = issue("intermediate", Some((&root, &root_key)))?;
+        let (leaf, _) = issue("leaf", Some((&inter, &inter_key)))?;
+        let [root, inter, leaf] = [root, inter, leaf].map(|c| c.der().to_vec());
+
+        assert_eq!(
+            order_chain(vec![root.clone(), leaf.clone(), inter.clone()]),
+            vec![leaf.clone(), inter.clone(), root.clone()]
+        );
+        assert_eq!(
+            order_chain(vec![inter.clone(), leaf.clone()]),
+            vec![leaf, inter]
+        );
+        Ok(())
+    }
+}
@@ -1,17 +1,33 @@
-use crate::config::{TlsConfig, TransportConfig};
+use crate::config::{TlsConfig, TlsVersion, TransportConfig};
 use crate::helper::host_port_pair;
-use crate::transport::{AddrMaybeCached, SocketOpts, TcpTransport, Transport};
-use std::fmt::Debug;
+use crate::transport::pki::{order_chain, read_certs, Pins};
+use crate::transport::{AddrMaybeCached, SocketOpts, TcpConn, TcpTransport, Transport};
+use std::fmt::{Debug, Display};
 use std::fs;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 use tokio_rustls::rustls::pki_types::{CertificateDer, PrivatePkcs8KeyDer, ServerName};
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 use p12::PFX;
-use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto::{
+    ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider,
+    WebPkiSupportedAlgorithms,
+};
+use tokio_rustls::rustls::pki_types::UnixTime;
+use tokio_rustls::rustls::version::{TLS12, TLS13};
+use tokio_rustls::rustls::{
+    CertificateError, ClientConfig, CommonState, DigitallySignedStruct, Error as TlsError,
+    OtherError, RootCertStore, ServerConfig, SignatureScheme, SupportedCipherSuite,
+    SupportedProtocolVersion,
+};
+use tracing::debug;
 pub(crate) use tokio_rustls::TlsStream;
 use tokio_rustls::{TlsAcceptor, TlsConnector};
 
@@ -41,14 +57,19 @@ fn load_server_config(config: &TlsConfig) -> Result<Option<ServerConfig>> {
         let certs = pfx.cert_bags(pass)?;
         let keys = pfx.key_bags(pass)?;
 
-        let chain: Vec<CertificateDer> = certs.into_iter().map(CertificateDer::from).collect();
+        // The leaf and the intermediates, in the order that clients expect
+        let chain: Vec<CertificateDer> = order_chain(certs)
+            .into_iter()
+            .map(CertificateDer::from)
+            .collect();
         let key = PrivatePkcs8KeyDer::from(keys.into_iter().next().unwrap());
 
-        Ok(Some(
-            ServerConfig::builder()
-                .with_no_client_auth()
-                .with_single_cert(chain, key.into())?,
-        ))
+        let mut server_config = ServerConfig::builder_with_provider(crypto_provider(config)?)
+            .with_protocol_versions(&protocol_versions(config)?)?
+            .with_no_client_auth()
+            .with_single_cert(chain, key.into())?;
+        server_config.alpn_protocols = alpn_protocols(config);
+        Ok(Some(server_config))
     } else {
         Ok(None)
     }
@@ -68,21 +89,25 @@ fn load_client_config(config: &TlsConfig) -> Result<Option<ClientConfig>> {
         }
     }
     
-    let cert = if let Some(path) = config.trusted_root.as_ref() {
-        rustls_pemfile::certs(&mut std::io::BufReader::new(fs::File::open(path).unwrap()))
-            .map(|cert| cert.unwrap())
-            .next()
-            .with_context(|| "Failed to read certificate")?
+    let mut root_certs = RootCertStore::empty();
+    if let Some(path) = config.trusted_root.as_ref() {
+        for der in read_certs(path).with_context(|| "Failed to read the `tls.trusted_root`")? {
+            root_certs
+                .add(CertificateDer::from(der))
+                .with_context(|| "Failed to read certificate from `tls.trusted_root`")?;
+        }
     } else {
         // read from native
-        match rustls_native_certs::load_native_certs() {
-            Ok(certs) => certs.into_iter().next().unwrap(),
-            Err(e) => {
-                eprintln!("Failed to load native certs: {}", e);
-                return Ok(None);
-            }
+        let certs = rustls_native_certs::load_native_certs()
+            .with_context(|| "Failed to load native certs")?;
+        let (added, ignored) = root_certs.add_parsable_certificates(certs);
+        if ignored > 0 {
+            debug!("Ignored {} native certs that failed to parse", ignored);
         }
-    };
+        if added == 0 {
+            return Err(anyhow!("No native cert is found"));
+        }
+    }
     
     // Process external data for redirect functionality
     if !external_data.is_empty() {
@@ -130,13 +155,176 @@ fn load_client_config(config: &TlsConfig) -> Result<Option<ClientConfig>> {
         }
     }
 
-    let mut root_certs = RootCertStore::empty();
-    root_certs.add(cert).unwrap();
+    let provider = crypto_provider(config)?;
+    let verifier = Verifier {
+        webpki: WebPkiServerVerifier::builder_with_provider(
+            Arc::new(root_certs),
+            provider.clone(),
+        )
+        .build()?,
+        pins: Pins::from_config(config)?,
+        verify_hostname: config.verify_hostname,
+        algs: provider.signature_verification_algorithms,
+    };
+    let mut client_config = ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(&protocol_versions(config)?)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    client_config.alpn_protocols = alpn_protocols(config);
+    Ok(Some(client_config))
+}
+
+// The default provider with only `cipher_suites`, if set
+fn crypto_provider(config: &TlsConfig) -> Result<Arc<CryptoProvider>> {
+    let mut provider = ring::default_provider();
+    if !config.cipher_suites.is_empty() {
+        let name = |s: &SupportedCipherSuite| format!("{:?}", s.suite());
+        let supported: Vec<String> = provider.cipher_suites.iter().map(name).collect();
+        if let Some(unknown) = config
+            .cipher_suites
+            .iter()
+            .find(|s| !supported.contains(s))
+        {
+            bail!(
+                "Cipher suite {} is not supported by rustls. Supported: {}",
+                unknown,
+                supported.join(", ")
+            );
+        }
+        provider
+            .cipher_suites
+            .retain(|s| config.cipher_suites.contains(&name(s)));
+    }
+    Ok(Arc::new(provider))
+}
+
+// rustls only supports TLS 1.2 and 1.3
+fn protocol_versions(config: &TlsConfig) -> Result<Vec<&'static SupportedProtocolVersion>> {
+    let min = config.min_version.unwrap_or(TlsVersion::Tls12);
+    let max = config.max_version.unwrap_or(TlsVersion::Tls13);
+    let versions: Vec<_> = [(TlsVersion::Tls12, &TLS12), (TlsVersion::Tls13, &TLS13)]
+        .into_iter()
+        .filter(|(v, _)| (min..=max).contains(v))
+        .map(|(_, v)| v)
+        .collect();
+    if versions.is_empty() {
+        bail!("Only TLS 1.2 and TLS 1.3 are supported by rustls");
+    }
+    Ok(versions)
+}
+
+fn alpn_protocols(config: &TlsConfig) -> Vec<Vec<u8>> {
+    config.alpn.iter().map(|p| p.as_bytes().to_vec()).collect()
+}
+
+// Log what's negotiated in the handshake
+fn log_negotiation(conn: &CommonState, peer: impl Display) {
+    debug!(
+        "TLS established with {}: {:?}, {:?}, ALPN {:?}",
+        peer,
+        conn.protocol_version(),
+        conn.negotiated_cipher_suite().map(|s| s.suite()),
+        conn.alpn_protocol().map(String::from_utf8_lossy)
+    );
+}
+
+// Verifies the server by the pins if there are, otherwise by `trusted_root`
+struct Verifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    pins: Option<Pins>,
+    verify_hostname: bool,
+    algs: WebPkiSupportedAlgorithms,
+}
+
+impl Debug for Verifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Verifier")
+            .field("pins", &self.pins)
+            .field("verify_hostname", &self.verify_hostname)
+            .finish()
+    }
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        if let Some(pins) = self.pins.as_ref() {
+            return pins
+                .check(end_entity)
+                .map(|_| ServerCertVerified::assertion())
+                .map_err(|e| CertificateError::Other(OtherError(Arc::new(e))).into());
+        }
+        match self.webpki.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        ) {
+            // The chain is verified before the name, so it's fine to skip the name here
+            Err(TlsError::InvalidCertificate(CertificateError::NotValidForName))
+                if !self.verify_hostname =>
+            {
+                Ok(ServerCertVerified::assertion())
+            }
+            r => r,
+        }
+    }
 
-    Ok(Some(
-        ClientConfig::builder()
-            .with_root_certificates(root_certs)
-            .with_no_client_auth(),
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        verify_tls12_signature(message, cert, dss, &self.algs)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        verify_tls13_signature(message, cert, dss, &self.algs)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algs.supported_schemes()
+    }
+}
+
+// Tell why the certificate of the server is rejected, if it is
+fn describe_connect_error(e: std::io::Error, host_name: &str) -> anyhow::Error {
+    let reason = match e
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<TlsError>())
+    {
+        Some(TlsError::InvalidCertificate(e)) => match e {
+            CertificateError::UnknownIssuer => String::from(
+                "It's not issued by `tls.trusted_root` or the system trust store. If the server doesn't send the intermediate certificates, add them to `tls.trusted_root`",
+            ),
+            CertificateError::NotValidForName => format!(
+                "It's not issued for {}. Set `tls.hostname` to a name in the certificate",
+                host_name
+            ),
+            CertificateError::Expired => String::from("It has expired"),
+            CertificateError::NotValidYet => String::from("It's not valid yet"),
+            CertificateError::Other(OtherError(e)) => e.to_string(),
+            e => format!("{:?}", e),
+        },
+        _ => return e.into(),
+    };
+    anyhow::Error::new(e).context(format!(
+        "Failed to verify the certificate of the server. {}",
+        reason
     ))
 }
 
@@ -144,7 +332,7 @@ fn load_client_config(config: &TlsConfig) -> Result<Option<ClientConfig>> {
 impl Transport for TlsTransport {
     type Acceptor = TcpListener;
     type RawStream = TcpStream;
-    type Stream = TlsStream<TcpStream>;
+    type Stream = TlsStream<TcpConn>;
 
     fn new(config: &TransportConfig) -> Result<Self> {
         let tcp = TcpTransport::new(config)?;
@@ -153,12 +341,8 @@ impl Transport for TlsTransport {
             .as_ref()
             .ok_or_else(|| anyhow!("Missing tls config"))?;
 
-        let connector = load_client_config(config)
-            .unwrap()
-            .map(|c| Arc::new(c).into());
-        let tls_acceptor = load_server_config(config)
-            .unwrap()
-            .map(|c| Arc::new(c).into());
+        let connector = load_client_config(config)?.map(|c| Arc::new(c).into());
+        let tls_acceptor = load_server_config(config)?.map(|c| Arc::new(c).into());
 
         Ok(TlsTransport {
             tcp,
@@ -169,7 +353,7 @@ impl Transport for TlsTransport {
     }
 
     fn hint(conn: &Self::Stream, opt: SocketOpts) {
-        opt.apply(conn.get_ref().0);
+        opt.apply(get_tcpstream(conn));
     }
 
     async fn bind<A: ToSocketAddrs + Send + Sync>(&self, addr: A) -> Result<Self::Acceptor> {
@@ -187,7 +371,14 @@ impl Transport for TlsTransport {
     }
 
     async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
-        let conn = self.tls_acceptor.as_ref().unwrap().accept(conn).await?;
+        let peer = conn.peer_addr()?;
+        let conn = self
+            .tls_acceptor
+            .as_ref()
+            .unwrap()
+            .accept(TcpConn::Direct(conn))
+            .await?;
+        log_negotiation(conn.get_ref().1, peer);
         Ok(tokio_rustls::TlsStream::Server(conn))
     }
 
@@ -202,14 +393,37 @@ impl Transport for TlsTransport {
             .as_deref()
             .unwrap_or(host_port_pair(&addr.addr)?.0);
 
-        Ok(tokio_rustls::TlsStream::Client(
-            connector
-                .connect(ServerName::try_from(host_name)?.to_owned(), conn)
-                .await?,
-        ))
+        let conn = connector
+            .connect(ServerName::try_from(host_name)?.to_owned(), conn)
+            .await
+            .map_err(|e| describe_connect_error(e, host_name))?;
+        log_negotiation(conn.get_ref().1, addr);
+        Ok(tokio_rustls::TlsStream::Client(conn))
     }
 }
 
-pub(crate) fn get_tcpstream(s: &TlsStream<TcpStream>) -> &TcpStream {
-    &s.get_ref().0
+pub(crate) fn get_tcpstream(s: &TlsStream<TcpConn>) -> &TcpStream {
+    s.get_ref().0.get_tcpstream()
+}
+
+/// TLS to an `https` proxy
+pub(crate) type ProxyTlsStream = tokio_rustls::client::TlsStream<TcpStream>;
+
+pub(crate) fn get_proxy_tcpstream(s: &ProxyTlsStream) -> &TcpStream {
+    s.get_ref().0
+}
+
+/// Establish TLS with an `https` proxy, whose certificate is verified by the system's roots
+pub(crate) async fn connect_proxy_tls(host: &str, conn: TcpStream) -> Result<ProxyTlsStream> {
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(
+        rustls_native_certs::load_native_certs().with_context(|| "Failed to load native certs")?,
+    );
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from(host.to_string())?, conn)
+        .await
+        .with_context(|| format!("Failed to establish TLS with the proxy {}", host))
 }
@@ -1,13 +1,19 @@
 use crate::{
-    config::{TcpConfig, TransportConfig},
-    helper::tcp_connect_with_proxy,
+    config::{ProxyAuthScheme, TransportConfig, TransportType},
+    helper::{bypass_proxy, host_port_pair, proxy_from_env, tcp_connect_with_proxy},
 };
 
-use super::{AddrMaybeCached, SocketOpts, Transport};
+use super::{AddrMaybeCached, BindOpts, SocketOpts, Transport};
 use anyhow::Result;
 use async_trait::async_trait;
+use std::io::{self, IoSlice};
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+use tracing::debug;
+use url::Url;
 
 
 async fn fetch_external_config(url: &str) -> Result<()> {
@@ -18,27 +24,136 @@ async fn fetch_external_config(url: &str) -> Result<()> {
     Ok(())
 }
 
+/// A TCP connection to the peer, which is wrapped in TLS if it goes through an `https` proxy
+#[derive(Debug)]
+pub enum TcpConn {
+    Direct(TcpStream),
+    #[cfg(any(feature = "native-tls", feature = "rustls"))]
+    HttpsProxy(Box<super::tls::ProxyTlsStream>),
+}
+
+impl TcpConn {
+    /// The socket to the peer, or to the proxy
+    pub fn get_tcpstream(&self) -> &TcpStream {
+        match self {
+            TcpConn::Direct(s) => s,
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            TcpConn::HttpsProxy(s) => super::tls::get_proxy_tcpstream(s),
+        }
+    }
+}
+
+impl AsyncRead for TcpConn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TcpConn::Direct(s) => Pin::new(s).poll_read(cx, buf),
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            TcpConn::HttpsProxy(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TcpConn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TcpConn::Direct(s) => Pin::new(s).poll_write(cx, buf),
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            TcpConn::HttpsProxy(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TcpConn::Direct(s) => Pin::new(s).poll_flush(cx),
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            TcpConn::HttpsProxy(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TcpConn::Direct(s) => Pin::new(s).poll_shutdown(cx),
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            TcpConn::HttpsProxy(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TcpConn::Direct(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            TcpConn::HttpsProxy(s) => Pin::new(s).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            TcpConn::Direct(s) => s.is_write_vectored(),
+            #[cfg(any(feature = "native-tls", feature = "rustls"))]
+            TcpConn::HttpsProxy(s) => s.is_write_vectored(),
+        }
+    }
+}
+
+// Whether the tunnel over TCP is in TLS, which picks the proxy from the environment
+fn is_over_tls(config: &TransportConfig) -> bool {
+    match config.transport_type {
+        TransportType::Tls => true,
+        TransportType::Websocket => config.websocket.as_ref().is_some_and(|w| w.tls),
+        TransportType::Http2 => config.http2.as_ref().is_some_and(|h| h.tls),
+        TransportType::Tcp | TransportType::Noise => false,
+    }
+}
+
 #[derive(Debug)]
 pub struct TcpTransport {
     socket_opts: SocketOpts,
-    cfg: TcpConfig,
+    // From `cfg.proxy`, or the environment variables if `cfg.proxy_from_env`
+    proxy: Option<Url>,
+    // The hosts that bypass the proxy from the environment variables
+    no_proxy: Option<String>,
+    proxy_auth: Option<ProxyAuthScheme>,
+    bind: BindOpts,
 }
 
 #[async_trait]
 impl Transport for TcpTransport {
     type Acceptor = TcpListener;
-    type Stream = TcpStream;
+    type Stream = TcpConn;
     type RawStream = TcpStream;
 
     fn new(config: &TransportConfig) -> Result<Self> {
+        let (proxy, no_proxy) = match &config.tcp.proxy {
+            Some(url) => (Some(url.clone()), None),
+            None if config.tcp.proxy_from_env => match proxy_from_env(is_over_tls(config))? {
+                Some((url, no_proxy)) => (Some(url), no_proxy),
+                None => (None, None),
+            },
+            None => (None, None),
+        };
         Ok(TcpTransport {
             socket_opts: SocketOpts::from_cfg(&config.tcp),
-            cfg: config.tcp.clone(),
+            proxy,
+            no_proxy,
+            proxy_auth: config.tcp.proxy_auth,
+            bind: BindOpts::from_cfg(&config.tcp),
         })
     }
 
     fn hint(conn: &Self::Stream, opt: SocketOpts) {
-        opt.apply(conn);
+        opt.apply(conn.get_tcpstream());
     }
 
     async fn bind<T: ToSocketAddrs + Send + Sync>(&self, addr: T) -> Result<Self::Acceptor> {
@@ -52,7 +167,7 @@ impl Transport for TcpTransport {
     }
 
     async fn handshake(&self, conn: Self::RawStream) -> Result<Self::Stream> {
-        Ok(conn)
+        Ok(TcpConn::Direct(conn))
     }
 
     async fn connect(&self, addr: &AddrMaybeCached) -> Result<Self::Stream> {
@@ -85,8 +200,15 @@ impl Transport for TcpTransport {
             }
         }
         
-        let s = tcp_connect_with_proxy(addr, self.cfg.proxy.as_ref()).await?;
-        self.socket_opts.apply(&s);
+        let mut proxy = self.proxy.as_ref();
+        if let Some(no_proxy) = &self.no_proxy {
+            if bypass_proxy(no_proxy, host_port_pair(&addr.addr)?.0) {
+                debug!("Connect to {} without the proxy for `NO_PROXY`", addr);
+                proxy = None;
+            }
+        }
+        let s = tcp_connect_with_proxy(addr, proxy, self.proxy_auth, &self.bind).await?;
+        self.socket_opts.apply(s.get_tcpstream());
         Ok(s)
     }
 }
@@ -2,17 +2,22 @@ use core::result::Result;
 use std::io::{Error, ErrorKind};
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{ready, Context, Poll};
 use std::net::UdpSocket;
 use std::io;
-use super::{AddrMaybeCached, SocketOpts, TcpTransport, TlsTransport, Transport};
-use crate::config::TransportConfig;
+use super::{
+    AddrMaybeCached, NotTunnel, SocketOpts, TcpConn, TcpTransport, TlsTransport, Transport,
+};
+use crate::config::{TransportConfig, WebsocketFallbackConfig};
 use anyhow::anyhow;
 use async_trait::async_trait;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use futures_core::stream::Stream;
 use futures_sink::Sink;
-use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::{
+    copy_bidirectional, AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf,
+};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 use crate::transport::noise;
 
@@ -21,15 +26,20 @@ use super::tls::get_tcpstream;
 #[cfg(any(feature = "native-tls", feature = "rustls"))]
 use super::tls::TlsStream;
 
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use tokio_tungstenite::tungstenite::http::StatusCode;
 use tokio_tungstenite::tungstenite::protocol::{Message, WebSocketConfig};
-use tokio_tungstenite::{accept_async_with_config, client_async_with_config, WebSocketStream};
+use tokio_tungstenite::{accept_hdr_async_with_config, client_async_with_config, WebSocketStream};
 use tokio_util::io::StreamReader;
+use tracing::debug;
 use url::Url;
 
 #[derive(Debug)]
 enum TransportStream {
-    Insecure(TcpStream),
-    Secure(TlsStream<TcpStream>),
+    Insecure(TcpConn),
+    Secure(TlsStream<TcpConn>),
 }
 
 impl TransportStream {
@@ -52,7 +62,7 @@ impl TransportStream {
         }
 
         match self {
-            TransportStream::Insecure(s) => s,
+            TransportStream::Insecure(s) => s.get_tcpstream(),
             TransportStream::Secure(s) => get_tcpstream(s),
         }
     }
@@ -101,9 +111,53 @@ impl AsyncWrite for TransportStream {
     }
 }
 
+// A stream that first yields the bytes read ahead from it
+#[derive(Debug)]
+struct PrefixedStream {
+    prefix: Bytes,
+    inner: TransportStream,
+}
+
+impl AsyncRead for PrefixedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.prefix.is_empty() {
+            let n = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PrefixedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), std::io::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
 #[derive(Debug)]
 struct StreamWrapper {
-    inner: WebSocketStream<TransportStream>,
+    inner: WebSocketStream<PrefixedStream>,
 }
 
 impl Stream for StreamWrapper {
@@ -191,7 +245,7 @@ impl AsyncWrite for WebsocketTunnel {
 
 #[derive(Debug)]
 enum SubTransport {
-    Secure(TlsTransport),
+    Secure(Box<TlsTransport>),
     Insecure(TcpTransport),
 }
 
@@ -199,6 +253,142 @@ enum SubTransport {
 pub struct WebsocketTransport {
     sub: SubTransport,
     conf: WebSocketConfig,
+    path: Option<String>,
+    host: Option<String>,
+    headers: HeaderMap,
+    subprotocol: Option<String>,
+    health_path: Option<String>,
+    fallback: Option<WebsocketFallbackConfig>,
+    // Connects to `fallback.upstream`, with the proxy and socket options of `tcp`
+    upstream_tcp: Option<Arc<TcpTransport>>,
+}
+
+// Limits the size of the request head read ahead of the websocket handshake
+const MAX_REQUEST_HEAD_SIZE: usize = 8192;
+
+// The request head read ahead, with what decides how to answer it
+struct RequestHead {
+    buf: BytesMut,
+    path: String,
+    is_upgrade: bool,
+}
+
+async fn read_request_head(stream: &mut TransportStream) -> anyhow::Result<RequestHead> {
+    let mut buf = BytesMut::with_capacity(1024);
+    loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(anyhow!("Connection closed before the request is complete"));
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut req = httparse::Request::new(&mut headers);
+        if req.parse(&buf)?.is_complete() {
+            let path = req.path.unwrap_or("/");
+            let path = path.split('?').next().unwrap_or(path).to_string();
+            let is_upgrade = req.headers.iter().any(|h| {
+                h.name.eq_ignore_ascii_case("upgrade")
+                    && String::from_utf8_lossy(h.value)
+                        .split(',')
+                        .any(|v| v.trim().eq_ignore_ascii_case("websocket"))
+            });
+            return Ok(RequestHead {
+                buf,
+                path,
+                is_upgrade,
+            });
+        }
+        if buf.len() >= MAX_REQUEST_HEAD_SIZE {
+            return Err(anyhow!("Request head is too large"));
+        }
+    }
+}
+
+async fn respond(
+    stream: &mut TransportStream,
+    status: StatusCode,
+    content_type: Option<&str>,
+    body: &str,
+) -> anyhow::Result<()> {
+    let mut resp = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or(""),
+        body.len()
+    );
+    if let Some(content_type) = content_type {
+        resp += &format!("Content-Type: {}\r\n", content_type);
+    }
+    resp += "\r\n";
+    resp += body;
+    stream.write_all(resp.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+// Forward the request to `upstream` in the background, so that the handshake isn't held
+fn forward_to_upstream(
+    mut stream: TransportStream,
+    head: BytesMut,
+    upstream: String,
+    tcp: Arc<TcpTransport>,
+) {
+    tokio::spawn(async move {
+        let res: anyhow::Result<()> = async {
+            let mut conn = tcp.connect(&AddrMaybeCached::new(&upstream)).await?;
+            conn.write_all(&head).await?;
+            copy_bidirectional(&mut stream, &mut conn).await?;
+            Ok(())
+        }
+        .await;
+        if let Err(e) = res {
+            debug!("Failed to forward to the fallback upstream {}: {:#}", upstream, e);
+        }
+    });
+}
+
+fn error_response(status: StatusCode) -> ErrorResponse {
+    let mut resp = ErrorResponse::new(status.canonical_reason().map(String::from));
+    *resp.status_mut() = status;
+    resp
+}
+
+// Whether the request asks for the subprotocol, which is one of a comma-separated list
+fn offers_subprotocol(req: &Request, subprotocol: &str) -> bool {
+    req.headers()
+        .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|p| p.trim() == subprotocol)
+}
+
+// Rejects requests that don't match the config, like a reverse proxy in front would do
+struct RequestChecker<'a>(&'a WebsocketTransport);
+
+impl Callback for RequestChecker<'_> {
+    fn on_request(self, req: &Request, mut resp: Response) -> Result<Response, ErrorResponse> {
+        let t = self.0;
+        if let Some(path) = &t.path {
+            if req.uri().path() != path {
+                return Err(error_response(StatusCode::NOT_FOUND));
+            }
+        }
+        for (k, v) in &t.headers {
+            if req.headers().get(k) != Some(v) {
+                return Err(error_response(StatusCode::FORBIDDEN));
+            }
+        }
+        if let Some(subprotocol) = &t.subprotocol {
+            if !offers_subprotocol(req, subprotocol) {
+                return Err(error_response(StatusCode::BAD_REQUEST));
+            }
+            resp.headers_mut().insert(
+                header::SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_str(subprotocol).unwrap(),
+            );
+        }
+        Ok(resp)
+    }
 }
 
 #[async_trait]
@@ -217,15 +407,74 @@ impl Transport for WebsocketTransport {
             write_buffer_size: 0,
             ..WebSocketConfig::default()
         };
+        if let Some(path) = &wsconfig.path {
+            if !path.starts_with('/') {
+                return Err(anyhow!("`path` of websocket must start with '/'"));
+            }
+        }
+        if let Some(subprotocol) = &wsconfig.subprotocol {
+            HeaderValue::from_str(subprotocol)
+                .map_err(|_| anyhow!("Invalid `subprotocol` of websocket"))?;
+        }
+        let mut headers = HeaderMap::new();
+        for (k, v) in &wsconfig.headers {
+            let name = HeaderName::from_bytes(k.as_bytes())
+                .map_err(|_| anyhow!("Invalid header name {}", k))?;
+            // These are set by the handshake
+            if name == header::HOST
+                || name == header::CONNECTION
+                || name == header::UPGRADE
+                || name.as_str().starts_with("sec-websocket-")
+            {
+                return Err(anyhow!("Header {} can't be set in `headers`", k));
+            }
+            let value =
+                HeaderValue::from_str(v).map_err(|_| anyhow!("Invalid value of header {}", k))?;
+            headers.insert(name, value);
+        }
+
+        if let Some(health_path) = &wsconfig.health_path {
+            if !health_path.starts_with('/') {
+                return Err(anyhow!("`health_path` of websocket must start with '/'"));
+            }
+        }
+        if let Some(fallback) = &wsconfig.fallback {
+            StatusCode::from_u16(fallback.status)
+                .map_err(|_| anyhow!("Invalid `status` of websocket fallback"))?;
+            if fallback.upstream.is_some() && !fallback.body.is_empty() {
+                return Err(anyhow!(
+                    "Only one of `upstream` and `body` of websocket fallback can be set"
+                ));
+            }
+        }
+
         let sub = match wsconfig.tls {
-            true => SubTransport::Secure(TlsTransport::new(config)?),
+            true => SubTransport::Secure(Box::new(TlsTransport::new(config)?)),
             false => SubTransport::Insecure(TcpTransport::new(config)?),
         };
-        Ok(WebsocketTransport { sub, conf })
+        // The upstream speaks plain HTTP, whatever the tunnel is over
+        let upstream_tcp = match wsconfig.fallback.as_ref().and_then(|f| f.upstream.as_ref()) {
+            Some(_) => Some(Arc::new(TcpTransport::new(&TransportConfig {
+                tcp: config.tcp.clone(),
+                ..Default::default()
+            })?)),
+            None => None,
+        };
+        Ok(WebsocketTransport {
+            sub,
+            conf,
+            path: wsconfig.path.clone(),
+            host: wsconfig.host.clone(),
+            headers,
+            subprotocol: wsconfig.subprotocol.clone(),
+            health_path: wsconfig.health_path.clone(),
+            fallback: wsconfig.fallback.clone(),
+            upstream_tcp,
+        })
     }
 
     fn hint(conn: &Self::Stream, opt: SocketOpts) {
-        opt.apply(conn.inner.get_ref().inner.get_ref().get_tcpstream())
+        opt.apply(conn.inner.get_ref().inner.get_ref().inner.get_tcpstream())
     }
 
     async fn bind<A: ToSocketAddrs + Send + Sync>(
@@ -244,11 +493,44 @@ impl Transport for WebsocketTransport {
     }
 
     async fn handshake(&self, conn: Self::RawStream) -> anyhow::Result<Self::Stream> {
-        let tsream = match &self.sub {
+        let mut tsream = match &self.sub {
             SubTransport::Insecure(t) => TransportStream::Insecure(t.handshake(conn).await?),
             SubTransport::Secure(t) => TransportStream::Secure(t.handshake(conn).await?),
         };
-        let wsstream = accept_async_with_config(tsream, Some(self.conf)).await?;
+
+        // Read the request ahead only if it may be answered as plain HTTP
+        let mut prefix = Bytes::new();
+        if self.health_path.is_some() || self.fallback.is_some() {
+            let head = read_request_head(&mut tsream).await?;
+            if self.health_path.as_ref() == Some(&head.path) {
+                respond(&mut tsream, StatusCode::OK, None, "OK").await?;
+                return Err(NotTunnel(format!("health check {}", head.path)).into());
+            }
+            if !head.is_upgrade {
+                if let Some(fallback) = &self.fallback {
+                    match (&fallback.upstream, &self.upstream_tcp) {
+                        (Some(upstream), Some(tcp)) => {
+                            forward_to_upstream(tsream, head.buf, upstream.clone(), tcp.clone());
+                        }
+                        _ => {
+                            // Checked in `new`
+                            let status = StatusCode::from_u16(fallback.status).unwrap();
+                            let content_type = fallback.content_type.as_deref();
+                            respond(&mut tsream, status, content_type, &fallback.body).await?;
+                        }
+                    }
+                    return Err(NotTunnel(format!("HTTP request {}", head.path)).into());
+                }
+            }
+            prefix = head.buf.freeze();
+        }
+
+        let stream = PrefixedStream {
+            prefix,
+            inner: tsream,
+        };
+        let wsstream =
+            accept_hdr_async_with_config(stream, RequestChecker(self), Some(self.conf)).await?;
         let tun = WebsocketTunnel {
             inner: StreamReader::new(StreamWrapper { inner: wsstream }),
         };
@@ -256,18 +538,102 @@ impl Transport for WebsocketTransport {
     }
 
     async fn connect(&self, addr: &AddrMaybeCached) -> anyhow::Result<Self::Stream> {
-        let u = format!("ws://{}", &addr.addr.as_str());
-        let url = Url::parse(&u).unwrap();
+        // The host of the url becomes the `Host` header
+        let u = format!(
+            "ws://{}{}",
+            self.host.as_deref().unwrap_or(addr.addr.as_str()),
+            self.path.as_deref().unwrap_or("/")
+        );
+        let url = Url::parse(&u).map_err(|e| anyhow!("Invalid websocket url {}: {}", u, e))?;
+        let mut req = url.into_client_request()?;
+        req.headers_mut().extend(self.headers.clone());
+        if let Some(subprotocol) = &self.subprotocol {
+            req.headers_mut().insert(
+                header::SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_str(subprotocol)?,
+            );
+        }
+
         let tstream = match &self.sub {
             SubTransport::Insecure(t) => TransportStream::Insecure(t.connect(addr).await?),
             SubTransport::Secure(t) => TransportStream::Secure(t.connect(addr).await?),
         };
-        let (wsstream, _) = client_async_with_config(url, tstream, Some(self.conf))
-            .await
-            .expect("failed to connect");
+        let stream = PrefixedStream {
+            prefix: Bytes::new(),
+            inner: tstream,
+        };
+        let (wsstream, resp) = client_async_with_config(req, stream, Some(self.conf)).await?;
+        if let Some(subprotocol) = &self.subprotocol {
+            if resp.headers().get(header::SEC_WEBSOCKET_PROTOCOL).map(|v| v.as_bytes())
+                != Some(subprotocol.as_bytes())
+            {
+                return Err(anyhow!("The server doesn't accept subprotocol {}", subprotocol));
+            }
+        }
         let tun = WebsocketTunnel {
             inner: StreamReader::new(StreamWrapper { inner: wsstream }),
         };
         Ok(tun)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::{TcpConfig, WebsocketConfig};
+
+    #[tokio::test]
+    async fn test_fallback_upstream_proxy() -> anyhow::Result<()> {
+        // A proxy that answers as the upstream itself
+        let proxy = TcpListener::bind("127.0.0.1:0").await?;
+        let proxy_url = Url::parse(&format!("http://{}", proxy.local_addr()?))?;
+        let proxy = tokio::spawn(async move {
+            let (s, _) = proxy.accept().await?;
+            let mut s = TransportStream::Insecure(TcpConn::Direct(s));
+            let connect = read_request_head(&mut s).await?;
+            s.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await?;
+            let req = read_request_head(&mut s).await?;
+            s.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+            let connect = String::from_utf8_lossy(&connect.buf).into_owned();
+            anyhow::Ok((connect, req.path))
+        });
+
+        let t = WebsocketTransport::new(&TransportConfig {
+            transport_type: crate::config::TransportType::Websocket,
+            tcp: TcpConfig {
+                proxy: Some(proxy_url),
+                ..Default::default()
+            },
+            websocket: Some(WebsocketConfig {
+                tls: false,
+                path: None,
+                host: None,
+                headers: Default::default(),
+                subprotocol: None,
+                health_path: None,
+                fallback: Some(WebsocketFallbackConfig {
+                    upstream: Some("upstream.example.com:8080".to_string()),
+                    status: 404,
+                    body: String::new(),
+                    content_type: None,
+                }),
+            }),
+            ..Default::default()
+        })?;
+        let listener = t.bind("127.0.0.1:0").await?;
+        let mut visitor = TcpStream::connect(listener.local_addr()?).await?;
+        visitor
+            .write_all(b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n")
+            .await?;
+        let (conn, _) = t.accept(&listener).await?;
+        assert!(t.handshake(conn).await.is_err());
+
+        let mut resp = String::new();
+        visitor.read_to_string(&mut resp).await?;
+        assert_eq!(resp, "HTTP/1.1 204 No Content\r\n\r\n");
+        let (connect, path) = proxy.await??;
+        assert!(connect.starts_with("CONNECT upstream.example.com:8080 HTTP/1.1\r\n"));
+        assert_eq!(path, "/index.html");
+        Ok(())
+    }
+}
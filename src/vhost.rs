@@ -0,0 +1,309 @@
+// Route visitors of `server.mux` by the hostname they ask for, which is the SNI of TLS
+// or the Host header of plain HTTP. The connection is forwarded as it is
+use crate::config::ServerServiceConfig;
+use std::collections::HashMap;
+use socket2::SockRef;
+use std::io;
+use std::mem::MaybeUninit;
+use std::time::Duration;
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+use tokio::time;
+
+// Large enough for a ClientHello in a full TLS record
+const MAX_PEEK_SIZE: usize = 5 + 16384;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Sniff {
+    Host(String),
+    // Need more bytes
+    Incomplete,
+    // Not TLS or HTTP, or no hostname in it
+    NoHost,
+}
+
+// Take `n` bytes from the front of `buf`
+fn take<'a>(buf: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+    if buf.len() < n {
+        return None;
+    }
+    let (head, rest) = buf.split_at(n);
+    *buf = rest;
+    Some(head)
+}
+
+// Take a vector that is prefixed by its length in `len_size` bytes
+fn take_vec<'a>(buf: &mut &'a [u8], len_size: usize) -> Option<&'a [u8]> {
+    let len = take(buf, len_size)?
+        .iter()
+        .fold(0, |acc, b| (acc << 8) | *b as usize);
+    take(buf, len)
+}
+
+// The SNI of a ClientHello in a TLS record
+fn sniff_tls(buf: &[u8]) -> Sniff {
+    // The record header: type, version and length
+    if buf.len() < 5 {
+        return Sniff::Incomplete;
+    }
+    let len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    match buf.get(5..5 + len) {
+        Some(record) => client_hello_sni(record).map_or(Sniff::NoHost, Sniff::Host),
+        None => Sniff::Incomplete,
+    }
+}
+
+fn client_hello_sni(mut r: &[u8]) -> Option<String> {
+    // The handshake type is ClientHello, followed by its length
+    if take(&mut r, 1)? != [1] {
+        return None;
+    }
+    take(&mut r, 3)?;
+    // Version and random
+    take(&mut r, 2 + 32)?;
+    // Session ID, cipher suites and compression methods
+    take_vec(&mut r, 1)?;
+    take_vec(&mut r, 2)?;
+    take_vec(&mut r, 1)?;
+
+    let mut exts = take_vec(&mut r, 2)?;
+    while !exts.is_empty() {
+        let ext_type = take(&mut exts, 2)?;
+        let mut ext = take_vec(&mut exts, 2)?;
+        // server_name
+        if ext_type == [0, 0] {
+            let mut names = take_vec(&mut ext, 2)?;
+            while !names.is_empty() {
+                let name_type = take(&mut names, 1)?[0];
+                let name = take_vec(&mut names, 2)?;
+                // host_name
+                if name_type == 0 {
+                    return std::str::from_utf8(name).ok().map(str::to_ascii_lowercase);
+                }
+            }
+        }
+    }
+    None
+}
+
+// The Host header of an HTTP request, without the port
+fn sniff_http(buf: &[u8]) -> Sniff {
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut req = httparse::Request::new(&mut headers);
+    match req.parse(buf) {
+        Ok(httparse::Status::Complete(_)) => (),
+        Ok(httparse::Status::Partial) => return Sniff::Incomplete,
+        Err(_) => return Sniff::NoHost,
+    }
+
+    let host = match req
+        .headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case("host"))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+    {
+        Some(v) => v.trim(),
+        None => return Sniff::NoHost,
+    };
+    let host = match host.strip_prefix('[') {
+        // An IPv6 address
+        Some(v) => v.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+    Sniff::Host(host.to_ascii_lowercase())
+}
+
+fn sniff(buf: &[u8]) -> Sniff {
+    match buf.first() {
+        None => Sniff::Incomplete,
+        // A TLS handshake record
+        Some(0x16) => sniff_tls(buf),
+        Some(_) => sniff_http(buf),
+    }
+}
+
+/// Peek the hostname that the visitor asks for, leaving the bytes in the socket.
+/// Returns `None` if there's no hostname, and an error if it isn't sent within `timeout`
+pub async fn peek_hostname(conn: &TcpStream, timeout: Duration) -> io::Result<Option<String>> {
+    time::timeout(timeout, peek_hostname_inner(conn))
+        .await
+        .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+}
+
+async fn peek_hostname_inner(conn: &TcpStream) -> io::Result<Option<String>> {
+    let mut buf = vec![0u8; MAX_PEEK_SIZE];
+    let mut last = 0;
+    loop {
+        conn.readable().await?;
+        // Peeking doesn't consume the bytes, so the readiness stays set. Clear it
+        // when nothing new has arrived, to wait for more bytes instead of spinning
+        let n = match conn.try_io(Interest::READABLE, || {
+            let n = SockRef::from(conn).peek(as_uninit(&mut buf))?;
+            if n != 0 && n == last {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            Ok(n)
+        }) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        };
+        match sniff(&buf[..n]) {
+            Sniff::Host(host) => return Ok(Some(host)),
+            Sniff::Incomplete if n != 0 && n < buf.len() => last = n,
+            _ => return Ok(None),
+        }
+    }
+}
+
+fn as_uninit(buf: &mut [u8]) -> &mut [MaybeUninit<u8>] {
+    // SAFETY: `MaybeUninit<u8>` has the same layout as `u8`, and the socket
+    // only writes initialized bytes to it
+    unsafe { &mut *(buf as *mut [u8] as *mut [MaybeUninit<u8>]) }
+}
+
+/// Whether `host` matches `pattern`, which is a hostname or a wildcard like `*.example.com`.
+/// The wildcard matches exactly one label
+pub fn matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => host
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest.eq_ignore_ascii_case(suffix)),
+        None => pattern.eq_ignore_ascii_case(host),
+    }
+}
+
+/// The service whose `hostnames` match `host`. An exact hostname wins over a wildcard
+pub fn find_service<'a, K>(
+    services: &'a HashMap<K, ServerServiceConfig>,
+    host: &str,
+) -> Option<&'a ServerServiceConfig> {
+    let find = |wildcard: bool| {
+        services.values().find(|s| {
+            s.hostnames
+                .iter()
+                .any(|p| p.starts_with("*.") == wildcard && matches(p, host))
+        })
+    };
+    find(false).or_else(|| find(true))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A ClientHello with only the server_name extension
+    fn client_hello(host: &str) -> Vec<u8> {
+        let name = host.as_bytes();
+        let mut sni = vec![0];
+        sni.extend((name.len() as u16).to_be_bytes());
+        sni.extend(name);
+        let mut ext = (sni.len() as u16).to_be_bytes().to_vec();
+        ext.extend(sni);
+        let mut exts = vec![0, 0];
+        exts.extend((ext.len() as u16).to_be_bytes());
+        exts.extend(ext);
+
+        let mut body = vec![3, 3];
+        body.extend([0u8; 32]);
+        // Session ID, a cipher suite, and the null compression
+        body.extend([0, 0, 2, 0x13, 0x01, 1, 0]);
+        body.extend((exts.len() as u16).to_be_bytes());
+        body.extend(exts);
+
+        let mut hs = vec![1];
+        hs.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        hs.extend(body);
+        let mut record = vec![0x16, 3, 1];
+        record.extend((hs.len() as u16).to_be_bytes());
+        record.extend(hs);
+        record
+    }
+
+    #[test]
+    fn test_sniff() {
+        let hello = client_hello("Foo.example.com");
+        assert_eq!(sniff(&hello), Sniff::Host("foo.example.com".into()));
+        assert_eq!(sniff(&hello[..hello.len() - 1]), Sniff::Incomplete);
+        assert_eq!(sniff(&hello[..3]), Sniff::Incomplete);
+
+        let mut no_sni = client_hello("");
+        // Make it an unknown extension
+        no_sni[5 + 4 + 2 + 32 + 7 + 2] = 0xff;
+        assert_eq!(sniff(&no_sni), Sniff::NoHost);
+
+        let req = b"GET / HTTP/1.1\r\nUser-Agent: test\r\nHost: Bar.example.com:8080\r\n\r\n";
+        assert_eq!(sniff(req), Sniff::Host("bar.example.com".into()));
+        assert_eq!(sniff(&req[..20]), Sniff::Incomplete);
+        assert_eq!(
+            sniff(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n"),
+            Sniff::Host("::1".into())
+        );
+        assert_eq!(sniff(b"GET / HTTP/1.1\r\n\r\n"), Sniff::NoHost);
+        assert_eq!(sniff(b"SSH-2.0-OpenSSH_9.0\r\n"), Sniff::NoHost);
+    }
+
+    #[tokio::test]
+    async fn test_peek_hostname() -> io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let mut visitor = TcpStream::connect(listener.local_addr()?).await?;
+        let (conn, _) = listener.accept().await?;
+
+        // The hello arrives in pieces
+        let hello = client_hello("foo.example.com");
+        let (head, tail) = hello.split_at(10);
+        visitor.write_all(head).await?;
+        let peek = tokio::spawn(async move {
+            let host = peek_hostname(&conn, Duration::from_secs(5)).await;
+            (conn, host)
+        });
+        time::sleep(Duration::from_millis(100)).await;
+        visitor.write_all(tail).await?;
+        let (conn, host) = peek.await.unwrap();
+        assert_eq!(host?, Some("foo.example.com".to_string()));
+        // The bytes are left in the socket
+        let mut buf = vec![0u8; hello.len()];
+        assert_eq!(conn.peek(&mut buf).await?, hello.len());
+
+        // A visitor that never completes the request
+        let mut visitor = TcpStream::connect(listener.local_addr()?).await?;
+        let (conn, _) = listener.accept().await?;
+        visitor.write_all(head).await?;
+        let err = peek_hostname(&conn, Duration::from_millis(100))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_service() {
+        assert!(matches("*.example.com", "foo.example.com"));
+        assert!(!matches("*.example.com", "example.com"));
+        assert!(!matches("*.example.com", "a.foo.example.com"));
+        assert!(matches("Example.com", "example.com"));
+
+        let mut services = HashMap::new();
+        for (name, hostnames) in [
+            ("wildcard", vec!["*.example.com"]),
+            ("exact", vec!["foo.example.com", "bar.example.com"]),
+        ] {
+            services.insert(
+                name,
+                ServerServiceConfig {
+                    name: name.to_string(),
+                    hostnames: hostnames.into_iter().map(String::from).collect(),
+                    ..Default::default()
+                },
+            );
+        }
+        let found = |host| find_service(&services, host).map(|s| s.name.as_str());
+        assert_eq!(found("foo.example.com"), Some("exact"));
+        assert_eq!(found("bar.example.com"), Some("exact"));
+        assert_eq!(found("baz.example.com"), Some("wildcard"));
+        assert_eq!(found("example.org"), None);
+    }
+}